@@ -1,15 +1,26 @@
-use std::collections::HashMap;
-use std::process::{ChildStdin, ChildStdout, Stdio};
-// use std::fmt::{Display, Formatter, Result as FmtResult};
-use std::io::{BufRead, BufReader, Write};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Weak};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::process::{ChildStderr, ChildStdin, ChildStdout, Command, Stdio};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::fs::{remove_file, read_link, symlink_metadata, create_dir_all};
 use std::os::unix::fs;
-use crossbeam::channel::{bounded, Receiver, SendError, Sender};
-use libc::{kill, killpg, SIGKILL};
+use std::path::PathBuf;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use crossbeam::channel::{
+    bounded, never, select, Receiver, RecvTimeoutError, SendTimeoutError, Sender, TrySendError,
+};
+#[cfg(feature = "tokio")]
+use crossbeam::channel::TryRecvError;
+use libc::{kill, killpg, ESRCH, SIGKILL, SIGTERM};
+use libraspserver::process_mode::RASPServerProcess;
+use lazy_static::lazy_static;
 use log::*;
 
 // use super::process::ProcessInfo;
@@ -23,6 +34,12 @@ use anyhow::{anyhow, Result as AnyhowResult};
 pub struct Control {
     pub working_atomic: Arc<AtomicBool>,
     pub control: Weak<AtomicBool>,
+    /// notified by `stop`, see `wait_stopped_timeout`
+    stopped: Arc<(Mutex<()>, Condvar)>,
+    /// why it was stopped, set by `stop_with_reason`
+    reason: Arc<Mutex<Option<String>>>,
+    /// set by `child`, stopping it stops this one too
+    parent: Option<Box<Control>>,
 }
 
 impl Control {
@@ -32,16 +49,43 @@ impl Control {
         Control {
             working_atomic: working,
             control,
+            stopped: Arc::new((Mutex::new(()), Condvar::new())),
+            reason: Arc::new(Mutex::new(None)),
+            parent: None,
         }
     }
+    /// a control stopped along with this one, which can also be stopped on its own
+    /// without stopping this one. its waiters are woken by either stop
+    pub fn child(&self) -> Self {
+        let working = Arc::new(AtomicBool::new(true));
+        let control = Arc::downgrade(&working);
+        Control {
+            working_atomic: working,
+            control,
+            // shared, so stopping the parent wakes the child's waiters
+            stopped: self.stopped.clone(),
+            reason: Arc::new(Mutex::new(None)),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+    fn running(&self) -> bool {
+        (*self.working_atomic).load(Ordering::Acquire)
+            && self.parent.as_ref().map_or(true, |parent| parent.running())
+    }
+    /// writes made before `stop` are visible once this returns false
     pub fn check(&mut self) -> bool {
-        (*self.working_atomic).load(Ordering::Relaxed)
+        self.running()
     }
-    pub fn stop(&mut self) -> Result<(), ()> {
+    /// `Ok(true)` for the call that stopped it, `Ok(false)` if it was stopped already
+    pub fn stop(&mut self) -> Result<bool, ()> {
         return match self.control.upgrade() {
             Some(working) => {
-                (*working).store(false, Ordering::Relaxed);
-                Ok(())
+                // Release pairs with the Acquire in `check` and the waits
+                let was_running = (*working).swap(false, Ordering::AcqRel);
+                // taken after the flag flipped, a waiter checking the flag is parked by now
+                let _lock = self.stopped.0.lock().unwrap();
+                self.stopped.1.notify_all();
+                Ok(was_running)
             }
             None => {
                 // world stopped
@@ -49,9 +93,430 @@ impl Control {
             }
         };
     }
+    /// `stop`, keeping `reason` for whoever sees the stop. a control stopped already
+    /// keeps the reason it had
+    pub fn stop_with_reason(&mut self, reason: String) -> Result<bool, ()> {
+        {
+            let mut current = self.reason.lock().unwrap();
+            // written before the flag flips, `check` returning false makes it visible
+            if current.is_none() && (*self.working_atomic).load(Ordering::Acquire) {
+                *current = Some(reason);
+            }
+        }
+        self.stop()
+    }
+    /// reason given to `stop_with_reason`, `None` while running or stopped without one.
+    /// a child stopped by its parent has the parent's reason
+    pub fn reason(&self) -> Option<String> {
+        let reason = self.reason.lock().unwrap().clone();
+        reason.or_else(|| self.parent.as_ref().and_then(|parent| parent.reason()))
+    }
+    /// block until stopped, checking every `poll_interval`
+    pub fn wait_until_stopped(&self, poll_interval: Duration) {
+        while self.running() {
+            thread::sleep(poll_interval);
+        }
+    }
+    /// block until stopped or `timeout` passed, woken by `stop` right away.
+    /// true once stopped
+    pub fn wait_stopped_timeout(&self, timeout: Duration) -> bool {
+        let (lock, stopped) = &*self.stopped;
+        let guard = lock.lock().unwrap();
+        let _ = stopped
+            .wait_timeout_while(guard, timeout, |_| self.running())
+            .unwrap();
+        !self.running()
+    }
+}
+
+/// `Control`s of several comm modes, stopped together by `stop_all`
+#[derive(Default)]
+pub struct ControlGroup {
+    controls: Mutex<Vec<Control>>,
+}
+
+impl ControlGroup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn register(&self, ctrl: Control) {
+        self.controls.lock().unwrap().push(ctrl);
+    }
+    pub fn len(&self) -> usize {
+        self.controls.lock().unwrap().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// stop every registered control, in registration order, one failing does not keep
+    /// the rest running. controls registered meanwhile wait for the whole group
+    pub fn stop_all(&self) -> Vec<Result<bool, ()>> {
+        let mut controls = self.controls.lock().unwrap();
+        controls.iter_mut().map(|ctrl| ctrl.stop()).collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum CommError {
+    /// golang eBPF daemon closed its stdin/stdout, it most likely crashed or exited
+    DaemonDied { reason: String },
+    /// no response from golang eBPF daemon in time
+    AttachTimeout {
+        pid: i32,
+        diagnostics: Box<TimeoutDiagnostics>,
+    },
+    /// bind mount source does not exist on host
+    MountSourceMissing { path: String },
+    /// golang eBPF daemon was rotated less than `rotate_min_interval` ago
+    RotateTooSoon { next_allowed: Instant },
+    /// pid already attached and `on_duplicate` is `OnDuplicate::Error`
+    AlreadyAttached { pid: i32 },
+    /// mount script succeeded but `path` is not a mountpoint in the target namespace,
+    /// usually mount propagation is not what NSMount expects
+    MountNotVisible { pid: i32, path: String },
+    /// nsenter older than the first release known to handle `-m -i -n -p`
+    NsEnterTooOld { found: String, minimum: String },
+    /// `pre_attach` hook vetoed the pid, or the attach rate limit dropped it
+    AttachSkipped { pid: i32, reason: String },
+    /// `pre_attach` hook or the attach rate limit asked to come back later, the pid is
+    /// refused until then
+    AttachDeferred { pid: i32, retry_after: Duration },
+    /// linking the probe socket into the target namespace failed, after `attempts` tries
+    LinkFailed {
+        target: String,
+        attempts: u32,
+        reason: String,
+    },
+    /// golang eBPF daemon kept reading stdin but not fast enough to take the whole write
+    /// before its deadline
+    StdinSlow { written: usize, total: usize },
+    /// golang eBPF daemon read nothing from stdin for `stalled_for`
+    StdinStalled {
+        written: usize,
+        total: usize,
+        stalled_for: Duration,
+    },
+    /// settings that can not work together, refused under `UnlinkedMount::Refuse`
+    IncoherentConfig { reason: String },
+    /// golang eBPF daemon wrote a stdout line of `length` bytes, over `limit`
+    ResponseTooLong { length: usize, limit: usize },
+    /// `path` can not be written, usually a read-only mount on a hardened host
+    ReadOnlyPath { path: String },
+    /// creating the link did not return within `timeout`, the target namespace or a
+    /// filesystem on the way is not responding
+    LinkTimeout { target: String, timeout: Duration },
+    /// the channel to the probe holds `capacity` messages not taken yet, the message was
+    /// not queued
+    Backpressure { queued: usize, capacity: usize },
+}
+
+impl Display for CommError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            CommError::DaemonDied { reason } => {
+                write!(f, "golang ebpf daemon died: {}", reason)
+            }
+            CommError::AttachTimeout { pid, diagnostics } => {
+                write!(
+                    f,
+                    "read stdout from ebpf server timeout: {}, elapsed: {:?}, daemon alive: {:?}",
+                    pid, diagnostics.elapsed, diagnostics.daemon_alive
+                )
+            }
+            CommError::MountSourceMissing { path } => {
+                write!(f, "mount source not exist: {}", path)
+            }
+            CommError::RotateTooSoon { next_allowed } => {
+                write!(
+                    f,
+                    "golang ebpf daemon rotated too soon, next allowed in: {:?}",
+                    next_allowed.saturating_duration_since(Instant::now())
+                )
+            }
+            CommError::AlreadyAttached { pid } => {
+                write!(f, "pid already attached: {}", pid)
+            }
+            CommError::MountNotVisible { pid, path } => {
+                write!(f, "mount not visible in namespace of {}: {}", pid, path)
+            }
+            CommError::NsEnterTooOld { found, minimum } => {
+                write!(f, "nsenter too old: {}, minimum: {}", found, minimum)
+            }
+            CommError::AttachSkipped { pid, reason } => {
+                write!(f, "attach skipped: {} {}", pid, reason)
+            }
+            CommError::AttachDeferred { pid, retry_after } => {
+                write!(f, "attach deferred: {} {:?}", pid, retry_after)
+            }
+            CommError::LinkFailed {
+                target,
+                attempts,
+                reason,
+            } => {
+                write!(
+                    f,
+                    "link bind path failed: {}, attempts: {}, {}",
+                    target, attempts, reason
+                )
+            }
+            CommError::StdinSlow { written, total } => {
+                write!(f, "ebpf daemon drains stdin too slowly: {}/{}", written, total)
+            }
+            CommError::StdinStalled {
+                written,
+                total,
+                stalled_for,
+            } => {
+                write!(
+                    f,
+                    "ebpf daemon stopped reading stdin: {}/{} for {:?}",
+                    written, total, stalled_for
+                )
+            }
+            CommError::IncoherentConfig { reason } => {
+                write!(f, "incoherent config: {}", reason)
+            }
+            CommError::ResponseTooLong { length, limit } => {
+                write!(f, "ebpf daemon response too long: {} bytes, limit: {}", length, limit)
+            }
+            CommError::ReadOnlyPath { path } => {
+                write!(f, "path not writable, read-only mount?: {}", path)
+            }
+            CommError::LinkTimeout { target, timeout } => {
+                write!(f, "link bind path timed out after {:?}: {}", timeout, target)
+            }
+            CommError::Backpressure { queued, capacity } => {
+                write!(f, "probe message channel full: {}/{}", queued, capacity)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommOperation {
+    StartComm,
+    StopComm,
+    Attach,
+    /// `ProcessMode` server restarted in place, see `ProcessMode::recycle`
+    Recycle,
+}
+
+/// state of the daemon when an attach timed out, filled best-effort: `None` where it
+/// could not be read
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TimeoutDiagnostics {
+    /// time since the wait for the response started
+    pub elapsed: Duration,
+    /// responses read for other pids while waiting
+    pub attempts: u32,
+    /// last line read from daemon stdout, for any pid
+    pub last_raw_response: Option<String>,
+    /// whether the daemon process still runs, `None` without a `server_pid`
+    pub daemon_alive: Option<bool>,
+    /// last `EBPF_STDERR_TAIL` lines of daemon stderr, oldest first
+    pub stderr_tail: Vec<String>,
+    /// bytes written to daemon stdin it has not read yet
+    pub pending_stdin_bytes: Option<usize>,
+}
+
+/// lifecycle events, only emitted when a mode has an `events` sender
+#[derive(Debug, Clone)]
+pub enum CommEvent {
+    OperationStarted {
+        operation: CommOperation,
+        pid: i32,
+        mnt_namespace: Option<String>,
+        /// only set when the mode has a `container_id_resolver`
+        container_id: Option<String>,
+    },
+    OperationCompleted {
+        operation: CommOperation,
+        pid: i32,
+        mnt_namespace: Option<String>,
+        container_id: Option<String>,
+        duration: Duration,
+        /// error message on failure
+        outcome: Result<(), String>,
+    },
+    /// daemon answered an attach `EbpfMode` had already given up on
+    LateAttachSucceeded { pid: i32, hooks: Vec<String> },
+    LateAttachFailed { pid: i32 },
+}
+
+/// source of the `corr_id` span field, one per operation
+#[cfg(feature = "tracing")]
+static NEXT_CORR_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+/// run `f` as one comm operation of `mode`, reported to `events`. with the `tracing`
+/// feature it also runs in a `comm_operation` span, which `comm_log!` events fall in
+fn with_operation_events<T>(
+    events: &Option<Sender<CommEvent>>,
+    container_id_resolver: &Option<ContainerIdResolver>,
+    operation: CommOperation,
+    mode: &'static str,
+    pid: i32,
+    mnt_namespace: Option<&String>,
+    f: impl FnOnce() -> AnyhowResult<T>,
+) -> AnyhowResult<T> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!(
+        "comm_operation",
+        operation = ?operation,
+        pid,
+        mnt_ns = mnt_namespace.map(String::as_str).unwrap_or_default(),
+        mode,
+        corr_id = NEXT_CORR_ID.fetch_add(1, Ordering::Relaxed),
+    )
+    .entered();
+    #[cfg(not(feature = "tracing"))]
+    let _ = mode;
+    let sender = if let Some(sender) = events {
+        sender
+    } else {
+        let result = f();
+        #[cfg(feature = "tracing")]
+        tracing::debug!(ok = result.is_ok(), "comm operation completed");
+        return result;
+    };
+    let container_id = container_id_resolver.as_ref().and_then(|resolve| {
+        resolve(mnt_namespace.map(|ns| ns.as_str()).unwrap_or_default(), pid)
+    });
+    // never block comm operations on a slow consumer
+    let _ = sender.try_send(CommEvent::OperationStarted {
+        operation,
+        pid,
+        mnt_namespace: mnt_namespace.cloned(),
+        container_id: container_id.clone(),
+    });
+    let start = Instant::now();
+    let result = f();
+    #[cfg(feature = "tracing")]
+    tracing::debug!(ok = result.is_ok(), "comm operation completed");
+    let _ = sender.try_send(CommEvent::OperationCompleted {
+        operation,
+        pid,
+        mnt_namespace: mnt_namespace.cloned(),
+        container_id,
+        duration: start.elapsed(),
+        outcome: match result {
+            Ok(_) => Ok(()),
+            Err(ref e) => Err(e.to_string()),
+        },
+    });
+    result
+}
+
+/// maps `(mnt_namespace, pid)` to the id container tooling knows, `mnt_namespace` may be empty
+pub type ContainerIdResolver = Arc<dyn Fn(&str, i32) -> Option<String> + Send + Sync>;
+
+/// default `ContainerIdResolver`, picks the 64 hex digit id out of docker,
+/// containerd and cri-o cgroup paths in `/proc/<pid>/cgroup`
+pub fn cgroup_container_id(_mnt_namespace: &str, pid: i32) -> Option<String> {
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    parse_cgroup_container_id(&cgroup)
+}
+
+fn parse_cgroup_container_id(cgroup: &str) -> Option<String> {
+    let regex = regex::Regex::new(r"(?:^|[/-])([0-9a-f]{64})(?:\.scope)?$").ok()?;
+    cgroup.lines().find_map(|line| {
+        let path = line.splitn(3, ':').nth(2)?;
+        regex
+            .captures(path)
+            .and_then(|caps| caps.get(1))
+            .map(|id| id.as_str().to_string())
+    })
+}
+
+/// level handed to `LogSink`, maps one to one onto `log::Level`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Level::Error,
+            LogLevel::Warn => Level::Warn,
+            LogLevel::Info => Level::Info,
+            LogLevel::Debug => Level::Debug,
+            LogLevel::Trace => Level::Trace,
+        }
+    }
+}
+
+/// receives formatted comm log messages for embedders not using the global logger
+pub type LogSink = Arc<dyn Fn(LogLevel, &str) + Send + Sync>;
+
+/// with the `tracing` feature comm messages are also tracing events, in the span of the
+/// operation they belong to. the log records stay, hosts logging through `log` only
+/// would lose them otherwise
+#[cfg(feature = "tracing")]
+macro_rules! comm_event {
+    ($level:expr, $message:expr) => {{
+        match $level {
+            LogLevel::Error => tracing::error!("{}", $message),
+            LogLevel::Warn => tracing::warn!("{}", $message),
+            LogLevel::Info => tracing::info!("{}", $message),
+            LogLevel::Debug => tracing::debug!("{}", $message),
+            LogLevel::Trace => tracing::trace!("{}", $message),
+        }
+        log!(Level::from($level), "{}", $message)
+    }};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! comm_event_enabled {
+    ($level:expr) => {
+        log_enabled!(Level::from($level))
+            || match $level {
+                LogLevel::Error => tracing::enabled!(tracing::Level::ERROR),
+                LogLevel::Warn => tracing::enabled!(tracing::Level::WARN),
+                LogLevel::Info => tracing::enabled!(tracing::Level::INFO),
+                LogLevel::Debug => tracing::enabled!(tracing::Level::DEBUG),
+                LogLevel::Trace => tracing::enabled!(tracing::Level::TRACE),
+            }
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! comm_event {
+    ($level:expr, $message:expr) => {
+        log!(Level::from($level), "{}", $message)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! comm_event_enabled {
+    ($level:expr) => {
+        log_enabled!(Level::from($level))
+    };
+}
+
+// log through the `log` facade as before, and to the sink when one is set
+macro_rules! comm_log {
+    ($sink:expr, $level:ident, $($arg:tt)+) => {{
+        let sink: &Option<LogSink> = &$sink;
+        let level = LogLevel::$level;
+        if sink.is_some() || comm_event_enabled!(level) {
+            let message = format!($($arg)+);
+            comm_event!(level, message);
+            if let Some(sink) = sink {
+                sink(level, &message);
+            }
+        }
+    }};
 }
 
 pub trait RASPComm {
+    /// `mnt_namespace` is best effort, the string was read earlier and `pid` may have moved
+    /// or been reused since. `ThreadMode::start_comm_in_namespace` takes the namespace itself
     fn start_comm(
         &mut self,
         pid: i32,
@@ -66,15 +531,384 @@ pub trait RASPComm {
         mnt_namespace: &String,
         message: &String,
     ) -> AnyhowResult<()>;
+    /// round trip of a PING answered by the probe through the report path
+    fn ping_probe(
+        &mut self,
+        pid: i32,
+        mnt_namespace: &String,
+        timeout: Duration,
+    ) -> AnyhowResult<Duration>;
+    /// capabilities of this mode, check before asking for a feature instead of handling errors
+    fn supported_features(&self) -> FeatureSet;
+    /// where probes in `mnt_namespace` connect, `None` if this mode does not serve it
+    fn transport_info(&self, mnt_namespace: &String) -> Option<TransportInfo>;
+}
+
+/// rasp_server listens on its default `--path` inside the namespace it switched to
+const PROCESS_MODE_BIND_PATH: &str = libraspserver::process_mode::DEFAULT_SOCK_PATH;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    /// unix socket served by a thread of the agent, reached from other namespaces
+    /// through a bind mount or link
+    Thread,
+    /// unix socket served by a rasp_server process running in the namespace
+    Process,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransportInfo {
+    /// socket path as seen from the namespace serving it
+    pub bind_path: String,
+    /// links created in the target namespace, `{pid}` is left as is since it differs per process
+    pub link_targets: Vec<String>,
+    pub transport: Transport,
+}
+
+/// capabilities a comm mode offers, all off by default
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FeatureSet {
+    pub binary_messages: bool,
+    pub coalescing: bool,
+    pub priority: bool,
+    pub compression: bool,
+    pub detach: bool,
+    pub ping: bool,
+    // eBPF, kernel dependent
+    pub http_capture: bool,
+    pub http_headers: bool,
+    pub ring_buffer: bool,
+    /// kernel exposes its BTF, the ring buffer daemon is CO-RE and cannot load without it
+    pub btf: bool,
+    /// eBPF daemon writes `ready` once it reads stdin, see `EbpfMode::start_server`
+    pub ready_handshake: bool,
+}
+
+/// agent -> probe PING, probe answers with a message of the same type carrying the same uuid
+pub const PROBE_PING_MESSAGE_TYPE: i32 = 18;
+
+static PROBE_PING_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// PINGs waiting for their PONG, keyed by ping uuid
+#[derive(Clone, Default)]
+pub struct ProbePings {
+    pending: Arc<Mutex<HashMap<String, Sender<()>>>>,
+}
+
+impl ProbePings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn ping(
+        &self,
+        pid: i32,
+        timeout: Duration,
+        send: impl FnOnce(&String) -> AnyhowResult<()>,
+    ) -> AnyhowResult<Duration> {
+        let uuid = format!(
+            "ping-{}-{}",
+            pid,
+            PROBE_PING_SEQ.fetch_add(1, Ordering::Relaxed)
+        );
+        let message = serde_json::json!({
+            "pid": pid,
+            "message_type": PROBE_PING_MESSAGE_TYPE,
+            "data": { "uuid": uuid },
+        })
+        .to_string();
+        let (sender, receiver) = bounded(1);
+        self.pending.lock().unwrap().insert(uuid.clone(), sender);
+        let start = Instant::now();
+        let result = send(&message).and_then(|_| {
+            receiver
+                .recv_timeout(timeout)
+                .map_err(|_| anyhow!("ping probe timeout: {} {:?}", pid, timeout))
+        });
+        self.pending.lock().unwrap().remove(&uuid);
+        result.map(|_| start.elapsed())
+    }
+    /// consume a PONG record, returns false for any other record
+    pub fn resolve(&self, record: &plugins::Record) -> bool {
+        let uuid = if let Some(uuid) = Self::pong_uuid(record) {
+            uuid
+        } else {
+            return false;
+        };
+        if let Some(sender) = self.pending.lock().unwrap().remove(&uuid) {
+            let _ = sender.try_send(());
+        } else {
+            debug!("drop pong without pending ping: {}", uuid);
+        }
+        true
+    }
+    fn pong_uuid(record: &plugins::Record) -> Option<String> {
+        let fields = record.get_data().get_fields();
+        // thread mode forwards raw probe message
+        if let Some(raw) = fields.get("RASP_DATA") {
+            let message: serde_json::Value = serde_json::from_str(raw).ok()?;
+            if message["message_type"].as_i64()? != PROBE_PING_MESSAGE_TYPE as i64 {
+                return None;
+            }
+            return message["data"]["uuid"].as_str().map(String::from);
+        }
+        // process mode forwards flattened message
+        if fields.get("message_type")? != &PROBE_PING_MESSAGE_TYPE.to_string() {
+            return None;
+        }
+        fields.get("uuid").cloned()
+    }
+    /// put a forwarder in front of `reports` which takes PONGs out of the report stream
+    pub fn tap(&self, reports: ReportBuffer) -> AnyhowResult<Sender<plugins::Record>> {
+        let (sender, receiver) = bounded::<plugins::Record>(50);
+        let pings = self.clone();
+        thread::Builder::new()
+            .name("probe_report_tap".to_string())
+            .spawn(move || {
+                for record in receiver.iter() {
+                    if pings.resolve(&record) {
+                        continue;
+                    }
+                    if reports.send(record).is_err() {
+                        return;
+                    }
+                }
+            })?;
+        Ok(sender)
+    }
+}
+
+const REPORT_BUFFER_POLL: Duration = Duration::from_millis(10);
+const REPORT_DRAIN_GRACE: Duration = Duration::from_secs(1);
+const LINK_RETRIES: u32 = 3;
+const LINK_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const LINK_TIMEOUT: Duration = Duration::from_secs(5);
+const PROBE_MESSAGE_CAPACITY: usize = 50;
+const MOUNT_RETRIES: u32 = 2;
+const MOUNT_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// what `ThreadMode` does when the socket dir needs mounting but `linking_to` is empty,
+/// the mount would never be linked where the probe looks for the socket
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum UnlinkedMount {
+    /// log a warning and do not mount
+    #[default]
+    Skip,
+    /// fail `start_comm` with `CommError::IncoherentConfig`
+    Refuse,
+}
+
+/// outcome of `ReportBuffer::drain`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DrainReport {
+    /// handed to the consumer within the grace
+    pub drained: usize,
+    /// still buffered or held by a blocked forwarder when the grace ran out, thrown away
+    pub dropped: usize,
+}
+
+impl DrainReport {
+    pub fn add(&mut self, other: DrainReport) {
+        self.drained += other.drained;
+        self.dropped += other.dropped;
+    }
+}
+
+/// probe report queue in front of the consumer, its capacity can be changed at runtime
+#[derive(Clone)]
+pub struct ReportBuffer {
+    channel: Arc<RwLock<(Sender<plugins::Record>, Receiver<plugins::Record>)>>,
+    closed: Arc<AtomicBool>,
+    subscribers: Arc<Mutex<Vec<Sender<plugins::Record>>>>,
+    /// records taken out by the forwarder, and handed to the consumer so far
+    taken: Arc<AtomicUsize>,
+    forwarded: Arc<AtomicUsize>,
+}
+
+impl ReportBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            channel: Arc::new(RwLock::new(bounded(capacity))),
+            closed: Arc::new(AtomicBool::new(false)),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            taken: Arc::new(AtomicUsize::new(0)),
+            forwarded: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+    /// a receiver getting a copy of every record forwarded from now on, e.g. to `select!`
+    /// over it along with other channels. every `subscribe` is a broadcast, while clones
+    /// of one returned receiver compete for its records. records are dropped for a
+    /// subscriber lagging `capacity` behind, the main consumer is never held up by it.
+    /// subscriptions outlive `resize`
+    pub fn subscribe(&self, capacity: usize) -> Receiver<plugins::Record> {
+        let (sender, receiver) = bounded(capacity);
+        self.add_subscriber(sender);
+        receiver
+    }
+    pub fn add_subscriber(&self, sender: Sender<plugins::Record>) {
+        self.subscribers.lock().unwrap().push(sender);
+    }
+    pub fn capacity(&self) -> Option<usize> {
+        self.channel.read().unwrap().0.capacity()
+    }
+    pub fn len(&self) -> usize {
+        self.channel.read().unwrap().1.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// blocks while the buffer is full, errors once the consumer is gone
+    pub fn send(&self, record: plugins::Record) -> AnyhowResult<()> {
+        let mut record = record;
+        loop {
+            if self.closed.load(Ordering::Relaxed) {
+                return Err(anyhow!("probe report consumer closed"));
+            }
+            // lock is released between tries so a resize is never starved
+            let result = self
+                .channel
+                .read()
+                .unwrap()
+                .0
+                .send_timeout(record, REPORT_BUFFER_POLL);
+            match result {
+                Ok(_) => return Ok(()),
+                Err(SendTimeoutError::Timeout(r)) | Err(SendTimeoutError::Disconnected(r)) => {
+                    record = r;
+                }
+            }
+        }
+    }
+    /// swap in a channel of `capacity`, buffered records are moved over first.
+    /// senders and the forwarder are held off by the write lock for the whole swap,
+    /// so no newer record can get in front of the moved ones
+    pub fn resize(&self, capacity: usize) -> AnyhowResult<()> {
+        if capacity == 0 {
+            return Err(anyhow!("report buffer capacity must be positive"));
+        }
+        let mut channel = self.channel.write().unwrap();
+        let buffered = channel.1.len();
+        if buffered > capacity {
+            return Err(anyhow!(
+                "report buffer holds {} records, can not shrink to {}",
+                buffered,
+                capacity
+            ));
+        }
+        let (sender, receiver) = bounded(capacity);
+        for record in channel.1.try_iter() {
+            let _ = sender.try_send(record);
+        }
+        *channel = (sender, receiver);
+        Ok(())
+    }
+    /// last step of shutting a buffer down: give the forwarder up to `grace` to hand
+    /// buffered records to the consumer, then discard the rest. later sends fail
+    pub fn drain(&self, grace: Duration) -> DrainReport {
+        let forwarded = self.forwarded.load(Ordering::Relaxed);
+        let deadline = Instant::now() + grace;
+        let in_flight = || {
+            !self.is_empty()
+                || self.taken.load(Ordering::Relaxed) != self.forwarded.load(Ordering::Relaxed)
+        };
+        while in_flight()
+            && !self.closed.load(Ordering::Relaxed)
+            && Instant::now() < deadline
+        {
+            thread::sleep(REPORT_BUFFER_POLL);
+        }
+        self.closed.store(true, Ordering::Relaxed);
+        let buffered = self.channel.read().unwrap().1.try_iter().count();
+        let drained = self.forwarded.load(Ordering::Relaxed);
+        // taken by a forwarder still blocked on the consumer, given up on like the rest
+        let held = self.taken.load(Ordering::Relaxed).saturating_sub(drained);
+        DrainReport {
+            drained: drained - forwarded,
+            dropped: buffered + held,
+        }
+    }
+    /// drain the buffer into `report_sender` until the consumer is gone,
+    /// or every other handle is dropped and nothing is left
+    pub fn forward_to(&self, report_sender: Sender<plugins::Record>) -> AnyhowResult<()> {
+        let channel = self.channel.clone();
+        let closed = self.closed.clone();
+        let subscribers = self.subscribers.clone();
+        let taken = self.taken.clone();
+        let forwarded = self.forwarded.clone();
+        thread::Builder::new()
+            .name("probe_report_forward".to_string())
+            .spawn(move || loop {
+                let record = channel.read().unwrap().1.recv_timeout(REPORT_BUFFER_POLL);
+                match record {
+                    Ok(record) => {
+                        taken.fetch_add(1, Ordering::Relaxed);
+                        subscribers.lock().unwrap().retain(|subscriber| {
+                            !matches!(
+                                subscriber.try_send(record.clone()),
+                                Err(TrySendError::Disconnected(_))
+                            )
+                        });
+                        if report_sender.send(record).is_err() {
+                            closed.store(true, Ordering::Relaxed);
+                            return;
+                        }
+                        forwarded.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(_) => {
+                        if Arc::strong_count(&channel) == 1 {
+                            return;
+                        }
+                    }
+                }
+            })?;
+        Ok(())
+    }
 }
 
 pub struct ThreadMode {
     pub ctrl: Control,
     pub log_level: String,
     pub bind_path: String,
-    pub linking_to: Option<String>,
+    /// every path the socket is linked to in target namespaces
+    pub linking_to: Vec<String>,
     pub using_mount: bool,
     pub agent_to_probe_sender: Sender<(i32, String)>,
+    pub events: Option<Sender<CommEvent>>,
+    pub pings: ProbePings,
+    pub log_sink: Option<LogSink>,
+    pub report_buffer: ReportBuffer,
+    pub container_id_resolver: Option<ContainerIdResolver>,
+    /// check /proc/<pid>/mountinfo after mounting, see `CommError::MountNotVisible`
+    pub verify_mount: bool,
+    /// time `drain_reports` gives buffered reports to reach the consumer
+    pub drain_grace: Duration,
+    /// thread running the probe server, see `server_healthy`
+    pub server_thread: thread::JoinHandle<()>,
+    /// `start_comm` only records the namespace, the mount and link are done by the first
+    /// `send_message_to_probe` to it
+    pub lazy_mount: bool,
+    /// namespaces waiting for their lazy mount, with every pid started in them
+    pub pending_mounts: HashMap<String, Vec<i32>>,
+    /// extra tries for a link failing with ENOENT, the mount it goes through may not
+    /// have propagated yet. the wait doubles from `link_retry_backoff` on every try
+    pub link_retries: u32,
+    pub link_retry_backoff: Duration,
+    /// wait for a single link to be created, a stuck one fails with `CommError::LinkTimeout`
+    pub link_timeout: Duration,
+    /// extra runs of a failing mount script, racing a container still starting up.
+    /// the wait doubles from `mount_retry_backoff` on every try
+    pub mount_retries: u32,
+    pub mount_retry_backoff: Duration,
+    pub unlinked_mount: UnlinkedMount,
+    /// bind mounts made by `start_comm` and undone by `stop_comm`, by mnt namespace:
+    /// the pid mounted through and the mount target inside the namespace
+    pub mounts: HashMap<String, (i32, String)>,
+    /// links made by `start_comm` and removed by `stop_comm`, by pid: the mnt namespace
+    /// and the link paths inside it
+    pub links: HashMap<i32, (String, Vec<String>)>,
+    /// link threads that outlived `link_timeout`, and the links they made after it
+    pub link_threads: LinkThreads,
+    /// nsenter running `umount` and `rm` in the target namespace
+    pub nsenter_bin: String,
 }
 
 impl ThreadMode {
@@ -83,11 +917,37 @@ impl ThreadMode {
         ctrl: Control,
         probe_report_sender: Sender<plugins::Record>,
         bind_path: String,
-        linking_to: Option<String>,
+        linking_to: Vec<String>,
         using_mount: bool,
     ) -> AnyhowResult<Self> {
-        let (sender, receiver) = bounded(50);
-        libraspserver::thread_mode::start(
+        Self::with_message_capacity(
+            log_level,
+            ctrl,
+            probe_report_sender,
+            bind_path,
+            linking_to,
+            using_mount,
+            PROBE_MESSAGE_CAPACITY,
+        )
+    }
+    /// `new` queueing at most `message_capacity` messages for probes, past that
+    /// `send_message_to_probe` fails with `CommError::Backpressure`
+    pub fn with_message_capacity(
+        log_level: String,
+        ctrl: Control,
+        probe_report_sender: Sender<plugins::Record>,
+        bind_path: String,
+        linking_to: Vec<String>,
+        using_mount: bool,
+        message_capacity: usize,
+    ) -> AnyhowResult<Self> {
+        Self::check_bind_dir(&bind_path)?;
+        let (sender, receiver) = bounded(message_capacity);
+        let pings = ProbePings::new();
+        let report_buffer = ReportBuffer::new(50);
+        report_buffer.forward_to(probe_report_sender)?;
+        let probe_report_sender = pings.tap(report_buffer.clone())?;
+        let server_thread = libraspserver::thread_mode::start(
             bind_path.clone(),
             20,
             libraspserver::utils::Control {
@@ -104,446 +964,7265 @@ impl ThreadMode {
             linking_to: linking_to,
             using_mount,
             agent_to_probe_sender: sender,
+            events: None,
+            pings,
+            log_sink: None,
+            report_buffer,
+            container_id_resolver: None,
+            verify_mount: false,
+            drain_grace: REPORT_DRAIN_GRACE,
+            server_thread,
+            lazy_mount: false,
+            pending_mounts: HashMap::new(),
+            link_retries: LINK_RETRIES,
+            link_retry_backoff: LINK_RETRY_BACKOFF,
+            link_timeout: LINK_TIMEOUT,
+            mount_retries: MOUNT_RETRIES,
+            mount_retry_backoff: MOUNT_RETRY_BACKOFF,
+            unlinked_mount: UnlinkedMount::default(),
+            mounts: HashMap::new(),
+            links: HashMap::new(),
+            link_threads: LinkThreads::default(),
+            nsenter_bin: settings::RASP_NS_ENTER_BIN(),
         })
     }
-}
-
-pub struct ProcessMode {
-    pub ctrl: Control,
-    pub log_level: String,
-    pub mnt_namesapce_server_map: HashMap<String, libraspserver::process_mode::RASPServerProcess>,
-    pub mnt_namespace_comm_pair: HashMap<String, (Sender<String>, Receiver<String>)>,
-}
-
-impl ProcessMode {
-    pub fn new(log_level: String, ctrl: Control) -> Self {
-        Self {
-            ctrl,
-            log_level,
-            mnt_namesapce_server_map: HashMap::new(),
-            mnt_namespace_comm_pair: HashMap::new(),
-        }
-    }
-}
-
-impl RASPComm for ProcessMode {
-    fn start_comm(
-        &mut self,
-        pid: i32,
-        mnt_namespace: &String,
-        probe_report_sender: Sender<plugins::Record>,
-        patch_field: HashMap<&'static str, String>,
-    ) -> AnyhowResult<()> {
-        let (probe_mesasge_sender, probe_message_receiver) = bounded(50);
-        let mut server_process = libraspserver::process_mode::RASPServerProcess::new(
-            pid,
-            probe_report_sender,
-            probe_message_receiver.clone(),
-            self.log_level.clone(),
-            patch_field,
-            libraspserver::utils::Control {
-                working_atomic: self.ctrl.working_atomic.clone(),
-                control: self.ctrl.control.clone(),
-            },
-        )?;
-        server_process.spawn(settings::RASP_SERVER_BIN().as_str())?;
-        self.mnt_namesapce_server_map
-            .insert(mnt_namespace.clone(), server_process);
-        self.mnt_namespace_comm_pair.insert(
-            mnt_namespace.clone(),
-            (probe_mesasge_sender, probe_message_receiver),
-        );
-        Ok(())
+    /// messages queued for probes and not taken by the server yet
+    pub fn queued_messages(&self) -> usize {
+        self.agent_to_probe_sender.len()
     }
-
-    fn stop_comm(&mut self, _pid: i32, mnt_namespace: &String) -> AnyhowResult<()> {
-        info!("stop server: {}", mnt_namespace.clone());
-        return if let Some(mut runner) = self.mnt_namesapce_server_map.remove(mnt_namespace) {
-            runner.kill();
-            Ok(())
-        } else {
-            Err(anyhow!(
-                "didn't start server for mnt namespace: {}",
-                mnt_namespace.clone()
-            ))
+    /// the server creates the probe socket next to `bind_path`, which is also the source
+    /// of the bind mount into target namespaces. the dir is created when missing
+    fn check_bind_dir(bind_path: &str) -> AnyhowResult<()> {
+        let path = std::path::Path::new(bind_path);
+        if !path.is_absolute() {
+            return Err(anyhow!("bind path must be absolute: {}", bind_path));
+        }
+        let bind_dir = match path.parent() {
+            Some(bind_dir) if path.file_name().is_some() => bind_dir,
+            _ => return Err(anyhow!("bind path has no parent dir to mount: {}", bind_path)),
         };
+        check_writable(bind_dir)?;
+        create_dir_all(bind_dir)
+            .map_err(|e| anyhow!("create bind dir failed: {} {}", bind_dir.display(), e))
     }
-    fn send_message_to_probe(
-        &mut self,
-        _pid: i32,
-        mnt_namespace: &String,
-        message: &String,
-    ) -> AnyhowResult<()> {
-        if let Some(p) = self.mnt_namespace_comm_pair.get(mnt_namespace) {
-            if let Err(e) = p.0.send(message.clone()) {
-                return Err(anyhow!("send to probe failed: {}", e.to_string()));
+    /// remove the links `start_comm` made in `mnt_namespace`, through the pid that made
+    /// each or else `pid`. a link replaced since, no longer pointing at `bind_path`, is
+    /// left alone
+    fn remove_links(&mut self, pid: i32, mnt_namespace: &String) -> AnyhowResult<()> {
+        self.record_late_links();
+        let linked_by: Vec<i32> = self
+            .links
+            .iter()
+            .filter(|(_, (namespace, _))| namespace == mnt_namespace)
+            .map(|(linked_by, _)| *linked_by)
+            .collect();
+        for linked_by in linked_by {
+            let (_, links) = self.links.remove(&linked_by).unwrap();
+            let through = match pid_in_namespace(&[linked_by, pid], mnt_namespace) {
+                Some(through) => through,
+                None => {
+                    comm_log!(
+                        self.log_sink,
+                        Info,
+                        "mnt namespace gone, nothing to unlink: {} {:?}",
+                        mnt_namespace,
+                        links
+                    );
+                    continue;
+                }
+            };
+            for link in links {
+                let ours = read_link(format!("/proc/{}/root{}", through, link))
+                    .map(|points_to| points_to == std::path::Path::new(&self.bind_path))
+                    .unwrap_or(false);
+                if !ours {
+                    comm_log!(self.log_sink, Warn, "link replaced, not removed: {}", link);
+                    continue;
+                }
+                run_in_namespace(&self.nsenter_bin, through, &["rm", "-f", link.as_str()])?;
+                comm_log!(self.log_sink, Info, "unlink {} in {} success", link, mnt_namespace);
             }
         }
         Ok(())
     }
-}
-
-impl RASPComm for ThreadMode {
-    fn start_comm(
-        &mut self,
-        pid: i32,
-        _mnt_namespace: &String,
-        _probe_report_sender: Sender<plugins::Record>,
-        _patch_filed: HashMap<&'static str, String>,
-    ) -> AnyhowResult<()> {
-        match check_need_mount(_mnt_namespace) {
-            Ok(same_ns) => {
-                self.using_mount = same_ns;
-                info!("process {} namespace using_mount : {}", pid, self.using_mount);
-            }
-            Err(e) => {
-                warn!(
-                    "check_need_mount failed, {}", e
-                );
+    /// add `linked` to the links `pid` made in `mnt_namespace`
+    fn record_links(&mut self, pid: i32, mnt_namespace: &str, linked: Vec<String>) {
+        let (_, links) = self
+            .links
+            .entry(pid)
+            .or_insert_with(|| (mnt_namespace.to_string(), Vec::new()));
+        for link in linked {
+            if !links.contains(&link) {
+                links.push(link);
             }
         }
-        if self.using_mount {
-            if let Some(bind_dir) = std::path::Path::new(&self.bind_path.clone()).parent() {
-                    let mount_target = resolve_mount_path(bind_dir.to_string_lossy().into_owned(), pid);
-                    let bind_dir_str = bind_dir.to_str().unwrap();
-                    mount(pid, bind_dir_str, mount_target.as_str())?;
-                    info!("mount from {} to {} success", bind_dir_str, mount_target);
-            }
+    }
+    /// record links made by a stuck link thread after its timeout, so `remove_links`
+    /// cleans them up like any other
+    fn record_late_links(&mut self) {
+        for (pid, mnt_namespace, link) in self.link_threads.take_late() {
+            comm_log!(self.log_sink, Info, "late link in {} recorded: {}", mnt_namespace, link);
+            self.record_links(pid, &mnt_namespace, vec![link]);
         }
-        if let Some(linking_to) = self.linking_to.clone() {
-            let root_dir = format!("/proc/{}/root", pid);
-            let mut target = format!("{}{}", root_dir, linking_to);
-            
-            let resolved_path = resolve_symlink_path(target.clone());
-            if !resolved_path.as_str().starts_with(&root_dir) {
-                target = format!("/proc/{}/root{}", pid ,resolved_path);
-            } else {
-                target = resolved_path;
-            }
-
-            make_path_exist(target.clone());
-        
-            match fs::symlink(self.bind_path.clone(), target.clone()) {
-                Ok(()) => {
-                    info!("link {} to {} success", self.bind_path.clone(), target.clone());
-                }
-                Err(err) => {
-                    error!("LN can not run: {}, link from {}, to {}", err, self.bind_path.clone(), target.clone());
-                    return Err(anyhow!("link bind path failed: {}", err));
-                }
+    }
+    /// undo the bind mount of `start_comm` through the first of `pids` still in
+    /// `mnt_namespace`. with none left the namespace is gone, and its mounts with it
+    fn unmount(&self, pids: &[i32], mnt_namespace: &String, target: &str) -> AnyhowResult<()> {
+        let pid = match pid_in_namespace(pids, mnt_namespace) {
+            Some(pid) => pid,
+            None => {
+                comm_log!(
+                    self.log_sink,
+                    Info,
+                    "mnt namespace gone, nothing to unmount: {} {}",
+                    mnt_namespace,
+                    target
+                );
+                return Ok(());
             }
-        }
+        };
+        // lazily, a probe may still have the socket open
+        run_in_namespace(&self.nsenter_bin, pid, &["umount", "-l", target])?;
+        comm_log!(self.log_sink, Info, "umount {} in {} success", target, mnt_namespace);
         Ok(())
     }
-    fn stop_comm(&mut self, _pid: i32, _mnt_namespace: &String) -> AnyhowResult<()> {
-        Ok(())
+    /// preflight checks that would otherwise fail a later `start_comm`, run once at startup
+    pub fn self_test(&self) -> AnyhowResult<()> {
+        Self::check_bind_dir(&self.bind_path)
     }
-    fn send_message_to_probe(
+    /// enlarge (or shrink) the report buffer without losing buffered records
+    pub fn resize_report_buffer(&self, new_capacity: usize) -> AnyhowResult<()> {
+        self.report_buffer.resize(new_capacity)
+    }
+    /// every probe report, see `ReportBuffer::subscribe`
+    pub fn subscribe_reports(&self, capacity: usize) -> Receiver<plugins::Record> {
+        self.report_buffer.subscribe(capacity)
+    }
+    /// the server thread is still running and `bind_path` is a socket. false early on,
+    /// until the server got to bind, and for good once the thread exited
+    pub fn server_healthy(&self) -> bool {
+        !self.server_thread.is_finished()
+            && symlink_metadata(&self.bind_path)
+                .map(|metadata| metadata.file_type().is_socket())
+                .unwrap_or(false)
+    }
+    /// flush buffered reports on shutdown, the buffer refuses reports afterwards
+    pub fn drain_reports(&self) -> DrainReport {
+        let report = self.report_buffer.drain(self.drain_grace);
+        comm_log!(
+            self.log_sink,
+            Info,
+            "reports drained: {}, dropped: {}",
+            report.drained,
+            report.dropped
+        );
+        report
+    }
+    /// `start_comm` with the target mount namespace held open, see `open_mnt_namespace`.
+    /// the link is created in that very namespace through setns instead of `/proc/<pid>/root`
+    pub fn start_comm_in_namespace(
         &mut self,
         pid: i32,
-        _mnt_namespace: &String,
-        message: &String,
+        mnt_namespace: OwnedFd,
+        _probe_report_sender: Sender<plugins::Record>,
+        _patch_filed: HashMap<&'static str, String>,
     ) -> AnyhowResult<()> {
-        debug!("recv thread mode message: {}", message);
-        match self.agent_to_probe_sender.send((pid, message.clone())) {
-            Ok(_) => {
-                debug!("sending to probe: {} {}", pid, message.clone());
-            }
-            Err(SendError(e)) => {
-                error!("send error: {:?}", e);
-                let _ = self.ctrl.stop();
-                return Err(anyhow!("send message to probe failed: {} {}", e.0, e.1));
+        let mnt_namespace_name = mnt_namespace_of(&mnt_namespace)?;
+        // mount still goes through NSMount by pid
+        if mnt_namespace_of(&open_mnt_namespace(pid)?)? != mnt_namespace_name {
+            return Err(anyhow!(
+                "process {} left mnt namespace: {}",
+                pid,
+                mnt_namespace_name
+            ));
+        }
+        self.start_comm_with(pid, &mnt_namespace_name, Some(&mnt_namespace))
+    }
+    /// drop `pid` from the lazy mounts of `mnt_namespace`, the namespace with its last pid
+    fn remove_pending_mount(&mut self, pid: i32, mnt_namespace: &String) {
+        if let Some(pending) = self.pending_mounts.get_mut(mnt_namespace) {
+            pending.retain(|pending_pid| *pending_pid != pid);
+            if pending.is_empty() {
+                self.pending_mounts.remove(mnt_namespace);
             }
         }
-        Ok(())
+    }
+    fn start_comm_with(
+        &mut self,
+        pid: i32,
+        _mnt_namespace: &String,
+        mnt_namespace_fd: Option<&OwnedFd>,
+    ) -> AnyhowResult<()> {
+        let events = self.events.clone();
+        let resolver = self.container_id_resolver.clone();
+        with_operation_events(
+            &events,
+            &resolver,
+            CommOperation::StartComm,
+            "thread",
+            pid,
+            Some(_mnt_namespace),
+            || {
+                match check_need_mount(pid) {
+                    Ok(same_ns) => {
+                        self.using_mount = same_ns;
+                        comm_log!(
+                            self.log_sink,
+                            Info,
+                            "process {} namespace using_mount : {}",
+                            pid,
+                            self.using_mount
+                        );
+                    }
+                    Err(e) => {
+                        comm_log!(
+                            self.log_sink,
+                            Warn,
+                            "check_need_mount failed, {}", e
+                        );
+                    }
+                }
+                if self.using_mount && self.linking_to.is_empty() {
+                    match self.unlinked_mount {
+                        UnlinkedMount::Skip => {
+                            comm_log!(
+                                self.log_sink,
+                                Warn,
+                                "mount skipped, no linking_to for process: {}",
+                                pid
+                            );
+                            return Ok(());
+                        }
+                        UnlinkedMount::Refuse => {
+                            return Err(CommError::IncoherentConfig {
+                                reason: format!(
+                                    "mount without linking_to, process: {}",
+                                    pid
+                                ),
+                            }
+                            .into());
+                        }
+                    }
+                }
+                if self.using_mount {
+                    if let Some(bind_dir) = std::path::Path::new(&self.bind_path.clone()).parent() {
+                            let mount_target = resolve_mount_path(bind_dir.to_string_lossy().into_owned(), pid);
+                            let bind_dir_str = bind_dir.to_str().unwrap();
+                            let (retries, backoff) = (self.mount_retries, self.mount_retry_backoff);
+                            retry_mount(retries, backoff, &self.log_sink, || {
+                                mount(pid, bind_dir_str, mount_target.as_str(), self.verify_mount)
+                            })?;
+                            self.mounts
+                                .insert(_mnt_namespace.clone(), (pid, mount_target.clone()));
+                            comm_log!(
+                                self.log_sink,
+                                Info,
+                                "mount from {} to {} success",
+                                bind_dir_str,
+                                mount_target
+                            );
+                    }
+                }
+                // every link is tried, one failing does not keep the socket off the others
+                self.record_late_links();
+                let mut linked = Vec::new();
+                let mut failures: Vec<anyhow::Error> = Vec::new();
+                for template in self.linking_to.clone() {
+                    let linking_to = match expand_linking_to(&template, pid, _mnt_namespace) {
+                        Ok(linking_to) => linking_to,
+                        Err(e) => {
+                            failures.push(e);
+                            continue;
+                        }
+                    };
+                    let (retries, backoff) = (self.link_retries, self.link_retry_backoff);
+                    let timeout = self.link_timeout;
+                    let (target, link) = if mnt_namespace_fd.is_some() {
+                        (linking_to.clone(), linking_to)
+                    } else {
+                        let root_dir = format!("/proc/{}/root", pid);
+                        let mut target = format!("{}{}", root_dir, linking_to);
+            
+                        let resolved_path = resolve_symlink_path(target.clone());
+                        if !resolved_path.as_str().starts_with(&root_dir) {
+                            target = format!("/proc/{}/root{}", pid ,resolved_path);
+                        } else {
+                            target = resolved_path;
+                        }
+                        let link = target.strip_prefix(root_dir.as_str()).unwrap_or(&target).to_string();
+                        (target, link)
+                    };
+                    if self.link_threads.stuck(_mnt_namespace, &link) {
+                        comm_log!(
+                            self.log_sink,
+                            Warn,
+                            "link still pending, not started again: {}",
+                            target
+                        );
+                        failures.push(CommError::LinkTimeout { target, timeout }.into());
+                        continue;
+                    }
+                    let key = LinkKey {
+                        pid,
+                        mnt_namespace: _mnt_namespace.clone(),
+                        link: link.clone(),
+                    };
+                    let threads = &self.link_threads;
+                    let result = if let Some(mnt_namespace_fd) = mnt_namespace_fd {
+                        retry_link(retries, backoff, || {
+                            symlink_in_namespace(
+                                mnt_namespace_fd,
+                                &self.bind_path,
+                                &target,
+                                timeout,
+                                threads,
+                                key.clone(),
+                            )
+                        })
+                    } else {
+                        retry_link(retries, backoff, || {
+                            let (original, link) = (self.bind_path.clone(), target.clone());
+                            let key = key.clone();
+                            link_within("rasp_link", timeout, &target, threads, key, move || {
+                                let _ = make_path_exist(link.clone());
+                                fs::symlink(original, link).map_err(|e| e.into())
+                            })
+                        })
+                    };
+                    match result {
+                        Ok(()) => {
+                            comm_log!(
+                                self.log_sink,
+                                Info,
+                                "link {} to {} success",
+                                self.bind_path.clone(),
+                                target.clone()
+                            );
+                            linked.push(link);
+                        }
+                        Err((attempts, err)) => {
+                            comm_log!(
+                                self.log_sink,
+                                Error,
+                                "LN can not run: {}, link from {}, to {}",
+                                err,
+                                self.bind_path.clone(),
+                                target.clone()
+                            );
+                            let timed_out = matches!(
+                                err.downcast_ref::<CommError>(),
+                                Some(CommError::LinkTimeout { .. })
+                            );
+                            if timed_out {
+                                failures.push(err);
+                                continue;
+                            }
+                            failures.push(
+                                CommError::LinkFailed {
+                                    target,
+                                    attempts,
+                                    reason: err.to_string(),
+                                }
+                                .into(),
+                            );
+                        }
+                    }
+                }
+                if !linked.is_empty() {
+                    self.record_links(pid, _mnt_namespace, linked);
+                }
+                if failures.len() > 1 {
+                    let reasons: Vec<String> = failures.iter().map(|e| e.to_string()).collect();
+                    return Err(anyhow!(
+                        "{} of {} links failed: {}",
+                        failures.len(),
+                        self.linking_to.len(),
+                        reasons.join("; ")
+                    ));
+                }
+                if let Some(failure) = failures.pop() {
+                    return Err(failure);
+                }
+                Ok(())
+            },
+        )
     }
 }
 
-fn mount(pid: i32, from: &str, to: &str) -> AnyhowResult<()> {
-    let pid_str = pid.to_string();
-    let nsenter_str = settings::RASP_NS_ENTER_BIN();
-    let args = [pid_str.as_str(), from, to, nsenter_str.as_str()];
-    return match run_async_process(
-        std::process::Command::new(settings::RASP_MOUNT_SCRIPT_BIN()).args(args),
-    ) {
-        Ok((exit_status, stdout, stderr)) => {
-            if !exit_status.success() {
-                error!(
-                    "mount script execute failed: {} {} {}",
-                    exit_status, stdout, stderr
-                );
-                return Err(anyhow!(
-                    "mount script execute failed: {} {} {} ",
-                    exit_status,
-                    stdout,
-                    stderr
-                ));
-            }
-            debug!("mount success: {} {} {}", exit_status, stdout, stderr);
-            Ok(())
+/// run `link` up to `retries` more times while it fails with ENOENT, sleeping `backoff`
+/// doubled on every try. the error comes with the number of attempts made
+fn retry_link(
+    retries: u32,
+    backoff: Duration,
+    mut link: impl FnMut() -> AnyhowResult<()>,
+) -> Result<(), (u32, anyhow::Error)> {
+    let mut attempts = 0;
+    let mut backoff = backoff;
+    loop {
+        attempts += 1;
+        let err = match link() {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let not_found = matches!(
+            err.downcast_ref::<std::io::Error>(),
+            Some(e) if e.kind() == ErrorKind::NotFound
+        );
+        if !not_found || attempts > retries {
+            return Err((attempts, err));
         }
-        Err(e) => Err(anyhow!("can not mount: {}", e)),
-    };
+        debug!("link failed, retry in {:?}: {}", backoff, err);
+        thread::sleep(backoff);
+        backoff *= 2;
+    }
 }
 
-pub fn make_path_exist(path: String) -> AnyhowResult<()> {
-    // check socket exist or path not exist
-    let _ = remove_file(path.clone());
-    let current_path = std::path::Path::new(&path).parent().unwrap();
-    if !current_path.exists() {
-        create_dir_all(&current_path)?;
+/// run `mount` up to `retries` more times while it fails, sleeping `backoff` doubled on
+/// every try. paths missing or not writable fail the same on every run and are not retried
+fn retry_mount(
+    retries: u32,
+    backoff: Duration,
+    log_sink: &Option<LogSink>,
+    mut mount: impl FnMut() -> AnyhowResult<()>,
+) -> AnyhowResult<()> {
+    let mut attempts = 0;
+    let mut backoff = backoff;
+    loop {
+        attempts += 1;
+        let err = match mount() {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let lasting = matches!(
+            err.downcast_ref::<CommError>(),
+            Some(CommError::MountSourceMissing { .. }) | Some(CommError::ReadOnlyPath { .. })
+        );
+        if lasting || attempts > retries {
+            return Err(err);
+        }
+        comm_log!(
+            log_sink,
+            Warn,
+            "mount failed, retry {}/{} in {:?}: {}",
+            attempts,
+            retries,
+            backoff,
+            err
+        );
+        thread::sleep(backoff);
+        backoff *= 2;
     }
-    Ok(())
 }
 
-pub fn check_need_mount(pid_mntns: &String) -> AnyhowResult<bool> {
-    let root_mnt = std::fs::read_link("/proc/1/ns/mnt")?;
-    debug!(
-        "pid namespace && root namespace : {} && {}",
-        pid_mntns, root_mnt.display()
-    );
-    Ok(&root_mnt.display().to_string() != pid_mntns)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BroadcastStatus {
+    Succeeded,
+    Failed,
+    TimedOut,
 }
 
-fn resolve_mount_path(path: String, pid: i32) -> String {
-    let target_path = format!("/proc/{}/root{}", pid, path);
-    let current_path = std::path::Path::new(&target_path);
-    let mut find_path = current_path;
+/// mnt namespaces by broadcast outcome
+#[derive(Debug, Clone, Default)]
+pub struct BroadcastReport {
+    pub succeeded: Vec<String>,
+    pub failed: Vec<String>,
+    pub timed_out: Vec<String>,
+}
 
-    while !find_path.exists() {
-        if let Some(parent_path) = find_path.parent() {
-            find_path = parent_path;
-        }
+/// serializes operations on one mnt namespace while other namespaces proceed.
+/// waiters are woken in no particular order, so a stop queued behind a start on the same
+/// namespace sees the server once the start is done, and a stop getting the lock before
+/// any start finds nothing to stop
+#[derive(Clone, Default)]
+pub struct NamespaceLocks {
+    busy: Arc<(Mutex<HashSet<String>>, Condvar)>,
+}
+
+/// held namespace, released on drop
+pub struct NamespaceLock {
+    busy: Arc<(Mutex<HashSet<String>>, Condvar)>,
+    mnt_namespace: String,
+}
+
+impl NamespaceLocks {
+    pub fn new() -> Self {
+        Self::default()
     }
-    if find_path.exists() {
-        if let Ok(subfix_path) = current_path.strip_prefix(find_path) {
-            let parent_resolve = resolve_symlink_path(find_path.to_string_lossy().into_owned());
-            if parent_resolve == find_path.to_string_lossy().into_owned() {
-                return path;
-            } 
-            else {
-                return format!("{}/{}", parent_resolve, subfix_path.display());
-            }
+    pub fn lock(&self, mnt_namespace: &str) -> NamespaceLock {
+        let (busy, released) = &*self.busy;
+        let mut busy = busy.lock().unwrap();
+        while busy.contains(mnt_namespace) {
+            busy = released.wait(busy).unwrap();
+        }
+        busy.insert(mnt_namespace.to_string());
+        NamespaceLock {
+            busy: self.busy.clone(),
+            mnt_namespace: mnt_namespace.to_string(),
         }
     }
-    path
+    /// namespaces currently locked
+    pub fn held(&self) -> usize {
+        self.busy.0.lock().unwrap().len()
+    }
 }
 
-fn resolve_symlink_path(path: String) -> String {
-    let new_path = std::path::Path::new(&path);
-    let check_path = new_path.parent().unwrap();
-    let file_name = new_path.file_name();
-    if let Ok(metadata) = symlink_metadata(check_path) {
-        if metadata.file_type().is_symlink() {
-            if let Ok(real_path) = read_link(check_path) {
-                let mut resolved_path = real_path;
-                if !resolved_path.is_absolute() {
-                    resolved_path = check_path.parent().unwrap().join(resolved_path);
-                }
-                if resolved_path.parent().is_none() {
-                    return format!("/{}", file_name.unwrap().to_string_lossy());
-                }
-                else {
-                    return format!("{}/{}", resolved_path.to_string_lossy(), file_name.unwrap().to_string_lossy());
-                }
-            }
-        }
+impl Drop for NamespaceLock {
+    fn drop(&mut self) {
+        let (busy, released) = &*self.busy;
+        busy.lock().unwrap().remove(&self.mnt_namespace);
+        released.notify_all();
     }
-
-    path
 }
 
-pub struct EbpfMode {
+pub struct ProcessMode {
     pub ctrl: Control,
-    pub kernel_version: procfs::sys::kernel::Version,
-    pub stdin: Option<ChildStdin>,
-    pub stdout: Option<ChildStdout>,
+    pub log_level: String,
+    pub mnt_namesapce_server_map: HashMap<String, libraspserver::process_mode::RASPServerProcess>,
+    pub mnt_namespace_comm_pair: HashMap<String, (Sender<String>, Receiver<String>)>,
+    pub events: Option<Sender<CommEvent>>,
+    pub pings: ProbePings,
+    pub log_sink: Option<LogSink>,
+    /// report buffer of each namespace server, all sized `report_capacity`
+    pub report_buffers: HashMap<String, ReportBuffer>,
+    pub report_capacity: usize,
+    /// messages queued for the probes of each new server, past that
+    /// `send_message_to_probe` fails with `CommError::Backpressure`
+    pub message_capacity: usize,
+    pub container_id_resolver: Option<ContainerIdResolver>,
+    /// container id of each served namespace, when resolvable
+    pub mnt_namespace_container_id: HashMap<String, String>,
+    /// cpus servers are pinned to, see `check_cpu_affinity`
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// configs every new server sends its probes on connect,
+    /// see `libraspserver::process_mode::INITIAL_CONFIG_ENV`
+    pub initial_config: Option<Vec<libraspserver::proto::PidMissingProbeConfig>>,
+    /// added to the report buffer of every server, see `subscribe_reports`
+    pub report_subscribers: Vec<Sender<plugins::Record>>,
+    /// servers running longer are recycled by `reap_expired_servers`
+    pub max_server_lifetime: Option<Duration>,
+    /// `reap_expired_servers` recycles one server at a time, at least this far apart
+    pub recycle_min_interval: Duration,
+    pub last_recycle: Option<Instant>,
+    /// spawn time of each namespace server
+    pub server_started: HashMap<String, Instant>,
+    pub recycle_count: u64,
+    /// taken by `start_comm`, `stop_comm` and `recycle` for their namespace
+    pub namespace_locks: NamespaceLocks,
+    /// time `stop_comm` gives buffered reports of the stopped server to reach the consumer
+    pub drain_grace: Duration,
+    /// time `stop_comm` gives a SIGTERMed server to exit before killing it
+    pub stop_grace: Duration,
+    /// summed over every `stop_comm`
+    pub drain_totals: DrainReport,
+    /// per served namespace, kept across `recycle`, reset by `stop_comm`
+    pub namespace_stats: HashMap<String, NamespaceStats>,
+    /// pids sharing the server of each namespace, the server stops with the last of them
+    pub namespace_pids: HashMap<String, HashSet<i32>>,
+    /// rasp_server spawned for every namespace
+    pub server_bin: String,
 }
 
-impl EbpfMode {
-    pub fn new(ctrl: Control) -> AnyhowResult<Self> {
-        let ebpf_manager = Self {
-            ctrl,
-            kernel_version: Self::detect_kernel_version()?,
-            stdin: None,
-            stdout: None,
-        };
-        let _ = ebpf_manager.switch_bpf_main_process()?;
-        Ok(ebpf_manager)
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamespaceStats {
+    /// `start_comm` of the namespace, a recycled server does not move it
+    pub started_at: Instant,
+    /// pids served, counting the one the server was started for
+    pub attach_count: u64,
+    pub recycle_count: u64,
+}
+
+impl NamespaceStats {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            attach_count: 1,
+            recycle_count: 0,
+        }
     }
-    pub fn detect_kernel_version() -> AnyhowResult<procfs::sys::kernel::Version> {
-        let kernel_version = procfs::sys::kernel::Version::current()?;
-        info!(
-            "current kernel version: {}.{}",
-            kernel_version.major, kernel_version.minor
-        );
-        Ok(kernel_version)
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
     }
-    pub fn switch_bpf_main_process(&self) -> AnyhowResult<String> {
-        /*
-        [4.14, 4.16) minimal support
-        [4.16, 5.2) http support(without header)
-        [5.2,  5.8) http support(with header)
-        [5.8,  current) http support(with header), ring buffer support
-        */
-        let bpf_process_version =
-            if self.kernel_version >= procfs::sys::kernel::Version::new(5, 8, 0) {
-                "_5.8"
-            } else if self.kernel_version >= procfs::sys::kernel::Version::new(5, 2, 0) {
-                "_5.2"
-            } else if self.kernel_version >= procfs::sys::kernel::Version::new(4, 16, 0) {
-                "_4.16"
-            } else if self.kernel_version >= procfs::sys::kernel::Version::new(4, 14, 0) {
-                "_4.14"
-            } else {
-                return Err(anyhow!(
-                    "version: {}.{} kernel not support",
-                    self.kernel_version.major,
-                    self.kernel_version.minor,
-                ));
-            };
-        return Ok(bpf_process_version.to_string());
+}
+
+impl Default for NamespaceStats {
+    fn default() -> Self {
+        Self::new()
     }
-    pub fn start_server(&mut self) -> AnyhowResult<()> {
-        let bin_path = settings::RASP_GOLANG_EBPF(&self.switch_bpf_main_process()?);
-        let mut child = std::process::Command::new(bin_path)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null())
-            .spawn()?;
-        debug!("spawn ebpf process success: {}", child.id());
-        let child_id = child.id();
-        self.stdin = child.stdin.take();
-        self.stdout = child.stdout.take();
-        /*
-            if self.stdin.is_none() {
-                return Err(anyhow!("can not take child stdin, pid: {}", child_id));
-            }
-            if self.stdout.is_none() {
-                return Err(anyhow!("can not take child stdout, pid: {}", child_id));
-            }
-        */
-        // start a thread for wait child die
-        let mut wait_ctrl = self.ctrl.clone();
-        thread::Builder::new()
-            .name("ebpf_server_wait".to_string())
-            .spawn(move || loop {
-                if !wait_ctrl.check() {
-                    Self::kill_server(child_id as i32);
-                    return;
-                }
-                match child.try_wait() {
-                    Ok(Some(status)) => {
-                        info!("Golang EBPF daemon exit with status: {}", status);
-                        return;
-                    }
-                    Ok(None) => {
-			thread::sleep(Duration::from_secs(10));
-		    }
-                    Err(e) => {
-                        error!("error attempting to wait: {}", e);
-                        Self::kill_server(child_id as i32);
-                        return;
-                    }
-                }
-            })?;
-        // sleep here for subprocess ready for listen stdin
-        thread::sleep(Duration::from_secs(2));
-        Ok(())
+}
+
+const PROCESS_RECYCLE_MIN_INTERVAL: Duration = Duration::from_secs(60);
+const PROCESS_STOP_GRACE: Duration = Duration::from_secs(3);
+
+impl ProcessMode {
+    pub fn new(log_level: String, ctrl: Control) -> Self {
+        Self {
+            ctrl,
+            log_level,
+            mnt_namesapce_server_map: HashMap::new(),
+            mnt_namespace_comm_pair: HashMap::new(),
+            events: None,
+            pings: ProbePings::new(),
+            log_sink: None,
+            report_buffers: HashMap::new(),
+            report_capacity: 50,
+            message_capacity: PROBE_MESSAGE_CAPACITY,
+            container_id_resolver: None,
+            mnt_namespace_container_id: HashMap::new(),
+            cpu_affinity: None,
+            initial_config: None,
+            report_subscribers: Vec::new(),
+            max_server_lifetime: None,
+            recycle_min_interval: PROCESS_RECYCLE_MIN_INTERVAL,
+            last_recycle: None,
+            server_started: HashMap::new(),
+            recycle_count: 0,
+            namespace_locks: NamespaceLocks::new(),
+            drain_grace: REPORT_DRAIN_GRACE,
+            stop_grace: PROCESS_STOP_GRACE,
+            drain_totals: DrainReport::default(),
+            namespace_stats: HashMap::new(),
+            namespace_pids: HashMap::new(),
+            server_bin: settings::RASP_SERVER_BIN(),
+        }
     }
-    pub fn attach(&mut self, pid: i32) -> AnyhowResult<bool> {
-        self.write_stdin(pid)?;
-        match self.read_stdout(pid) {
-            Ok(result) => {
-                if !result.is_empty() {
-                    return Ok(false);
-                }
+    /// send `message` to the probes of every served namespace, a full channel gets
+    /// `timeout` before it is given up on. `progress` sees each namespace as it is done
+    pub fn broadcast_to_probes(
+        &mut self,
+        message: &String,
+        timeout: Duration,
+        progress: Option<&dyn Fn(&String, BroadcastStatus)>,
+    ) -> BroadcastReport {
+        let mut report = BroadcastReport::default();
+        for (mnt_namespace, (sender, _)) in self.mnt_namespace_comm_pair.iter() {
+            let status = match sender.send_timeout(message.clone(), timeout) {
+                Ok(_) => BroadcastStatus::Succeeded,
+                Err(SendTimeoutError::Timeout(_)) => BroadcastStatus::TimedOut,
+                Err(SendTimeoutError::Disconnected(_)) => BroadcastStatus::Failed,
+            };
+            match status {
+                BroadcastStatus::Succeeded => report.succeeded.push(mnt_namespace.clone()),
+                BroadcastStatus::Failed => report.failed.push(mnt_namespace.clone()),
+                BroadcastStatus::TimedOut => report.timed_out.push(mnt_namespace.clone()),
             }
-            Err(e) => {
-                error!("ebpf running abnormally: {}, quiting.", e);
-                let _ = self.ctrl.stop();
-                return Err(e);
+            if let Some(progress) = progress {
+                progress(mnt_namespace, status);
             }
         }
-        Ok(true)
+        report
     }
-    pub fn write_stdin(&mut self, pid: i32) -> AnyhowResult<()> {
-        let mut stdin = self.stdin.as_ref().unwrap();
-        stdin.write_all(format!("{}\n", pid).as_bytes())?;
-        stdin.flush()?;
+    /// enlarge (or shrink) the report buffers of running and future servers
+    pub fn resize_report_buffer(&mut self, new_capacity: usize) -> AnyhowResult<()> {
+        for report_buffer in self.report_buffers.values() {
+            report_buffer.resize(new_capacity)?;
+        }
+        self.report_capacity = new_capacity;
         Ok(())
     }
-    pub fn read_stdout(&mut self, pid: i32) -> AnyhowResult<String> {
-        let mut buf_reader = if let Some(stdout) = self.stdout.take() {
-            BufReader::new(stdout)
-        } else {
-            return Err(anyhow!(""));
-        };
-        let mut times = 10;
-        let interval = 1; // second
-        loop {
-            times -= 1;
-            if times <= 0 {
-                return Err(anyhow!("read stdout from ebpf server timeout: {}", pid));
-            }
-            if buf_reader.fill_buf()?.len() <= 0 {
-                std::thread::sleep(Duration::from_secs(interval));
-                continue;
-            }
-            let mut read_from_server = String::new();
-            let size = buf_reader.read_line(&mut read_from_server)?;
-            if size == 0 {
-                return Err(anyhow!("read stdout from ebpf server EOF"));
-            }
-            let (pid_from_server, success) = Self::parse_server_response(&read_from_server)?;
-            if pid_from_server != pid {
+    /// probe reports of running and future servers, see `ReportBuffer::subscribe`
+    pub fn subscribe_reports(&mut self, capacity: usize) -> Receiver<plugins::Record> {
+        let (sender, receiver) = bounded(capacity);
+        for report_buffer in self.report_buffers.values() {
+            report_buffer.add_subscriber(sender.clone());
+        }
+        self.report_subscribers.push(sender);
+        receiver
+    }
+    /// restart the server of `mnt_namespace` in place, its probes reconnect to the new one
+    pub fn recycle(&mut self, mnt_namespace: &String) -> AnyhowResult<()> {
+        let server_bin = self.server_bin.clone();
+        self.recycle_with(mnt_namespace, |server| server.spawn(&server_bin))
+    }
+    fn recycle_with(
+        &mut self,
+        mnt_namespace: &String,
+        respawn: impl FnOnce(&mut RASPServerProcess) -> AnyhowResult<()>,
+    ) -> AnyhowResult<()> {
+        let _namespace = self.namespace_locks.lock(mnt_namespace);
+        let events = self.events.clone();
+        let resolver = self.container_id_resolver.clone();
+        let server = match self.mnt_namesapce_server_map.get_mut(mnt_namespace) {
+            Some(server) => server,
+            None => {
                 return Err(anyhow!(
-                    "pid miss match: expect: {} response: {}",
-                    pid,
-                    pid_from_server
-                ));
+                    "didn't start server for mnt namespace: {}",
+                    mnt_namespace
+                ))
             }
-            if success {
-                return Ok(String::new());
-            } else {
-                return Ok(format!("target pid: {} attach failed", pid));
+        };
+        let pid = server.pid;
+        with_operation_events(
+            &events,
+            &resolver,
+            CommOperation::Recycle,
+            "process",
+            pid,
+            Some(mnt_namespace),
+            || {
+                server.kill();
+                respawn(server)
+            },
+        )?;
+        comm_log!(self.log_sink, Info, "recycled server: {}", mnt_namespace);
+        self.server_started
+            .insert(mnt_namespace.clone(), Instant::now());
+        self.recycle_count += 1;
+        if let Some(stats) = self.namespace_stats.get_mut(mnt_namespace) {
+            stats.recycle_count += 1;
+        }
+        Ok(())
+    }
+    /// messages queued for the probes of `mnt_namespace` and not taken by its server yet
+    pub fn queued_messages(&self, mnt_namespace: &String) -> Option<usize> {
+        self.mnt_namespace_comm_pair
+            .get(mnt_namespace)
+            .map(|(sender, _)| sender.len())
+    }
+    /// whether the server of `mnt_namespace` was spawned and has not exited since
+    pub fn server_alive(&self, mnt_namespace: &String) -> bool {
+        self.mnt_namesapce_server_map
+            .get(mnt_namespace)
+            .map_or(false, |server| server.is_running())
+    }
+    /// respawn in place every server found exited, a server failing to respawn is dropped
+    /// with its namespace. meant to be polled like `reap_expired_servers`
+    pub fn restart_dead_servers(&mut self) -> Vec<String> {
+        let server_bin = self.server_bin.clone();
+        self.restart_dead_servers_with(|server| server.spawn(&server_bin))
+    }
+    fn restart_dead_servers_with(
+        &mut self,
+        mut respawn: impl FnMut(&mut RASPServerProcess) -> AnyhowResult<()>,
+    ) -> Vec<String> {
+        let mut dead: Vec<String> = self
+            .mnt_namesapce_server_map
+            .keys()
+            .filter(|mnt_namespace| !self.server_alive(mnt_namespace))
+            .cloned()
+            .collect();
+        dead.sort();
+        let mut restarted = Vec::new();
+        for mnt_namespace in dead {
+            comm_log!(self.log_sink, Warn, "server of {} exited, restarting it", mnt_namespace);
+            match self.recycle_with(&mnt_namespace, &mut respawn) {
+                Ok(()) => restarted.push(mnt_namespace),
+                Err(e) => {
+                    comm_log!(
+                        self.log_sink,
+                        Error,
+                        "restart server of {} failed, dropping it: {}",
+                        mnt_namespace,
+                        e
+                    );
+                    self.remove_server(&mnt_namespace);
+                }
             }
         }
+        restarted
     }
-    pub fn kill_server(pid: i32) {
-        unsafe {
-            killpg(pid, SIGKILL);
-            kill(pid as i32, SIGKILL);
+    /// `pid` is served by the running server of `mnt_namespace` too
+    pub fn record_attach(&mut self, pid: i32, mnt_namespace: &String) {
+        self.namespace_pids
+            .entry(mnt_namespace.clone())
+            .or_default()
+            .insert(pid);
+        if let Some(stats) = self.namespace_stats.get_mut(mnt_namespace) {
+            stats.attach_count += 1;
         }
     }
-    pub fn parse_server_response(response: &String) -> AnyhowResult<(i32, bool)> {
-        let regex = regex::Regex::new(r"(\d{1,20}):(succeed|failed)")?;
-        if let Some(caps) = regex.captures(response) {
-            if caps.len() != 3 {
-                return Err(anyhow!("response format can not parse: {}", response));
-            }
-            // pid
-            let pid: i32 = if let Some(pid) = caps.get(1) {
-                pid.as_str().parse()?
-            } else {
-                return Err(anyhow!("response format can not parse: {}", response));
+    /// stop the server of `mnt_namespace` and forget everything about it, false when
+    /// there was none
+    fn remove_server(&mut self, mnt_namespace: &String) -> bool {
+        let mut runner = match self.mnt_namesapce_server_map.remove(mnt_namespace) {
+            Some(runner) => runner,
+            None => return false,
+        };
+        if !runner.stop_gracefully(self.stop_grace) {
+            comm_log!(
+                self.log_sink,
+                Warn,
+                "server of {} killed, not stopped within {:?}",
+                mnt_namespace,
+                self.stop_grace
+            );
+        }
+        if let Some(report_buffer) = self.report_buffers.remove(mnt_namespace) {
+            let report = report_buffer.drain(self.drain_grace);
+            comm_log!(
+                self.log_sink,
+                Info,
+                "reports of {} drained: {}, dropped: {}",
+                mnt_namespace,
+                report.drained,
+                report.dropped
+            );
+            self.drain_totals.add(report);
+        }
+        self.mnt_namespace_comm_pair.remove(mnt_namespace);
+        self.server_started.remove(mnt_namespace);
+        self.namespace_stats.remove(mnt_namespace);
+        self.namespace_pids.remove(mnt_namespace);
+        self.mnt_namespace_container_id.remove(mnt_namespace);
+        true
+    }
+    /// served namespaces and their stats, sorted by namespace
+    pub fn managed(&self) -> Vec<(String, NamespaceStats)> {
+        let mut managed: Vec<(String, NamespaceStats)> = self
+            .namespace_stats
+            .iter()
+            .map(|(mnt_namespace, stats)| (mnt_namespace.clone(), stats.clone()))
+            .collect();
+        managed.sort_by(|a, b| a.0.cmp(&b.0));
+        managed
+    }
+    /// recycle the oldest server running past `max_server_lifetime`, if
+    /// `recycle_min_interval` passed since the last one. meant to be polled, a busy host
+    /// with many old servers gets them restarted one by one
+    pub fn reap_expired_servers(&mut self) -> Option<String> {
+        let server_bin = self.server_bin.clone();
+        self.reap_expired_servers_with(|server| server.spawn(&server_bin))
+    }
+    fn reap_expired_servers_with(
+        &mut self,
+        respawn: impl FnOnce(&mut RASPServerProcess) -> AnyhowResult<()>,
+    ) -> Option<String> {
+        let max_server_lifetime = self.max_server_lifetime?;
+        let now = Instant::now();
+        if let Some(last_recycle) = self.last_recycle {
+            if now < last_recycle + self.recycle_min_interval {
+                return None;
+            }
+        }
+        let mnt_namespace = self
+            .server_started
+            .iter()
+            .filter(|(_, started)| now.duration_since(**started) >= max_server_lifetime)
+            .min_by_key(|(_, started)| **started)
+            .map(|(mnt_namespace, _)| mnt_namespace.clone())?;
+        self.last_recycle = Some(now);
+        if let Err(e) = self.recycle_with(&mnt_namespace, respawn) {
+            comm_log!(self.log_sink, Warn, "recycle server {} failed: {}", mnt_namespace, e);
+        }
+        Some(mnt_namespace)
+    }
+    /// clear guard state so the next call is tried right away, returns what was reset
+    pub fn reset_protections(&mut self) -> Vec<&'static str> {
+        let mut reset = Vec::new();
+        if self.last_recycle.take().is_some() {
+            reset.push("recycle cooldown");
+        }
+        for guard in reset.iter() {
+            comm_log!(self.log_sink, Info, "process protection reset: {}", guard);
+        }
+        reset
+    }
+}
+
+impl RASPComm for ProcessMode {
+    fn start_comm(
+        &mut self,
+        pid: i32,
+        mnt_namespace: &String,
+        probe_report_sender: Sender<plugins::Record>,
+        patch_field: HashMap<&'static str, String>,
+    ) -> AnyhowResult<()> {
+        let _namespace = self.namespace_locks.lock(mnt_namespace);
+        let events = self.events.clone();
+        let resolver = self.container_id_resolver.clone();
+        with_operation_events(
+            &events,
+            &resolver,
+            CommOperation::StartComm,
+            "process",
+            pid,
+            Some(mnt_namespace),
+            || {
+                // one server per namespace, `probe_report_sender` of the first pid is kept
+                let running = self
+                    .mnt_namesapce_server_map
+                    .get(mnt_namespace)
+                    .map(|runner| runner.is_running());
+                match running {
+                    Some(true) => {
+                        comm_log!(self.log_sink, Debug, "reusing server of {}", mnt_namespace);
+                        if let Some(runner) = self.mnt_namesapce_server_map.get_mut(mnt_namespace)
+                        {
+                            runner.update_patch_field(patch_field);
+                        }
+                        self.record_attach(pid, mnt_namespace);
+                        return Ok(());
+                    }
+                    Some(false) => {
+                        comm_log!(self.log_sink, Warn, "server of {} gone, replacing it", mnt_namespace);
+                        self.remove_server(mnt_namespace);
+                    }
+                    None => {}
+                }
+                if let Some(cpus) = self.cpu_affinity.as_ref() {
+                    check_cpu_affinity(cpus)?;
+                }
+                let (probe_mesasge_sender, probe_message_receiver) =
+                    bounded(self.message_capacity);
+                let report_buffer = ReportBuffer::new(self.report_capacity);
+                for subscriber in self.report_subscribers.iter() {
+                    report_buffer.add_subscriber(subscriber.clone());
+                }
+                report_buffer.forward_to(probe_report_sender)?;
+                let mut server_process = libraspserver::process_mode::RASPServerProcess::new(
+                    pid,
+                    self.pings.tap(report_buffer.clone())?,
+                    probe_message_receiver.clone(),
+                    self.log_level.clone(),
+                    patch_field,
+                    libraspserver::utils::Control {
+                        working_atomic: self.ctrl.working_atomic.clone(),
+                        control: self.ctrl.control.clone(),
+                    },
+                )?;
+                if let Some(cpus) = self.cpu_affinity.clone() {
+                    server_process.set_cpu_affinity(cpus);
+                }
+                if let Some(configs) = self.initial_config.clone() {
+                    server_process.set_initial_config(configs);
+                }
+                server_process.spawn(&self.server_bin)?;
+                self.mnt_namesapce_server_map
+                    .insert(mnt_namespace.clone(), server_process);
+                self.mnt_namespace_comm_pair.insert(
+                    mnt_namespace.clone(),
+                    (probe_mesasge_sender, probe_message_receiver),
+                );
+                self.report_buffers.insert(mnt_namespace.clone(), report_buffer);
+                self.server_started
+                    .insert(mnt_namespace.clone(), Instant::now());
+                self.namespace_stats
+                    .insert(mnt_namespace.clone(), NamespaceStats::new());
+                self.namespace_pids
+                    .insert(mnt_namespace.clone(), HashSet::from([pid]));
+                if let Some(resolve) = self.container_id_resolver.as_ref() {
+                    if let Some(container_id) = resolve(mnt_namespace, pid) {
+                        self.mnt_namespace_container_id
+                            .insert(mnt_namespace.clone(), container_id);
+                    }
+                }
+                Ok(())
+            },
+        )
+    }
+
+    fn stop_comm(&mut self, pid: i32, mnt_namespace: &String) -> AnyhowResult<()> {
+        let _namespace = self.namespace_locks.lock(mnt_namespace);
+        let events = self.events.clone();
+        let resolver = self.container_id_resolver.clone();
+        with_operation_events(
+            &events,
+            &resolver,
+            CommOperation::StopComm,
+            "process",
+            pid,
+            Some(mnt_namespace),
+            || {
+                // a pid not sharing the server, like the 0 of a shutdown, stops it regardless
+                if let Some(pids) = self.namespace_pids.get_mut(mnt_namespace) {
+                    if pids.remove(&pid) && !pids.is_empty() {
+                        comm_log!(
+                            self.log_sink,
+                            Info,
+                            "server of {} kept for {} more pids",
+                            mnt_namespace,
+                            pids.len()
+                        );
+                        return Ok(());
+                    }
+                }
+                comm_log!(self.log_sink, Info, "stop server: {}", mnt_namespace.clone());
+                if self.remove_server(mnt_namespace) {
+                    Ok(())
+                } else {
+                    Err(anyhow!(
+                        "didn't start server for mnt namespace: {}",
+                        mnt_namespace.clone()
+                    ))
+                }
+            },
+        )
+    }
+    fn send_message_to_probe(
+        &mut self,
+        _pid: i32,
+        mnt_namespace: &String,
+        message: &String,
+    ) -> AnyhowResult<()> {
+        if self.mnt_namesapce_server_map.contains_key(mnt_namespace)
+            && !self.server_alive(mnt_namespace)
+        {
+            // nobody reads the channel anymore, the message would be lost without a word
+            self.remove_server(mnt_namespace);
+            return Err(anyhow!("server of {} exited, dropped", mnt_namespace));
+        }
+        let p = match self.mnt_namespace_comm_pair.get(mnt_namespace) {
+            Some(p) => p,
+            None => return Err(anyhow!("no comm pair for namespace {}", mnt_namespace)),
+        };
+        match p.0.try_send(message.clone()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                let queued = p.0.len();
+                let capacity = p.0.capacity().unwrap_or(queued);
+                comm_log!(
+                    self.log_sink,
+                    Warn,
+                    "probe messages of {} backed up: {}/{}",
+                    mnt_namespace,
+                    queued,
+                    capacity
+                );
+                Err(CommError::Backpressure { queued, capacity }.into())
+            }
+            Err(e) => Err(anyhow!("send to probe failed: {}", e.to_string())),
+        }
+    }
+    fn ping_probe(
+        &mut self,
+        pid: i32,
+        mnt_namespace: &String,
+        timeout: Duration,
+    ) -> AnyhowResult<Duration> {
+        let pings = self.pings.clone();
+        pings.ping(pid, timeout, |message| {
+            self.send_message_to_probe(pid, mnt_namespace, message)
+        })
+    }
+    fn supported_features(&self) -> FeatureSet {
+        FeatureSet {
+            ping: true,
+            ..Default::default()
+        }
+    }
+    fn transport_info(&self, mnt_namespace: &String) -> Option<TransportInfo> {
+        if !self.mnt_namesapce_server_map.contains_key(mnt_namespace) {
+            return None;
+        }
+        Some(TransportInfo {
+            bind_path: String::from(PROCESS_MODE_BIND_PATH),
+            link_targets: Vec::new(),
+            transport: Transport::Process,
+        })
+    }
+}
+
+impl RASPComm for ThreadMode {
+    fn start_comm(
+        &mut self,
+        pid: i32,
+        _mnt_namespace: &String,
+        _probe_report_sender: Sender<plugins::Record>,
+        _patch_filed: HashMap<&'static str, String>,
+    ) -> AnyhowResult<()> {
+        if self.lazy_mount {
+            comm_log!(self.log_sink, Debug, "mount deferred: {} {}", pid, _mnt_namespace);
+            let pending = self.pending_mounts.entry(_mnt_namespace.clone()).or_default();
+            if !pending.contains(&pid) {
+                pending.push(pid);
+            }
+            return Ok(());
+        }
+        self.start_comm_with(pid, _mnt_namespace, None)
+    }
+    fn stop_comm(&mut self, pid: i32, mnt_namespace: &String) -> AnyhowResult<()> {
+        self.remove_pending_mount(pid, mnt_namespace);
+        let events = self.events.clone();
+        let resolver = self.container_id_resolver.clone();
+        with_operation_events(
+            &events,
+            &resolver,
+            CommOperation::StopComm,
+            "thread",
+            pid,
+            Some(mnt_namespace),
+            || {
+                self.remove_links(pid, mnt_namespace)?;
+                match self.mounts.remove(mnt_namespace) {
+                    Some((mounted_by, target)) => {
+                        self.unmount(&[pid, mounted_by], mnt_namespace, &target)
+                    }
+                    None => Ok(()),
+                }
+            },
+        )
+    }
+    fn send_message_to_probe(
+        &mut self,
+        pid: i32,
+        _mnt_namespace: &String,
+        message: &String,
+    ) -> AnyhowResult<()> {
+        if let Some(mount_pids) = self.pending_mounts.get(_mnt_namespace).cloned() {
+            for mount_pid in mount_pids {
+                // kept pending on failure, the next send tries again
+                self.start_comm_with(mount_pid, _mnt_namespace, None).map_err(|e| {
+                    anyhow!("deferred mount for {} failed: {}", _mnt_namespace, e)
+                })?;
+                self.remove_pending_mount(mount_pid, _mnt_namespace);
+            }
+        }
+        comm_log!(self.log_sink, Debug, "recv thread mode message: {}", message);
+        match self.agent_to_probe_sender.try_send((pid, message.clone())) {
+            Ok(_) => {
+                comm_log!(self.log_sink, Debug, "sending to probe: {} {}", pid, message.clone());
+            }
+            Err(TrySendError::Full(_)) => {
+                let queued = self.agent_to_probe_sender.len();
+                let capacity = self.agent_to_probe_sender.capacity().unwrap_or(queued);
+                comm_log!(self.log_sink, Warn, "probe messages backed up: {}/{}", queued, capacity);
+                return Err(CommError::Backpressure { queued, capacity }.into());
+            }
+            Err(TrySendError::Disconnected(e)) => {
+                comm_log!(self.log_sink, Error, "send error: {:?}", e);
+                let _ = self
+                    .ctrl
+                    .stop_with_reason(format!("send message to probe failed: {}", e.0));
+                return Err(anyhow!("send message to probe failed: {} {}", e.0, e.1));
+            }
+        }
+        Ok(())
+    }
+    fn ping_probe(
+        &mut self,
+        pid: i32,
+        mnt_namespace: &String,
+        timeout: Duration,
+    ) -> AnyhowResult<Duration> {
+        let pings = self.pings.clone();
+        pings.ping(pid, timeout, |message| {
+            self.send_message_to_probe(pid, mnt_namespace, message)
+        })
+    }
+    fn supported_features(&self) -> FeatureSet {
+        FeatureSet {
+            ping: true,
+            ..Default::default()
+        }
+    }
+    fn transport_info(&self, mnt_namespace: &String) -> Option<TransportInfo> {
+        Some(TransportInfo {
+            bind_path: self.bind_path.clone(),
+            link_targets: self
+                .linking_to
+                .iter()
+                .map(|template| expand_mnt_namespace(template, mnt_namespace))
+                .collect(),
+            transport: Transport::Thread,
+        })
+    }
+}
+
+/// what `EbpfMode::read_probe_response_before` woke up to
+enum Received {
+    Routed((i32, ProbeResponse)),
+    Line(Result<Result<String, CommError>, RecvTimeoutError>),
+}
+
+/// answer handed over by `ResponseRouter`, tagged with its pid by `register_many` only
+trait RoutedResponse {
+    /// `pid` is the one waited on, for answers not tagged
+    fn tagged(self, pid: i32) -> (i32, ProbeResponse);
+}
+
+impl RoutedResponse for ProbeResponse {
+    fn tagged(self, pid: i32) -> (i32, ProbeResponse) {
+        (pid, self)
+    }
+}
+
+impl RoutedResponse for (i32, ProbeResponse) {
+    fn tagged(self, _: i32) -> (i32, ProbeResponse) {
+        self
+    }
+}
+
+/// result of `read_line_capped`
+#[derive(Debug, PartialEq)]
+enum CappedLine {
+    Eof,
+    Line,
+    /// `length` bytes up to the newline, only the first `max` were kept
+    TooLong { length: usize },
+}
+
+/// `read_line` keeping at most `max` bytes of the line, newline included, in `line`.
+/// the rest of an oversized line is read and thrown away, memory stays bounded however
+/// long the line is
+fn read_line_capped(
+    reader: &mut impl BufRead,
+    line: &mut Vec<u8>,
+    max: usize,
+) -> std::io::Result<CappedLine> {
+    let mut length = 0;
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let (chunk, done) = match available.iter().position(|b| *b == b'\n') {
+            Some(newline) => (&available[..=newline], true),
+            None => (available, available.is_empty()),
+        };
+        let keep = max.saturating_sub(line.len()).min(chunk.len());
+        line.extend_from_slice(&chunk[..keep]);
+        let used = chunk.len();
+        reader.consume(used);
+        length += used;
+        if done {
+            return Ok(match length {
+                0 => CappedLine::Eof,
+                length if length > max => CappedLine::TooLong { length },
+                _ => CappedLine::Line,
+            });
+        }
+    }
+}
+
+/// cpus in `/sys/devices/system/cpu/online`, like `0-3,6`
+pub fn online_cpus() -> AnyhowResult<Vec<usize>> {
+    parse_cpu_list(&std::fs::read_to_string("/sys/devices/system/cpu/online")?)
+}
+
+fn parse_cpu_list(list: &str) -> AnyhowResult<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let first: usize = bounds.next().unwrap_or_default().parse()?;
+        let last: usize = match bounds.next() {
+            Some(last) => last.parse()?,
+            None => first,
+        };
+        cpus.extend(first..=last);
+    }
+    Ok(cpus)
+}
+
+/// `cpu_affinity` must be a non empty set of online cpus. affinity is applied before exec,
+/// inside whatever cgroup the agent runs in, so cpus outside its cpuset fail the spawn
+pub fn check_cpu_affinity(cpus: &[usize]) -> AnyhowResult<()> {
+    if cpus.is_empty() {
+        return Err(anyhow!("cpu affinity is empty"));
+    }
+    let online = online_cpus()?;
+    if let Some(cpu) = cpus
+        .iter()
+        .find(|cpu| !online.contains(cpu) || **cpu >= libc::CPU_SETSIZE as usize)
+    {
+        return Err(anyhow!("cpu affinity: cpu {} not online: {:?}", cpu, online));
+    }
+    Ok(())
+}
+
+pub fn open_mnt_namespace(pid: i32) -> AnyhowResult<OwnedFd> {
+    Ok(std::fs::File::open(format!("/proc/{}/ns/mnt", pid))?.into())
+}
+
+/// `mnt:[inode]` name of a namespace fd, same format as `/proc/<pid>/ns/mnt` links
+pub fn mnt_namespace_of(mnt_namespace: &OwnedFd) -> AnyhowResult<String> {
+    let file = std::fs::File::from(mnt_namespace.try_clone()?);
+    Ok(format!("mnt:[{}]", file.metadata()?.ino()))
+}
+
+/// create `link` pointing at `original` inside `mnt_namespace`. setns into a mount
+/// namespace is refused to threads sharing fs attributes, so it runs on a throwaway thread
+fn symlink_in_namespace(
+    mnt_namespace: &OwnedFd,
+    original: &str,
+    link: &str,
+    timeout: Duration,
+    threads: &LinkThreads,
+    key: LinkKey,
+) -> AnyhowResult<()> {
+    let mnt_namespace = mnt_namespace.try_clone()?;
+    let original = original.to_string();
+    let target = link.to_string();
+    link_within("rasp_setns_link", timeout, link, threads, key, move || {
+        unsafe {
+            if libc::unshare(libc::CLONE_FS) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            if libc::setns(mnt_namespace.as_raw_fd(), libc::CLONE_NEWNS) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        make_path_exist(target.clone())?;
+        fs::symlink(&original, &target)?;
+        Ok(())
+    })
+}
+
+/// a link made by a link thread: for `pid`, at `link` inside `mnt_namespace`
+#[derive(Clone, Debug)]
+pub struct LinkKey {
+    pub pid: i32,
+    pub mnt_namespace: String,
+    pub link: String,
+}
+
+/// link threads stuck past their timeout, by mnt namespace and link. a stuck link is not
+/// started again until its thread returns, and a link it makes after all is kept for
+/// `take_late`
+#[derive(Clone, Default)]
+pub struct LinkThreads {
+    inner: Arc<Mutex<LinkThreadsInner>>,
+}
+
+#[derive(Default)]
+struct LinkThreadsInner {
+    stuck: HashSet<(String, String)>,
+    late: Vec<LinkKey>,
+}
+
+impl LinkThreads {
+    /// a thread linking `link` in `mnt_namespace` timed out and has not returned yet
+    pub fn stuck(&self, mnt_namespace: &str, link: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .stuck
+            .contains(&(mnt_namespace.to_string(), link.to_string()))
+    }
+    /// links made by stuck threads since the last call, as `(pid, mnt namespace, link)`
+    pub fn take_late(&self) -> Vec<(i32, String, String)> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .late
+            .drain(..)
+            .map(|key| (key.pid, key.mnt_namespace, key.link))
+            .collect()
+    }
+    fn timed_out(&self, key: &LinkKey) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .stuck
+            .insert((key.mnt_namespace.clone(), key.link.clone()));
+    }
+    fn returned_late(&self, key: LinkKey, result: AnyhowResult<()>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .stuck
+            .remove(&(key.mnt_namespace.clone(), key.link.clone()));
+        match result {
+            Ok(()) => inner.late.push(key),
+            Err(e) => debug!("late link failed: {} {}", key.link, e),
+        }
+    }
+}
+
+/// run `link` on a thread named `name`, waiting at most `timeout` for it. a link stuck on
+/// an unresponsive namespace leaves its thread behind, blocked where the kernel holds it.
+/// `threads` marks it stuck until it returns, and keeps the link if it is made after all
+fn link_within(
+    name: &str,
+    timeout: Duration,
+    target: &str,
+    threads: &LinkThreads,
+    key: LinkKey,
+    link: impl FnOnce() -> AnyhowResult<()> + Send + 'static,
+) -> AnyhowResult<()> {
+    let (sender, receiver) = bounded(1);
+    // cleared when the wait times out, the thread then reports to `threads` instead
+    let waiting = Arc::new(Mutex::new(true));
+    let (thread_waiting, thread_threads) = (waiting.clone(), threads.clone());
+    let thread_key = key.clone();
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            let result = link();
+            let waiting = thread_waiting.lock().unwrap();
+            if *waiting {
+                let _ = sender.send(result);
+            } else {
+                thread_threads.returned_late(thread_key, result);
+            }
+        })?;
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => {
+            let mut waiting = waiting.lock().unwrap();
+            // returned between the timeout and taking the lock
+            if let Ok(result) = receiver.try_recv() {
+                return result;
+            }
+            *waiting = false;
+            threads.timed_out(&key);
+            Err(CommError::LinkTimeout {
+                target: target.to_string(),
+                timeout,
+            }
+            .into())
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(anyhow!("link thread panicked: {}", target)),
+    }
+}
+
+/// expand `{pid}` and `{mnt_namespace}` (inode of `mnt:[inode]`) in a `linking_to` template,
+/// values without markers are used as is
+pub fn expand_linking_to(template: &str, pid: i32, mnt_namespace: &String) -> AnyhowResult<String> {
+    let expanded = expand_mnt_namespace(template, mnt_namespace).replace("{pid}", &pid.to_string());
+    check_link_target(&expanded)?;
+    Ok(expanded)
+}
+
+/// the `{mnt_namespace}` part of `expand_linking_to`, `{pid}` is left as is
+pub fn expand_mnt_namespace(template: &str, mnt_namespace: &String) -> String {
+    let namespace_id = mnt_namespace
+        .trim_start_matches("mnt:[")
+        .trim_end_matches(']');
+    template.replace("{mnt_namespace}", namespace_id)
+}
+
+/// link target is joined under `/proc/<pid>/root`, so it must not be able to walk out of it
+pub fn check_link_target(target: &str) -> AnyhowResult<()> {
+    let path = std::path::Path::new(target);
+    if !path.is_absolute() {
+        return Err(anyhow!("link target must be absolute: {}", target));
+    }
+    if target.contains('\0') || target.contains('{') || target.contains('}') {
+        return Err(anyhow!("link target contains invalid character: {}", target));
+    }
+    if path
+        .components()
+        .any(|component| component == std::path::Component::ParentDir)
+    {
+        return Err(anyhow!("link target must not contain `..`: {}", target));
+    }
+    Ok(())
+}
+
+/// util-linux release nsenter first shipped in
+const NSENTER_MIN_VERSION: (u32, u32) = (2, 23);
+
+/// run `nsenter --version` at startup, an old nsenter shows up as attach failures much later
+pub fn check_nsenter_version(nsenter: &str) -> AnyhowResult<(u32, u32)> {
+    let output = Command::new(nsenter).arg("--version").output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = match parse_nsenter_version(&stdout) {
+        Some(version) => version,
+        None => return Err(anyhow!("nsenter version unrecognized: {}", stdout.trim())),
+    };
+    if version < NSENTER_MIN_VERSION {
+        return Err(CommError::NsEnterTooOld {
+            found: format!("{}.{}", version.0, version.1),
+            minimum: format!("{}.{}", NSENTER_MIN_VERSION.0, NSENTER_MIN_VERSION.1),
+        }
+        .into());
+    }
+    Ok(version)
+}
+
+/// `(major, minor)` from `nsenter from util-linux 2.38.1`
+fn parse_nsenter_version(output: &str) -> Option<(u32, u32)> {
+    let regex = regex::Regex::new(r"util-linux (\d+)\.(\d+)").ok()?;
+    let caps = regex.captures(output)?;
+    Some((caps[1].parse().ok()?, caps[2].parse().ok()?))
+}
+
+fn mount(pid: i32, from: &str, to: &str, verify: bool) -> AnyhowResult<()> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("mount", pid, from, to).entered();
+    check_mount_paths(pid, from, to)?;
+    let pid_str = pid.to_string();
+    let nsenter_str = settings::RASP_NS_ENTER_BIN();
+    let args = [pid_str.as_str(), from, to, nsenter_str.as_str()];
+    return match run_async_process(
+        std::process::Command::new(settings::RASP_MOUNT_SCRIPT_BIN()).args(args),
+    ) {
+        Ok((exit_status, stdout, stderr)) => {
+            if !exit_status.success() {
+                error!(
+                    "mount script execute failed: {} {} {}",
+                    exit_status, stdout, stderr
+                );
+                return Err(anyhow!(
+                    "mount script execute failed: {} {} {} ",
+                    exit_status,
+                    stdout,
+                    stderr
+                ));
+            }
+            debug!("mount success: {} {} {}", exit_status, stdout, stderr);
+            if verify {
+                check_mount_visible(pid, to)?;
+            }
+            Ok(())
+        }
+        Err(e) => Err(anyhow!("can not mount: {}", e)),
+    };
+}
+
+/// first of `pids` still in `mnt_namespace`
+fn pid_in_namespace(pids: &[i32], mnt_namespace: &String) -> Option<i32> {
+    pids.iter().copied().find(|pid| {
+        read_link(format!("/proc/{}/ns/mnt", pid))
+            .map(|namespace| namespace.display().to_string() == *mnt_namespace)
+            .unwrap_or(false)
+    })
+}
+
+/// run `command` in the mount namespace of `pid`
+fn run_in_namespace(nsenter: &str, pid: i32, command: &[&str]) -> AnyhowResult<()> {
+    let pid_str = pid.to_string();
+    let mut args = vec!["-t", pid_str.as_str(), "-m"];
+    args.extend_from_slice(command);
+    match run_async_process(std::process::Command::new(nsenter).args(args)) {
+        Ok((exit_status, stdout, stderr)) => {
+            if !exit_status.success() {
+                return Err(anyhow!(
+                    "{} execute failed: {} {} {}",
+                    command.join(" "),
+                    exit_status,
+                    stdout,
+                    stderr
+                ));
+            }
+            Ok(())
+        }
+        Err(e) => Err(anyhow!("can not run {}: {}", command.join(" "), e)),
+    }
+}
+
+/// the script only reports its exit code, make sure the mount really shows up in `pid`'s
+/// namespace. mountinfo of `pid` lists mountpoints relative to its own root
+fn check_mount_visible(pid: i32, to: &str) -> AnyhowResult<()> {
+    let root_dir = format!("/proc/{}/root", pid);
+    let path = to.strip_prefix(root_dir.as_str()).unwrap_or(to);
+    let path = path.trim_end_matches('/');
+    let mountinfo = std::fs::read_to_string(format!("/proc/{}/mountinfo", pid))?;
+    if parse_mountinfo_mountpoints(&mountinfo)
+        .iter()
+        .any(|mountpoint| mountpoint == path)
+    {
+        return Ok(());
+    }
+    Err(CommError::MountNotVisible {
+        pid,
+        path: path.to_string(),
+    }
+    .into())
+}
+
+/// fifth field of each mountinfo line, with the octal escapes (`\040` for space) decoded
+fn parse_mountinfo_mountpoints(mountinfo: &str) -> Vec<String> {
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|field| {
+            let mut decoded = Vec::with_capacity(field.len());
+            let bytes = field.as_bytes();
+            let mut i = 0;
+            while i < bytes.len() {
+                let escaped = bytes.get(i + 1..i + 4).and_then(|octal| {
+                    u8::from_str_radix(std::str::from_utf8(octal).ok()?, 8).ok()
+                });
+                match escaped {
+                    Some(byte) if bytes[i] == b'\\' => {
+                        decoded.push(byte);
+                        i += 4;
+                    }
+                    _ => {
+                        decoded.push(bytes[i]);
+                        i += 1;
+                    }
+                }
+            }
+            String::from_utf8_lossy(&decoded).into_owned()
+        })
+        .collect()
+}
+
+/// catch configuration mistakes before NSMount turns them into an opaque failure,
+/// target directories are created by NSMount so only the namespace root can be checked
+fn check_mount_paths(pid: i32, from: &str, to: &str) -> AnyhowResult<()> {
+    if !std::path::Path::new(from).exists() {
+        return Err(CommError::MountSourceMissing {
+            path: from.to_string(),
+        }
+        .into());
+    }
+    check_writable(std::path::Path::new(from))?;
+    if !to.starts_with('/') {
+        return Err(anyhow!("mount target must be absolute: {}", to));
+    }
+    let root_dir = format!("/proc/{}/root", pid);
+    if !std::path::Path::new(&root_dir).exists() {
+        return Err(anyhow!("mount target namespace not reachable: {}", root_dir));
+    }
+    Ok(())
+}
+
+/// `CommError::ReadOnlyPath` unless `path` can be written. a path not created yet is
+/// checked through its closest existing parent, where it will be created
+pub fn check_writable(path: &std::path::Path) -> AnyhowResult<()> {
+    check_writable_with(path, |existing| {
+        let existing = std::ffi::CString::new(existing.as_os_str().as_bytes())?;
+        if unsafe { libc::access(existing.as_ptr(), libc::W_OK) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    })
+}
+
+fn check_writable_with(
+    path: &std::path::Path,
+    access: impl Fn(&std::path::Path) -> std::io::Result<()>,
+) -> AnyhowResult<()> {
+    let mut existing = path;
+    while !existing.exists() {
+        existing = match existing.parent() {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
+    }
+    let e = match access(existing) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    match e.raw_os_error() {
+        Some(libc::EROFS) | Some(libc::EACCES) | Some(libc::EPERM) => Err(CommError::ReadOnlyPath {
+            path: existing.display().to_string(),
+        }
+        .into()),
+        _ => Err(e.into()),
+    }
+}
+
+pub fn make_path_exist(path: String) -> AnyhowResult<()> {
+    // check socket exist or path not exist
+    let _ = remove_file(path.clone());
+    let current_path = std::path::Path::new(&path).parent().unwrap();
+    if !current_path.exists() {
+        create_dir_all(&current_path)?;
+    }
+    Ok(())
+}
+
+const ROOT_MNT_NAMESPACE_LINK: &str = "/proc/1/ns/mnt";
+const ROOT_MNT_INIT_RETRIES: u32 = 3;
+const ROOT_MNT_INIT_BACKOFF: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    static ref ROOT_MNT_NAMESPACE: RootMntNamespace = RootMntNamespace::init(
+        PathBuf::from(ROOT_MNT_NAMESPACE_LINK),
+        ROOT_MNT_INIT_RETRIES,
+        ROOT_MNT_INIT_BACKOFF,
+    );
+}
+
+/// `(st_dev, st_ino)` of a namespace file, the same for every process in the namespace
+pub fn namespace_id(path: &std::path::Path) -> std::io::Result<(u64, u64)> {
+    let metadata = std::fs::metadata(path)?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+/// mount namespace of pid 1, stat once on first use. when the link can not be stat then,
+/// `get` tries again until one stat succeeds, and keeps that one
+pub struct RootMntNamespace {
+    pub link: PathBuf,
+    pub cached: Mutex<Option<(u64, u64)>>,
+}
+
+impl RootMntNamespace {
+    pub fn init(link: PathBuf, retries: u32, backoff: Duration) -> Self {
+        let mut attempt = 0;
+        let cached = loop {
+            match namespace_id(&link) {
+                Ok(namespace) => break Some(namespace),
+                Err(e) if attempt < retries => {
+                    debug!("read root mnt namespace failed, retry: {}", e);
+                    attempt += 1;
+                    thread::sleep(backoff);
+                }
+                Err(e) => {
+                    warn!(
+                        "root mnt namespace unreadable at init, reading it on next call: {} {}",
+                        link.display(),
+                        e
+                    );
+                    break None;
+                }
+            }
+        };
+        Self {
+            link,
+            cached: Mutex::new(cached),
+        }
+    }
+    pub fn get(&self) -> AnyhowResult<(u64, u64)> {
+        let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(namespace) = *cached {
+            return Ok(namespace);
+        }
+        let namespace = namespace_id(&self.link)?;
+        *cached = Some(namespace);
+        Ok(namespace)
+    }
+}
+
+/// pids under `proc_root` sharing the pid namespace of `pid`, `pid` included, sorted.
+/// entries of the host /proc are host pids already. the scan costs one readlink per
+/// process, and is refused for the root pid namespace, which holds every process
+pub fn pid_namespace_members(proc_root: &std::path::Path, pid: i32) -> AnyhowResult<Vec<i32>> {
+    let pid_namespace = |pid: &str| read_link(proc_root.join(pid).join("ns/pid"));
+    let target = pid_namespace(&pid.to_string())?;
+    if let Ok(root) = pid_namespace("1") {
+        if root == target {
+            return Err(anyhow!(
+                "pid namespace of {} is the root one: {}",
+                pid,
+                target.display()
+            ));
+        }
+    }
+    let mut members = Vec::new();
+    for entry in std::fs::read_dir(proc_root)?.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let member: i32 = match name.parse() {
+            Ok(member) => member,
+            Err(_) => continue,
+        };
+        // gone since listed, or a kernel thread without a readable namespace
+        if let Ok(namespace) = pid_namespace(&name) {
+            if namespace == target {
+                members.push(member);
+            }
+        }
+    }
+    members.sort_unstable();
+    Ok(members)
+}
+
+/// whether `pid` lives outside the mount namespace of pid 1
+pub fn check_need_mount(pid: i32) -> AnyhowResult<bool> {
+    let root_mnt = ROOT_MNT_NAMESPACE.get()?;
+    let pid_mnt = namespace_id(std::path::Path::new(&format!("/proc/{}/ns/mnt", pid)))?;
+    debug!("pid namespace && root namespace : {:?} && {:?}", pid_mnt, root_mnt);
+    Ok(root_mnt != pid_mnt)
+}
+
+fn resolve_mount_path(path: String, pid: i32) -> String {
+    let target_path = format!("/proc/{}/root{}", pid, path);
+    let current_path = std::path::Path::new(&target_path);
+    let mut find_path = current_path;
+
+    while !find_path.exists() {
+        if let Some(parent_path) = find_path.parent() {
+            find_path = parent_path;
+        }
+    }
+    if find_path.exists() {
+        if let Ok(subfix_path) = current_path.strip_prefix(find_path) {
+            let parent_resolve = resolve_symlink_path(find_path.to_string_lossy().into_owned());
+            if parent_resolve == find_path.to_string_lossy().into_owned() {
+                return path;
+            } 
+            else {
+                return format!("{}/{}", parent_resolve, subfix_path.display());
+            }
+        }
+    }
+    path
+}
+
+fn resolve_symlink_path(path: String) -> String {
+    let new_path = std::path::Path::new(&path);
+    let check_path = new_path.parent().unwrap();
+    let file_name = new_path.file_name();
+    if let Ok(metadata) = symlink_metadata(check_path) {
+        if metadata.file_type().is_symlink() {
+            if let Ok(real_path) = read_link(check_path) {
+                let mut resolved_path = real_path;
+                if !resolved_path.is_absolute() {
+                    resolved_path = check_path.parent().unwrap().join(resolved_path);
+                }
+                if resolved_path.parent().is_none() {
+                    return format!("/{}", file_name.unwrap().to_string_lossy());
+                }
+                else {
+                    return format!("{}/{}", resolved_path.to_string_lossy(), file_name.unwrap().to_string_lossy());
+                }
+            }
+        }
+    }
+
+    path
+}
+
+pub const KERNEL_BTF_PATH: &str = "/sys/kernel/btf/vmlinux";
+const EBPF_READ_TIMEOUT: Duration = Duration::from_secs(10);
+const EBPF_ROTATE_MIN_INTERVAL: Duration = Duration::from_secs(60);
+const EBPF_READY_SLEEP: Duration = Duration::from_secs(2);
+const EBPF_READY_PROBE_MAX: Duration = Duration::from_secs(5);
+const EBPF_READY_TIMEOUT: Duration = Duration::from_secs(10);
+const EBPF_STDIN_STALL_TIMEOUT: Duration = Duration::from_secs(2);
+const EBPF_KILL_GRACE: Duration = Duration::from_secs(3);
+const EBPF_KILL_POLL: Duration = Duration::from_millis(50);
+/// PIPE_BUF, a chunk this size is written to the pipe whole or not at all
+const EBPF_STDIN_CHUNK: usize = 4096;
+/// stderr lines kept for `TimeoutDiagnostics`
+const EBPF_STDERR_TAIL: usize = 20;
+const EBPF_MAX_LINE_LENGTH: usize = 64 * 1024;
+const EBPF_PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttachOutcome {
+    /// `hooks` installed by the daemon, empty when the daemon does not report them
+    Succeed { hooks: Vec<String> },
+    Failed,
+    TimedOut,
+    /// not attempted, `ctrl` was stopped before the batch reached the pid
+    Aborted,
+    /// not written, the daemon drained stdin too slowly for the batch budget
+    NotAttempted,
+}
+
+/// passed to the `attach_batch_with_progress` callback after each pid
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttachProgress {
+    pub processed: usize,
+    pub total: usize,
+    pub pid: i32,
+    pub last_outcome: AttachOutcome,
+}
+
+/// golang eBPF daemon stdin protocol, one command per line. released daemons only know
+/// `Attach` without options, which is written as the bare pid. option keys and values
+/// must not contain `,`, `=` or whitespace
+#[derive(Debug, Clone, PartialEq)]
+pub enum DaemonCommand {
+    /// `<pid>` or `<pid> key=value,key=value`
+    Attach {
+        pid: i32,
+        options: Vec<(String, String)>,
+    },
+    /// `detach <pid>`
+    Detach { pid: i32 },
+    /// `list`
+    List,
+    /// `version`
+    Version,
+    /// `ping`
+    Ping,
+}
+
+impl DaemonCommand {
+    pub fn to_wire(&self) -> String {
+        match self {
+            DaemonCommand::Attach { pid, options } if options.is_empty() => format!("{}\n", pid),
+            DaemonCommand::Attach { pid, options } => {
+                let options: Vec<String> = options
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect();
+                format!("{} {}\n", pid, options.join(","))
+            }
+            DaemonCommand::Detach { pid } => format!("detach {}\n", pid),
+            DaemonCommand::List => String::from("list\n"),
+            DaemonCommand::Version => String::from("version\n"),
+            DaemonCommand::Ping => String::from("ping\n"),
+        }
+    }
+    pub fn from_wire(line: &str) -> AnyhowResult<Self> {
+        let line = line.trim();
+        let unknown = || anyhow!("unknown daemon command: {}", line);
+        let mut parts = line.splitn(2, ' ');
+        let head = parts.next().unwrap_or_default();
+        let rest = parts.next();
+        match (head, rest) {
+            ("list", None) => Ok(DaemonCommand::List),
+            ("version", None) => Ok(DaemonCommand::Version),
+            ("ping", None) => Ok(DaemonCommand::Ping),
+            ("detach", Some(pid)) => Ok(DaemonCommand::Detach {
+                pid: pid.parse().map_err(|_| unknown())?,
+            }),
+            (pid, options) => {
+                let pid = pid.parse().map_err(|_| unknown())?;
+                let mut parsed = Vec::new();
+                for option in options.unwrap_or_default().split(',') {
+                    if option.is_empty() {
+                        continue;
+                    }
+                    let (key, value) = option.split_once('=').ok_or_else(unknown)?;
+                    parsed.push((key.to_string(), value.to_string()));
+                }
+                Ok(DaemonCommand::Attach {
+                    pid,
+                    options: parsed,
+                })
+            }
+        }
+    }
+}
+
+/// lines the golang eBPF daemon writes to stdout, answering `DaemonCommand`s
+#[derive(Debug, Clone, PartialEq)]
+pub enum DaemonResponse {
+    /// `<pid>:<status>` answering an attach, see `ProbeResponse`
+    Attach { pid: i32, response: ProbeResponse },
+    /// `<pid>:detached` or `<pid>:detach_failed`
+    Detach { pid: i32, detached: bool },
+    /// `list:<pid>,<pid>`
+    List { pids: Vec<i32> },
+    /// `version:<version>`
+    Version { version: String },
+    /// `pong`
+    Pong,
+    /// `ready`, written once at startup by daemons with `FeatureSet::ready_handshake`
+    Ready,
+}
+
+impl DaemonResponse {
+    pub fn to_wire(&self) -> String {
+        match self {
+            DaemonResponse::Attach { pid, response } => match response {
+                ProbeResponse::Succeeded { hooks } if hooks.is_empty() => {
+                    format!("{}:succeed\n", pid)
+                }
+                ProbeResponse::Succeeded { hooks } => {
+                    format!("{}:succeed:{}\n", pid, hooks.join(","))
+                }
+                ProbeResponse::Failed(None) => format!("{}:failed\n", pid),
+                ProbeResponse::Failed(Some(reason)) => format!("{}:failed:{}\n", pid, reason),
+                ProbeResponse::Pending => format!("{}:pending\n", pid),
+                ProbeResponse::Unknown(status) => format!("{}:{}\n", pid, status),
+            },
+            DaemonResponse::Detach { pid, detached: true } => format!("{}:detached\n", pid),
+            DaemonResponse::Detach { pid, .. } => format!("{}:detach_failed\n", pid),
+            DaemonResponse::List { pids } => {
+                let pids: Vec<String> = pids.iter().map(|pid| pid.to_string()).collect();
+                format!("list:{}\n", pids.join(","))
+            }
+            DaemonResponse::Version { version } => format!("version:{}\n", version),
+            DaemonResponse::Pong => String::from("pong\n"),
+            DaemonResponse::Ready => String::from("ready\n"),
+        }
+    }
+    /// the daemon may prefix lines with log noise, pid answers are searched for anywhere.
+    /// a malformed line is an error, a failed attach is not
+    pub fn from_wire(line: &str) -> Result<Self, ProbeError> {
+        let line = line.trim();
+        let malformed = || ProbeError::Malformed {
+            line: line.to_string(),
+        };
+        if line == "pong" {
+            return Ok(DaemonResponse::Pong);
+        }
+        if line == "ready" {
+            return Ok(DaemonResponse::Ready);
+        }
+        if let Some(version) = line.strip_prefix("version:") {
+            return Ok(DaemonResponse::Version {
+                version: version.to_string(),
+            });
+        }
+        if let Some(pids) = line.strip_prefix("list:") {
+            let mut parsed = Vec::new();
+            for pid in pids.split(',').filter(|pid| !pid.is_empty()) {
+                parsed.push(pid.parse().map_err(|_| malformed())?);
+            }
+            return Ok(DaemonResponse::List { pids: parsed });
+        }
+        let caps = PROBE_RESPONSE.captures(line).ok_or_else(malformed)?;
+        let pid = caps[1].parse().map_err(|_| malformed())?;
+        let detail = caps
+            .get(3)
+            .map(|detail| detail.as_str().trim())
+            .filter(|detail| !detail.is_empty());
+        let response = match &caps[2] {
+            "succeed" => {
+                // hooks end at the first space or colon, log noise may follow
+                let hooks = detail
+                    .and_then(|detail| detail.split(|c: char| c.is_whitespace() || c == ':').next())
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter(|hook| !hook.is_empty())
+                    .map(String::from)
+                    .collect();
+                ProbeResponse::Succeeded { hooks }
+            }
+            "failed" => ProbeResponse::Failed(detail.map(String::from)),
+            "pending" => ProbeResponse::Pending,
+            "detached" => {
+                return Ok(DaemonResponse::Detach {
+                    pid,
+                    detached: true,
+                })
+            }
+            "detach_failed" => {
+                return Ok(DaemonResponse::Detach {
+                    pid,
+                    detached: false,
+                })
+            }
+            status => ProbeResponse::Unknown(status.to_string()),
+        };
+        Ok(DaemonResponse::Attach { pid, response })
+    }
+}
+
+lazy_static! {
+    static ref PROBE_RESPONSE: regex::Regex =
+        regex::Regex::new(r"(\d{1,20}):([a-z_]+)(?::(.*))?").unwrap();
+}
+
+/// answer of the golang eBPF daemon to one attach, carried by `DaemonResponse::Attach`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeResponse {
+    /// `<pid>:succeed` or `<pid>:succeed:hook_a,hook_b`
+    Succeeded { hooks: Vec<String> },
+    /// `<pid>:failed` or `<pid>:failed:<reason>`, the reason runs to the end of the line
+    Failed(Option<String>),
+    /// `<pid>:pending`, still attaching, the final answer follows
+    Pending,
+    /// `<pid>:<status>` with a status this side does not know
+    Unknown(String),
+}
+
+impl From<ProbeResponse> for AttachOutcome {
+    fn from(response: ProbeResponse) -> Self {
+        match response {
+            ProbeResponse::Succeeded { hooks } => AttachOutcome::Succeed { hooks },
+            _ => AttachOutcome::Failed,
+        }
+    }
+}
+
+/// a daemon line that is no answer to an attach
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeError {
+    /// not a `DaemonResponse`, no `<pid>:<status>` anywhere in the line
+    Malformed { line: String },
+    /// answer to another command, `detach` for one
+    Unexpected { line: String },
+}
+
+impl Display for ProbeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            ProbeError::Malformed { line } => {
+                write!(f, "can not found any proper format in response: {}", line)
+            }
+            ProbeError::Unexpected { line } => {
+                write!(f, "unexpected response to attach: {}", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+/// kernels in `[min, max)` run the daemon built with `suffix` and get `features`
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionBand {
+    pub min: procfs::sys::kernel::Version,
+    /// `None` for no upper bound, only the last band may leave it open
+    pub max: Option<procfs::sys::kernel::Version>,
+    pub suffix: String,
+    pub features: FeatureSet,
+}
+
+/// daemon picked for the running kernel, see `EbpfMode::select_band`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BandSelection {
+    pub band: VersionBand,
+    /// suffix of the band the kernel version falls in, when a lower band was picked
+    pub stepped_down_from: Option<String>,
+    /// why the lower band was picked
+    pub reason: Option<String>,
+}
+
+/// built in table, kernels older than 4.14 are not supported
+pub fn default_version_matrix() -> Vec<VersionBand> {
+    let version = procfs::sys::kernel::Version::new;
+    let band = |min, max, suffix: &str, features| VersionBand {
+        min,
+        max,
+        suffix: suffix.to_string(),
+        features,
+    };
+    let http = FeatureSet {
+        http_capture: true,
+        ..Default::default()
+    };
+    let http_headers = FeatureSet {
+        http_headers: true,
+        ..http
+    };
+    let ring_buffer = FeatureSet {
+        ring_buffer: true,
+        ..http_headers
+    };
+    // one band per daemon build, a new kernel gets its line here and its build in rasp/Makefile
+    vec![
+        band(version(4, 14, 0), Some(version(4, 16, 0)), "_4.14", FeatureSet::default()),
+        band(version(4, 16, 0), Some(version(5, 2, 0)), "_4.16", http),
+        band(version(5, 2, 0), Some(version(5, 8, 0)), "_5.2", http_headers),
+        band(version(5, 8, 0), Some(version(6, 0, 0)), "_5.8", ring_buffer),
+        band(version(6, 0, 0), None, "_6.0", ring_buffer),
+    ]
+}
+
+/// bands must be ascending and must not overlap, gaps are unsupported kernels
+pub fn check_version_matrix(bands: &[VersionBand]) -> AnyhowResult<()> {
+    for (i, band) in bands.iter().enumerate() {
+        let max = match band.max {
+            Some(max) => max,
+            None if i + 1 == bands.len() => continue,
+            None => return Err(anyhow!("only the last band may be open: {}", band.suffix)),
+        };
+        if band.min >= max {
+            return Err(anyhow!("band is empty: {}", band.suffix));
+        }
+        if let Some(next) = bands.get(i + 1) {
+            if next.min < max {
+                return Err(anyhow!(
+                    "bands not ascending or overlapping: {} {}",
+                    band.suffix,
+                    next.suffix
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn bpf_suffix_for_version<'a>(
+    bands: &'a [VersionBand],
+    version: &procfs::sys::kernel::Version,
+) -> Option<&'a VersionBand> {
+    bands
+        .iter()
+        .find(|band| {
+            band.min <= *version
+                && match band.max {
+                    Some(max) => *version < max,
+                    None => true,
+                }
+        })
+}
+
+/// kernel release from `uname(2)`, e.g. `5.10.0-21-amd64`
+pub fn uname_release() -> AnyhowResult<String> {
+    let mut utsname: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut utsname) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let release = unsafe { std::ffi::CStr::from_ptr(utsname.release.as_ptr()) };
+    Ok(release.to_string_lossy().into_owned())
+}
+
+/// result of `EbpfMode::warmup`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WarmupReport {
+    /// the daemon answered the warmup attach
+    pub ready: bool,
+    pub elapsed: Duration,
+}
+
+/// result of `EbpfMode::kill_server`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KillOutcome {
+    /// exited within the grace period after SIGTERM
+    Terminated,
+    /// SIGKILL sent
+    Signaled,
+    /// ESRCH only, the process was already reaped
+    AlreadyGone,
+    /// `errno` of the signal call, EPERM means the agent can no longer signal the daemon
+    Error { errno: i32 },
+}
+
+impl KillOutcome {
+    /// `errnos` of each signal call sent, 0 for a call that succeeded
+    fn classify(errnos: &[i32]) -> Self {
+        if errnos.contains(&0) {
+            return KillOutcome::Signaled;
+        }
+        match errnos.iter().find(|errno| **errno != ESRCH) {
+            Some(errno) => KillOutcome::Error { errno: *errno },
+            None => KillOutcome::AlreadyGone,
+        }
+    }
+}
+
+/// what `attach` does with a pid already in `LateAttaches::attached`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OnDuplicate {
+    /// report success without contacting the daemon
+    #[default]
+    Ignore,
+    /// send the attach to the daemon again
+    Reattach,
+    /// fail with `CommError::AlreadyAttached`
+    Error,
+}
+
+/// attaches given up on after a timeout, reconciled by the stdout reader when the daemon
+/// answers late so the answer never shows up in the read of a later attach
+#[derive(Clone, Default)]
+pub struct LateAttaches {
+    /// pid -> events sender of the attach that timed out
+    pending: Arc<Mutex<HashMap<i32, Option<Sender<CommEvent>>>>>,
+    /// pids attached, late attaches included
+    pub attached: Arc<Mutex<HashSet<i32>>>,
+}
+
+impl LateAttaches {
+    pub fn is_pending(&self, pid: i32) -> bool {
+        self.pending.lock().unwrap().contains_key(&pid)
+    }
+    pub fn is_attached(&self, pid: i32) -> bool {
+        self.attached.lock().unwrap().contains(&pid)
+    }
+    fn add_pending(&self, pid: i32, events: Option<Sender<CommEvent>>) {
+        self.pending.lock().unwrap().insert(pid, events);
+    }
+    fn record(&self, pid: i32, outcome: &AttachOutcome) {
+        if let AttachOutcome::Succeed { .. } = outcome {
+            self.attached.lock().unwrap().insert(pid);
+        }
+    }
+    /// consume a response for a pending attach, false if nobody was waiting on `pid`
+    fn reconcile(&self, pid: i32, outcome: &AttachOutcome) -> bool {
+        let events = match self.pending.lock().unwrap().remove(&pid) {
+            Some(events) => events,
+            None => return false,
+        };
+        self.record(pid, outcome);
+        if let Some(sender) = events {
+            let _ = sender.try_send(match outcome {
+                AttachOutcome::Succeed { hooks } => CommEvent::LateAttachSucceeded {
+                    pid,
+                    hooks: hooks.clone(),
+                },
+                _ => CommEvent::LateAttachFailed { pid },
+            });
+        }
+        true
+    }
+}
+
+/// per pid channels the stdout reader hands attach answers to, so an attach waits on
+/// its own answer instead of the next line. lines for pids nobody registered still go
+/// through `EbpfMode::stdout`
+#[derive(Clone, Default)]
+pub struct ResponseRouter {
+    waiters: Arc<Mutex<HashMap<i32, Waiter>>>,
+    /// bumped by `clear`, tells the waiters of one daemon from the next one's
+    generation: Arc<AtomicUsize>,
+}
+
+enum Waiter {
+    Blocking(Sender<ProbeResponse>),
+    /// one channel for several pids, see `register_many`
+    Shared(Sender<(i32, ProbeResponse)>),
+    #[cfg(feature = "tokio")]
+    Async(tokio::sync::mpsc::UnboundedSender<ProbeResponse>),
+}
+
+impl ResponseRouter {
+    /// answers for `pid` from now on, `Pending` ones included, until `unregister`
+    pub fn register(&self, pid: i32) -> Receiver<ProbeResponse> {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        self.waiters.lock().unwrap().insert(pid, Waiter::Blocking(sender));
+        receiver
+    }
+    /// `register` for every pid of `pids`, answers come tagged with their pid
+    pub fn register_many(&self, pids: &[i32]) -> Receiver<(i32, ProbeResponse)> {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let mut waiters = self.waiters.lock().unwrap();
+        for pid in pids {
+            waiters.insert(*pid, Waiter::Shared(sender.clone()));
+        }
+        receiver
+    }
+    /// `register` for a waiter on a tokio runtime
+    #[cfg(feature = "tokio")]
+    pub fn register_async(
+        &self,
+        pid: i32,
+    ) -> tokio::sync::mpsc::UnboundedReceiver<ProbeResponse> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.waiters.lock().unwrap().insert(pid, Waiter::Async(sender));
+        receiver
+    }
+    pub fn unregister(&self, pid: i32) {
+        self.waiters.lock().unwrap().remove(&pid);
+    }
+    /// false if nobody waits on `pid`
+    pub fn route(&self, pid: i32, response: ProbeResponse) -> bool {
+        match self.waiters.lock().unwrap().get(&pid) {
+            Some(Waiter::Blocking(waiter)) => waiter.send(response).is_ok(),
+            Some(Waiter::Shared(waiter)) => waiter.send((pid, response)).is_ok(),
+            #[cfg(feature = "tokio")]
+            Some(Waiter::Async(waiter)) => waiter.send(response).is_ok(),
+            None => false,
+        }
+    }
+    pub fn clear(&self) {
+        let mut waiters = self.waiters.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        waiters.clear();
+    }
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+    /// `clear` unless it was cleared since `generation`, a stdout reader outliving
+    /// its daemon leaves the next daemon's waiters alone
+    pub fn clear_generation(&self, generation: usize) {
+        let mut waiters = self.waiters.lock().unwrap();
+        if self.generation.load(Ordering::SeqCst) == generation {
+            self.generation.fetch_add(1, Ordering::SeqCst);
+            waiters.clear();
+        }
+    }
+}
+
+/// how `EbpfMode::restart_if_exited` brings back a daemon that exited on its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RestartPolicy {
+    /// restarts in a row before giving up, a daemon that ran `max_backoff` starts over
+    pub max_restarts: u32,
+    /// wait before the second restart in a row, doubled on every one after
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// clears the router when the stdout reader ends, however it ends
+struct ClearOnExit {
+    router: ResponseRouter,
+    generation: usize,
+}
+
+impl Drop for ClearOnExit {
+    fn drop(&mut self) {
+        self.router.clear_generation(self.generation);
+    }
+}
+
+pub struct ReaderThread {
+    handle: thread::JoinHandle<()>,
+    /// disconnected once the thread returns
+    done: Receiver<()>,
+}
+
+/// one golang eBPF daemon. attaches take `&mut self`, threads share the mode behind a
+/// `Mutex`, which keeps every write paired with the read of its response
+pub struct EbpfMode {
+    pub ctrl: Control,
+    pub kernel_version: procfs::sys::kernel::Version,
+    pub version_matrix: Vec<VersionBand>,
+    /// daemon binary run by `start_server` instead of the `version_matrix` one, for mock
+    /// daemons and patched builds. set by `with_bin_path`
+    pub bin_path_override: Option<String>,
+    pub stdin: Option<ChildStdin>,
+    /// lines read from daemon stdout, disconnected once the daemon closes it. a line over
+    /// `max_line_length` comes as `CommError::ResponseTooLong`
+    pub stdout: Option<Receiver<Result<String, CommError>>>,
+    pub daemon_alive: bool,
+    pub events: Option<Sender<CommEvent>>,
+    pub log_sink: Option<LogSink>,
+    /// responses for pids not being attached, see `drain_unsolicited`
+    pub unsolicited: Vec<(i32, AttachOutcome)>,
+    /// pids written to the daemon and not answered yet. a response for any other pid
+    /// never reaches attach outcomes or `late_attaches`, only `unsolicited`
+    pub requested: HashSet<i32>,
+    /// `(uid, gid)` the daemon runs as. setuid drops every capability, so a non root
+    /// daemon needs CAP_BPF/CAP_PERFMON (CAP_SYS_ADMIN before 5.8) and CAP_SYS_PTRACE
+    /// granted on its binary as file capabilities, otherwise loading programs fails
+    pub run_as: Option<(u32, u32)>,
+    pub server_pid: Option<i32>,
+    /// `rotate` calls closer than this are refused unless forced
+    pub rotate_min_interval: Duration,
+    pub last_rotate: Option<Instant>,
+    pub container_id_resolver: Option<ContainerIdResolver>,
+    /// cpus the daemon is pinned to, see `check_cpu_affinity`
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// keep timed out attaches pending instead of stopping, see `LateAttaches`
+    pub resume_timed_out: bool,
+    /// how long one attach waits for its response. the reader thread hands lines over as
+    /// soon as the daemon writes them, so there is no poll interval to trade latency against:
+    /// a longer wait only delays reporting a stuck daemon
+    pub max_total_wait: Duration,
+    pub late_attaches: LateAttaches,
+    pub router: ResponseRouter,
+    /// thread draining daemon stdout, joined by `stop_stdout_reader`
+    pub stdout_reader: Option<ReaderThread>,
+    /// thread reaping the daemon, joined by `stop`
+    pub server_wait: Option<thread::JoinHandle<()>>,
+    /// child of `ctrl` watched by `server_wait`, stopping it tears down this daemon only
+    pub server_ctrl: Option<Control>,
+    pub on_duplicate: OnDuplicate,
+    /// kernel BTF checked by `btf_available`, configurable for tests and chroots
+    pub btf_path: PathBuf,
+    /// scanned by `attach_pid_namespace`
+    pub proc_root: PathBuf,
+    /// longest stdout or stderr line kept from the daemon, newline included, see
+    /// `read_line_capped`
+    pub max_line_length: usize,
+    /// stdin writes fail with `StdinStalled` once the daemon reads nothing for this long
+    pub stdin_stall_timeout: Duration,
+    /// time the daemon gets after SIGTERM to detach its programs, see `kill_server`
+    pub kill_grace: Duration,
+    /// how long `ping` waits for the daemon to answer
+    pub ping_timeout: Duration,
+    /// how long `start_server` waits for `ready` from a daemon with the handshake
+    pub ready_timeout: Duration,
+    /// last line read from daemon stdout, reported in `TimeoutDiagnostics`
+    pub last_raw_response: Option<String>,
+    /// last `EBPF_STDERR_TAIL` lines of daemon stderr, filled by its reader thread
+    pub stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    /// restart a daemon that exited while `ctrl` still runs, `None` leaves it dead
+    pub restart_policy: Option<RestartPolicy>,
+    /// restarts in a row, see `RestartPolicy::max_restarts`
+    pub restarts: u32,
+    pub next_restart: Option<Instant>,
+    /// out of restarts, the daemon stays dead
+    pub restart_given_up: bool,
+    /// when the running daemon was spawned
+    pub server_started: Option<Instant>,
+}
+
+impl EbpfMode {
+    pub fn new(ctrl: Control) -> AnyhowResult<Self> {
+        Self::build(ctrl, default_version_matrix(), None)
+    }
+    /// replace the built in kernel table, every band needs its daemon binary installed
+    pub fn with_version_matrix(
+        ctrl: Control,
+        version_matrix: Vec<VersionBand>,
+    ) -> AnyhowResult<Self> {
+        check_version_matrix(&version_matrix)?;
+        for band in version_matrix.iter() {
+            let bin_path = settings::RASP_GOLANG_EBPF(&band.suffix);
+            if !std::path::Path::new(&bin_path).exists() {
+                return Err(anyhow!("ebpf daemon binary not exist: {}", bin_path));
+            }
+        }
+        Self::build(ctrl, version_matrix, None)
+    }
+    /// run `bin_path` instead of a `version_matrix` daemon, no band has to cover the kernel
+    pub fn with_bin_path(ctrl: Control, bin_path: String) -> AnyhowResult<Self> {
+        if !std::path::Path::new(&bin_path).exists() {
+            return Err(anyhow!("ebpf daemon binary not exist: {}", bin_path));
+        }
+        Self::build(ctrl, default_version_matrix(), Some(bin_path))
+    }
+    fn build(
+        ctrl: Control,
+        version_matrix: Vec<VersionBand>,
+        bin_path_override: Option<String>,
+    ) -> AnyhowResult<Self> {
+        let ebpf_manager = Self {
+            ctrl,
+            version_matrix,
+            bin_path_override,
+            kernel_version: Self::detect_kernel_version()?,
+            stdin: None,
+            stdout: None,
+            daemon_alive: false,
+            events: None,
+            log_sink: None,
+            unsolicited: Vec::new(),
+            requested: HashSet::new(),
+            run_as: None,
+            server_pid: None,
+            rotate_min_interval: EBPF_ROTATE_MIN_INTERVAL,
+            last_rotate: None,
+            container_id_resolver: None,
+            cpu_affinity: None,
+            resume_timed_out: false,
+            max_total_wait: EBPF_READ_TIMEOUT,
+            late_attaches: LateAttaches::default(),
+            router: ResponseRouter::default(),
+            stdout_reader: None,
+            server_wait: None,
+            server_ctrl: None,
+            on_duplicate: OnDuplicate::default(),
+            btf_path: PathBuf::from(KERNEL_BTF_PATH),
+            proc_root: PathBuf::from("/proc"),
+            max_line_length: EBPF_MAX_LINE_LENGTH,
+            stdin_stall_timeout: EBPF_STDIN_STALL_TIMEOUT,
+            kill_grace: EBPF_KILL_GRACE,
+            ping_timeout: EBPF_PING_TIMEOUT,
+            ready_timeout: EBPF_READY_TIMEOUT,
+            last_raw_response: None,
+            stderr_tail: Arc::new(Mutex::new(VecDeque::new())),
+            restart_policy: None,
+            restarts: 0,
+            next_restart: None,
+            restart_given_up: false,
+            server_started: None,
+        };
+        info!("kernel btf available: {}", ebpf_manager.btf_available());
+        if ebpf_manager.bin_path_override.is_some() {
+            return Ok(ebpf_manager);
+        }
+        let selection = ebpf_manager.select_band()?;
+        if let (Some(from), Some(reason)) = (selection.stepped_down_from, selection.reason) {
+            warn!(
+                "golang ebpf daemon {} instead of {}: {}",
+                selection.band.suffix, from, reason
+            );
+        }
+        Ok(ebpf_manager)
+    }
+    /// from `/proc/sys/kernel/osrelease`, or from `uname(2)` where procfs is not readable
+    pub fn detect_kernel_version() -> AnyhowResult<procfs::sys::kernel::Version> {
+        Self::detect_kernel_version_with(
+            || Ok(procfs::sys::kernel::Version::current()?),
+            uname_release,
+        )
+    }
+    fn detect_kernel_version_with(
+        procfs: impl FnOnce() -> AnyhowResult<procfs::sys::kernel::Version>,
+        uname: impl FnOnce() -> AnyhowResult<String>,
+    ) -> AnyhowResult<procfs::sys::kernel::Version> {
+        let (kernel_version, source) = match procfs() {
+            Ok(kernel_version) => (kernel_version, "procfs"),
+            Err(procfs_error) => {
+                warn!("kernel version from procfs failed: {}", procfs_error);
+                let release = uname().map_err(|e| {
+                    anyhow!("kernel version undetected, procfs: {}, uname: {}", procfs_error, e)
+                })?;
+                let kernel_version = procfs::sys::kernel::Version::from_str(&release)
+                    .map_err(|e| anyhow!("uname release {:?} unparsed: {}", release, e))?;
+                (kernel_version, "uname")
+            }
+        };
+        info!(
+            "current kernel version: {}.{}, from {}",
+            kernel_version.major, kernel_version.minor, source
+        );
+        Ok(kernel_version)
+    }
+    /// daemon binary suffix from `version_matrix`, see `select_band`
+    pub fn switch_bpf_main_process(&self) -> AnyhowResult<String> {
+        Ok(self.select_band()?.band.suffix)
+    }
+    /// the `version_matrix` band of the running kernel. a ring buffer daemon cannot load
+    /// without kernel BTF, the closest lower band without ring buffer is picked instead
+    pub fn select_band(&self) -> AnyhowResult<BandSelection> {
+        let band = match bpf_suffix_for_version(&self.version_matrix, &self.kernel_version) {
+            Some(band) => band,
+            None => {
+                return Err(anyhow!(
+                    "version: {}.{} kernel not support",
+                    self.kernel_version.major,
+                    self.kernel_version.minor,
+                ))
+            }
+        };
+        if !band.features.ring_buffer || self.btf_available() {
+            return Ok(BandSelection {
+                band: band.clone(),
+                stepped_down_from: None,
+                reason: None,
+            });
+        }
+        let lower = self
+            .version_matrix
+            .iter()
+            .rev()
+            .find(|lower| lower.min < band.min && !lower.features.ring_buffer)
+            .ok_or_else(|| {
+                anyhow!(
+                    "kernel btf missing: {}, no band without ring buffer below {}",
+                    self.btf_path.display(),
+                    band.suffix
+                )
+            })?;
+        Ok(BandSelection {
+            band: lower.clone(),
+            stepped_down_from: Some(band.suffix.clone()),
+            reason: Some(format!("kernel btf missing: {}", self.btf_path.display())),
+        })
+    }
+    pub fn btf_available(&self) -> bool {
+        self.btf_path.is_file()
+    }
+    pub fn supported_features(&self) -> FeatureSet {
+        let mut features = self
+            .select_band()
+            .map(|selection| selection.band.features)
+            .unwrap_or_default();
+        features.btf = self.btf_available();
+        features.ring_buffer &= features.btf;
+        features
+    }
+    /// `bin_path_override`, otherwise the daemon of the running kernel's band
+    pub fn daemon_bin_path(&self) -> AnyhowResult<String> {
+        match self.bin_path_override.as_ref() {
+            Some(bin_path) => Ok(bin_path.clone()),
+            None => Ok(settings::RASP_GOLANG_EBPF(&self.switch_bpf_main_process()?)),
+        }
+    }
+    pub fn start_server(&mut self) -> AnyhowResult<()> {
+        self.spawn_daemon()?;
+        if self.supported_features().ready_handshake {
+            return self.wait_ready();
+        }
+        // released daemons say nothing, sleep here for subprocess ready for listen stdin
+        thread::sleep(EBPF_READY_SLEEP);
+        if let Some(server_pid) = self.server_pid {
+            Self::log_readiness_window(&self.log_sink, server_pid);
+        }
+        Ok(())
+    }
+    /// `start_server` for `restart_if_exited_async`, the waits run off the runtime thread
+    #[cfg(feature = "tokio")]
+    async fn start_server_async(&mut self) -> AnyhowResult<()> {
+        self.spawn_daemon()?;
+        if self.supported_features().ready_handshake {
+            return self.wait_ready_async().await;
+        }
+        tokio::time::sleep(EBPF_READY_SLEEP).await;
+        if let Some(server_pid) = self.server_pid {
+            let log_sink = self.log_sink.clone();
+            tokio::task::spawn_blocking(move || Self::log_readiness_window(&log_sink, server_pid))
+                .await?;
+        }
+        Ok(())
+    }
+    /// spawn the daemon of the running kernel's band, or `bin_path_override`
+    fn spawn_daemon(&mut self) -> AnyhowResult<()> {
+        let bin_path = self.daemon_bin_path()?;
+        if self.bin_path_override.is_some() {
+            comm_log!(self.log_sink, Info, "golang ebpf daemon overridden: {}", bin_path);
+        } else if let Ok(BandSelection {
+            band,
+            stepped_down_from: Some(from),
+            reason: Some(reason),
+        }) = self.select_band()
+        {
+            comm_log!(
+                self.log_sink,
+                Warn,
+                "golang ebpf daemon {} instead of {}: {}",
+                band.suffix,
+                from,
+                reason
+            );
+        }
+        self.spawn_server(&mut Command::new(bin_path))
+    }
+    /// block until the daemon writes `ready`, a daemon silent for `ready_timeout` is killed
+    fn wait_ready(&mut self) -> AnyhowResult<()> {
+        let started = Instant::now();
+        let deadline = started + self.ready_timeout;
+        loop {
+            let line = self.read_line_before(0, deadline);
+            if Self::timed_out(&line) {
+                if let Some(server_pid) = self.server_pid.take() {
+                    Self::kill_server(server_pid, self.kill_grace);
+                }
+                return Err(self.not_ready());
+            }
+            if let Some(result) = self.ready_line(started, line) {
+                return result;
+            }
+        }
+    }
+    /// `wait_ready` receiving and killing on the blocking pool
+    #[cfg(feature = "tokio")]
+    async fn wait_ready_async(&mut self) -> AnyhowResult<()> {
+        let started = Instant::now();
+        let deadline = started + self.ready_timeout;
+        loop {
+            let line = match self.stdout.clone() {
+                Some(stdout) => {
+                    let received =
+                        tokio::task::spawn_blocking(move || stdout.recv_deadline(deadline))
+                            .await?;
+                    self.take_line(0, received)
+                }
+                None => Err(Self::stdout_not_available()),
+            };
+            if Self::timed_out(&line) {
+                if let Some(server_pid) = self.server_pid.take() {
+                    let kill_grace = self.kill_grace;
+                    tokio::task::spawn_blocking(move || Self::kill_server(server_pid, kill_grace))
+                        .await?;
+                }
+                return Err(self.not_ready());
+            }
+            if let Some(result) = self.ready_line(started, line) {
+                return result;
+            }
+        }
+    }
+    fn timed_out(line: &AnyhowResult<String>) -> bool {
+        matches!(
+            line.as_ref().map_err(|e| e.downcast_ref::<CommError>()),
+            Err(Some(CommError::AttachTimeout { .. }))
+        )
+    }
+    /// the daemon was killed for not writing `ready` in time
+    fn not_ready(&mut self) -> anyhow::Error {
+        self.daemon_alive = false;
+        anyhow!("golang ebpf daemon not ready after {:?}", self.ready_timeout)
+    }
+    /// one line read by `wait_ready` before the timeout, `None` while the daemon is loading
+    fn ready_line(
+        &mut self,
+        started: Instant,
+        line: AnyhowResult<String>,
+    ) -> Option<AnyhowResult<()>> {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                self.check_daemon_died(&e);
+                return Some(Err(e));
+            }
+        };
+        if let Ok(DaemonResponse::Ready) = DaemonResponse::from_wire(&line) {
+            comm_log!(
+                self.log_sink,
+                Info,
+                "golang ebpf daemon ready after {:?}",
+                started.elapsed()
+            );
+            return Some(Ok(()));
+        }
+        comm_log!(
+            self.log_sink,
+            Debug,
+            "ebpf response ignored before ready: {}",
+            line.trim()
+        );
+        None
+    }
+    /// report whether the fixed sleep in `start_server` was long enough, data for tuning it
+    fn log_readiness_window(log_sink: &Option<LogSink>, server_pid: i32) {
+        let start = Instant::now();
+        let mut probes = 0;
+        loop {
+            let ready = match Self::waiting_on_stdin(server_pid) {
+                Some(ready) => ready,
+                None => {
+                    comm_log!(log_sink, Info, "ebpf daemon readiness unknown");
+                    return;
+                }
+            };
+            probes += 1;
+            if ready && probes == 1 {
+                comm_log!(
+                    log_sink,
+                    Info,
+                    "ebpf daemon already ready after {:?} sleep",
+                    EBPF_READY_SLEEP
+                );
+                return;
+            }
+            if ready {
+                comm_log!(
+                    log_sink,
+                    Info,
+                    "ebpf daemon not ready after {:?} sleep, ready {:?} later",
+                    EBPF_READY_SLEEP,
+                    start.elapsed()
+                );
+                return;
+            }
+            if start.elapsed() >= EBPF_READY_PROBE_MAX {
+                comm_log!(
+                    log_sink,
+                    Info,
+                    "ebpf daemon not ready after {:?} sleep, still not ready {:?} later",
+                    EBPF_READY_SLEEP,
+                    start.elapsed()
+                );
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+    /// whether a thread of `pid` is blocked reading stdin, `None` if /proc/pid/task/*/syscall
+    /// can not be read
+    fn waiting_on_stdin(pid: i32) -> Option<bool> {
+        let tasks = std::fs::read_dir(format!("/proc/{}/task", pid)).ok()?;
+        let mut readable = false;
+        for task in tasks.flatten() {
+            let syscall = match std::fs::read_to_string(task.path().join("syscall")) {
+                Ok(syscall) => syscall,
+                Err(_) => continue,
+            };
+            readable = true;
+            let mut fields = syscall.split_whitespace();
+            if fields.next() == Some(&libc::SYS_read.to_string()) && fields.next() == Some("0x0") {
+                return Some(true);
+            }
+        }
+        if readable {
+            Some(false)
+        } else {
+            None
+        }
+    }
+    /// replace the running daemon with a fresh one, `force` skips the minimum interval
+    pub fn rotate(&mut self, force: bool) -> AnyhowResult<()> {
+        self.rotate_with(force, Self::start_server)
+    }
+    fn rotate_with(
+        &mut self,
+        force: bool,
+        start: impl FnOnce(&mut Self) -> AnyhowResult<()>,
+    ) -> AnyhowResult<()> {
+        let now = Instant::now();
+        if let Some(last_rotate) = self.last_rotate {
+            let next_allowed = last_rotate + self.rotate_min_interval;
+            if !force && now < next_allowed {
+                return Err(CommError::RotateTooSoon { next_allowed }.into());
+            }
+        }
+        // failed rotations count too, a broken daemon must not be restarted in a loop
+        self.last_rotate = Some(now);
+        if let Some(server_pid) = self.server_pid.take() {
+            comm_log!(self.log_sink, Info, "rotate golang ebpf daemon: {}", server_pid);
+            Self::kill_server(server_pid, self.kill_grace);
+        }
+        self.stop_stdout_reader(Duration::from_secs(1));
+        self.stdin = None;
+        self.stdout = None;
+        self.daemon_alive = false;
+        start(self)
+    }
+    /// clear guard state so the next call is tried right away, returns what was reset.
+    /// a dead daemon is not a guard, `rotate` starts a new one
+    pub fn reset_protections(&mut self) -> Vec<&'static str> {
+        let mut reset = Vec::new();
+        if self.last_rotate.take().is_some() {
+            reset.push("rotate cooldown");
+        }
+        if self.restarts > 0 || self.next_restart.is_some() || self.restart_given_up {
+            self.restarts = 0;
+            self.next_restart = None;
+            self.restart_given_up = false;
+            reset.push("crash-loop guard");
+        }
+        for guard in reset.iter() {
+            comm_log!(self.log_sink, Info, "ebpf protection reset: {}", guard);
+        }
+        reset
+    }
+    /// start a new daemon if the running one exited on its own and `restart_policy` allows
+    /// it, then attach every pid of `attached_pids` again. true if a daemon was started.
+    /// called by `attach` and polled by `RASPManager::maintain_servers`, so an idle agent
+    /// restarts it too. the `ebpf_server_wait` thread only notices the exit
+    pub fn restart_if_exited(&mut self) -> AnyhowResult<bool> {
+        self.restart_if_exited_with(Self::start_server)
+    }
+    fn restart_if_exited_with(
+        &mut self,
+        start: impl FnOnce(&mut Self) -> AnyhowResult<()>,
+    ) -> AnyhowResult<bool> {
+        if !self.begin_restart() {
+            return Ok(false);
+        }
+        self.stop_stdout_reader(Duration::from_secs(1));
+        start(self)?;
+        let pids = self.attached_pids();
+        if pids.is_empty() {
+            return Ok(true);
+        }
+        self.late_attaches.attached.lock().unwrap().clear();
+        let outcomes = self.attach_batch(&pids, self.max_total_wait)?;
+        let failed = outcomes
+            .values()
+            .filter(|outcome| !matches!(outcome, AttachOutcome::Succeed { .. }))
+            .count();
+        self.log_reattached(pids.len(), failed);
+        Ok(true)
+    }
+    /// `restart_if_exited` for `attach_async`, the daemon is waited for and the pids
+    /// attached again without blocking the runtime thread
+    #[cfg(feature = "tokio")]
+    async fn restart_if_exited_async(&mut self) -> AnyhowResult<bool> {
+        if !self.begin_restart() {
+            return Ok(false);
+        }
+        if let Some(reader) = self.stdout_reader.take() {
+            let timeout = Duration::from_secs(1);
+            if !tokio::task::spawn_blocking(move || Self::join_reader(reader, timeout)).await? {
+                self.log_reader_left_behind(timeout);
+            }
+        }
+        self.start_server_async().await?;
+        let pids = self.attached_pids();
+        if pids.is_empty() {
+            return Ok(true);
+        }
+        self.late_attaches.attached.lock().unwrap().clear();
+        let failed = self.reattach_async(&pids).await?;
+        self.log_reattached(pids.len(), failed);
+        Ok(true)
+    }
+    /// `attach_batch` of `restart_if_exited_async`, one pid after the other within one
+    /// `max_total_wait`. returns how many were not attached again
+    #[cfg(feature = "tokio")]
+    async fn reattach_async(&mut self, pids: &[i32]) -> AnyhowResult<usize> {
+        let started = Instant::now();
+        let deadline = started + self.max_total_wait;
+        let mut failed = 0;
+        for pid in pids.iter().copied() {
+            let command = DaemonCommand::Attach {
+                pid,
+                options: Vec::new(),
+            };
+            // registered before the write, an answer must not beat its waiter
+            let mut routed = self.router.register_async(pid);
+            if let Err(e) = self.write_wire_async(command.to_wire(), deadline).await {
+                self.router.unregister(pid);
+                self.check_daemon_died(&e);
+                return Err(e);
+            }
+            self.requested.insert(pid);
+            let response = self.read_probe_async(pid, &mut routed, started, deadline).await;
+            self.router.unregister(pid);
+            match response {
+                Ok(response) => {
+                    let outcome = response.into();
+                    self.late_attaches.record(pid, &outcome);
+                    if !matches!(outcome, AttachOutcome::Succeed { .. }) {
+                        failed += 1;
+                    }
+                }
+                Err(e) => {
+                    if let Some(CommError::DaemonDied { .. }) = e.downcast_ref() {
+                        return Err(e);
+                    }
+                    self.requested.remove(&pid);
+                    failed += 1;
+                }
+            }
+        }
+        Ok(failed)
+    }
+    fn log_reattached(&self, pids: usize, failed: usize) {
+        comm_log!(
+            self.log_sink,
+            Info,
+            "ebpf pids attached again after restart: {}, failed: {}",
+            pids - failed,
+            failed
+        );
+    }
+    /// the policy half of a restart: true once the exited daemon is cleared away and a
+    /// new one is due. the old stdout reader is left to the caller
+    fn begin_restart(&mut self) -> bool {
+        let policy = match self.restart_policy {
+            Some(policy) => policy,
+            None => return false,
+        };
+        let server_pid = match self.server_pid {
+            Some(server_pid) => server_pid,
+            None => return false,
+        };
+        if !self.ctrl.check() || (self.daemon_alive && !Self::server_exited(server_pid)) {
+            return false;
+        }
+        let now = Instant::now();
+        if let Some(started) = self.server_started {
+            if now - started >= policy.max_backoff {
+                self.restarts = 0;
+                self.next_restart = None;
+                self.restart_given_up = false;
+            }
+        }
+        if self.restarts >= policy.max_restarts {
+            if !self.restart_given_up {
+                comm_log!(
+                    self.log_sink,
+                    Error,
+                    "golang ebpf daemon exited after {} restarts, giving up",
+                    self.restarts
+                );
+                self.restart_given_up = true;
+            }
+            return false;
+        }
+        if let Some(next_restart) = self.next_restart {
+            if now < next_restart {
+                return false;
+            }
+        }
+        let backoff = policy
+            .initial_backoff
+            .checked_mul(1 << self.restarts.min(16))
+            .unwrap_or(policy.max_backoff)
+            .min(policy.max_backoff);
+        self.restarts += 1;
+        self.next_restart = Some(now + backoff);
+        comm_log!(
+            self.log_sink,
+            Warn,
+            "golang ebpf daemon exited: {}, restart: {}/{}",
+            server_pid,
+            self.restarts,
+            policy.max_restarts
+        );
+        self.server_pid = None;
+        self.stdin = None;
+        self.stdout = None;
+        self.daemon_alive = false;
+        true
+    }
+    fn spawn_server(&mut self, command: &mut Command) -> AnyhowResult<()> {
+        if let Some(cpus) = self.cpu_affinity.as_ref() {
+            check_cpu_affinity(cpus)?;
+            libraspserver::process_mode::pin_to_cpus(command, cpus);
+        }
+        if let Some((uid, gid)) = self.run_as {
+            // registered last, hooks added before still run with full privileges
+            unsafe {
+                command.pre_exec(move || Self::drop_privileges(uid, gid));
+            }
+        }
+        let mut child = match command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                if let Some((uid, gid)) = self.run_as {
+                    return Err(anyhow!(
+                        "spawn ebpf process as uid: {} gid: {} failed: {}",
+                        uid,
+                        gid,
+                        e
+                    ));
+                }
+                return Err(e.into());
+            }
+        };
+        comm_log!(self.log_sink, Debug, "spawn ebpf process success: {}", child.id());
+        let child_id = child.id();
+        self.server_pid = Some(child_id as i32);
+        self.server_started = Some(Instant::now());
+        self.requested.clear();
+        self.router.clear();
+        self.last_raw_response = None;
+        self.stderr_tail.lock().unwrap().clear();
+        self.stdin = child.stdin.take();
+        if let Some(stdin) = self.stdin.as_ref() {
+            // writes poll in `write_wire_before` instead of blocking on a full pipe
+            let fd = stdin.as_raw_fd();
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags < 0
+                || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+            {
+                return Err(anyhow!(
+                    "set ebpf stdin nonblocking failed: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+        }
+        self.stdout = match child.stdout.take() {
+            Some(stdout) => {
+                let (receiver, reader) = Self::read_stdout_lines(
+                    stdout,
+                    self.ctrl.clone(),
+                    self.log_sink.clone(),
+                    self.late_attaches.clone(),
+                    self.router.clone(),
+                    self.max_line_length,
+                )?;
+                self.stdout_reader = Some(reader);
+                Some(receiver)
+            }
+            None => None,
+        };
+        if let Some(stderr) = child.stderr.take() {
+            Self::read_stderr_lines(
+                stderr,
+                self.ctrl.clone(),
+                self.log_sink.clone(),
+                self.stderr_tail.clone(),
+                self.max_line_length,
+            )?;
+        }
+        self.daemon_alive = true;
+        /*
+            if self.stdin.is_none() {
+                return Err(anyhow!("can not take child stdin, pid: {}", child_id));
+            }
+            if self.stdout.is_none() {
+                return Err(anyhow!("can not take child stdout, pid: {}", child_id));
+            }
+        */
+        // start a thread for wait child die
+        let mut wait_ctrl = self.ctrl.child();
+        self.server_ctrl = Some(wait_ctrl.clone());
+        let log_sink = self.log_sink.clone();
+        let kill_grace = self.kill_grace;
+        let handle = thread::Builder::new()
+            .name("ebpf_server_wait".to_string())
+            .spawn(move || loop {
+                if !wait_ctrl.check() {
+                    comm_log!(
+                        log_sink,
+                        Info,
+                        "stop golang ebpf daemon {}: {}",
+                        child_id,
+                        wait_ctrl.reason().as_deref().unwrap_or("no reason given")
+                    );
+                    let outcome = Self::kill_server(child_id as i32, kill_grace);
+                    if !matches!(outcome, KillOutcome::Error { .. }) {
+                        let _ = child.wait();
+                    }
+                    return;
+                }
+                match child.try_wait() {
+                    Ok(Some(status)) => {
+                        comm_log!(
+                            log_sink,
+                            Info,
+                            "Golang EBPF daemon exit with status: {}",
+                            status
+                        );
+                        // restarted by `restart_if_exited` when a policy is set
+                        return;
+                    }
+                    Ok(None) => {
+                        // woken right away once `ctrl` or `server_ctrl` stops
+                        wait_ctrl.wait_stopped_timeout(Duration::from_secs(10));
+                    }
+                    Err(e) => {
+                        comm_log!(log_sink, Error, "error attempting to wait: {}", e);
+                        Self::kill_server(child_id as i32, kill_grace);
+                        return;
+                    }
+                }
+            })?;
+        self.server_wait = Some(handle);
+        Ok(())
+    }
+    /// tear the daemon down now instead of leaving it to `ebpf_server_wait`: stop `ctrl`,
+    /// SIGTERM the daemon, SIGKILL it after `kill_grace`, and join the threads watching
+    /// it. a stopped mode stops again without signaling anything
+    pub fn stop(&mut self) -> AnyhowResult<()> {
+        let _ = self.ctrl.stop_with_reason(String::from("ebpf mode stopped"));
+        let mut errors = Vec::new();
+        if let Some(server_pid) = self.server_pid.take() {
+            comm_log!(self.log_sink, Info, "stop golang ebpf daemon: {}", server_pid);
+            if let KillOutcome::Error { errno } = Self::kill_server(server_pid, self.kill_grace) {
+                errors.push(format!(
+                    "kill ebpf daemon failed: {}",
+                    std::io::Error::from_raw_os_error(errno)
+                ));
+            }
+        }
+        if let Some(server_wait) = self.server_wait.take() {
+            let _ = server_wait.join();
+        }
+        self.server_ctrl = None;
+        if !self.stop_stdout_reader(Duration::from_secs(1)) {
+            errors.push(String::from("ebpf stdout reader not joined"));
+        }
+        self.stdin = None;
+        self.stdout = None;
+        self.daemon_alive = false;
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("stop golang ebpf daemon: {}", errors.join(", ")))
+        }
+    }
+    // runs between fork and exec, only async signal safe calls allowed
+    fn drop_privileges(uid: u32, gid: u32) -> std::io::Result<()> {
+        unsafe {
+            if libc::setgroups(0, std::ptr::null()) != 0
+                || libc::setgid(gid) != 0
+                || libc::setuid(uid) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+    /// log daemon stderr line by line, the only place BPF load failures show up, keeping
+    /// the last lines in `tail`. the thread ends on EOF, when the daemon exits, and is
+    /// never joined
+    fn read_stderr_lines(
+        stderr: ChildStderr,
+        mut ctrl: Control,
+        log_sink: Option<LogSink>,
+        tail: Arc<Mutex<VecDeque<String>>>,
+        max_line_length: usize,
+    ) -> AnyhowResult<()> {
+        thread::Builder::new()
+            .name("ebpf_server_stderr".to_string())
+            .spawn(move || {
+                let mut buf_reader = BufReader::new(stderr);
+                let mut line = Vec::new();
+                while ctrl.check() {
+                    line.clear();
+                    match read_line_capped(&mut buf_reader, &mut line, max_line_length) {
+                        Ok(CappedLine::Eof) | Err(_) => return,
+                        Ok(_) => {
+                            // stderr is only logged, an oversized line is kept truncated
+                            let line = String::from_utf8_lossy(&line);
+                            let line = line.trim_end();
+                            let lower = line.to_lowercase();
+                            if ["error", "fail", "panic"].iter().any(|w| lower.contains(w)) {
+                                comm_log!(log_sink, Error, "[ebpf-daemon] {}", line);
+                            } else {
+                                comm_log!(log_sink, Warn, "[ebpf-daemon] {}", line);
+                            }
+                            let mut tail = tail.lock().unwrap();
+                            if tail.len() == EBPF_STDERR_TAIL {
+                                tail.pop_front();
+                            }
+                            tail.push_back(line.to_string());
+                        }
+                    }
+                }
+            })?;
+        Ok(())
+    }
+    fn read_stdout_lines(
+        stdout: ChildStdout,
+        ctrl: Control,
+        log_sink: Option<LogSink>,
+        late_attaches: LateAttaches,
+        router: ResponseRouter,
+        max_line_length: usize,
+    ) -> AnyhowResult<(Receiver<Result<String, CommError>>, ReaderThread)> {
+        // blocking reads can not tell a slow daemon from a dead one,
+        // so drain stdout here and let EOF show up as a disconnected channel
+        let (sender, receiver) = bounded(50);
+        let (done_sender, done) = bounded::<()>(0);
+        let mut ctrl = ctrl;
+        // routed waiters see the daemon gone as soon as its stdout is
+        let clear_router = ClearOnExit {
+            generation: router.generation(),
+            router: router.clone(),
+        };
+        let handle = thread::Builder::new()
+            .name("ebpf_server_stdout".to_string())
+            .spawn(move || {
+                let _done_sender = done_sender;
+                let _clear_router = clear_router;
+                let mut buf_reader = BufReader::new(stdout);
+                let mut read = Vec::new();
+                loop {
+                    if !ctrl.check() {
+                        return;
+                    }
+                    read.clear();
+                    match read_line_capped(&mut buf_reader, &mut read, max_line_length) {
+                        Ok(CappedLine::Eof) => {
+                            comm_log!(log_sink, Warn, "Golang EBPF daemon stdout EOF");
+                            return;
+                        }
+                        Ok(CappedLine::TooLong { length }) => {
+                            let line = String::from_utf8_lossy(&read);
+                            comm_log!(
+                                log_sink,
+                                Warn,
+                                "ebpf daemon stdout line too long: {} bytes, starts with: {}",
+                                length,
+                                line.chars().take(64).collect::<String>()
+                            );
+                            let too_long = CommError::ResponseTooLong {
+                                length,
+                                limit: max_line_length,
+                            };
+                            if sender.send(Err(too_long)).is_err() {
+                                return;
+                            }
+                        }
+                        Ok(CappedLine::Line) => {
+                            let line = String::from_utf8_lossy(&read).into_owned();
+                            if let Ok((pid, outcome)) = Self::parse_attach_response(&line) {
+                                if late_attaches.reconcile(pid, &outcome) {
+                                    comm_log!(
+                                        log_sink,
+                                        Info,
+                                        "late ebpf attach response: {} {:?}",
+                                        pid,
+                                        outcome
+                                    );
+                                    continue;
+                                }
+                            }
+                            if let Ok((pid, response)) = Self::parse_server_response(&line) {
+                                if router.route(pid, response) {
+                                    continue;
+                                }
+                            }
+                            if sender.send(Ok(line)).is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => {
+                            comm_log!(
+                                log_sink,
+                                Error,
+                                "read stdout from ebpf server failed: {}",
+                                e
+                            );
+                            return;
+                        }
+                    }
+                }
+            })?;
+        Ok((receiver, ReaderThread { handle, done }))
+    }
+    /// join the stdout reader, it only returns once the daemon closed stdout
+    /// so kill the daemon first. false if it did not finish within `timeout`
+    pub fn stop_stdout_reader(&mut self, timeout: Duration) -> bool {
+        let reader = match self.stdout_reader.take() {
+            Some(reader) => reader,
+            None => return true,
+        };
+        let joined = Self::join_reader(reader, timeout);
+        if !joined {
+            self.log_reader_left_behind(timeout);
+        }
+        joined
+    }
+    fn join_reader(reader: ReaderThread, timeout: Duration) -> bool {
+        match reader.done.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Disconnected) => {
+                let _ = reader.handle.join();
+                true
+            }
+            _ => false,
+        }
+    }
+    fn log_reader_left_behind(&self, timeout: Duration) {
+        comm_log!(
+            self.log_sink,
+            Warn,
+            "ebpf stdout reader not finished in {:?}, leaving it behind",
+            timeout
+        );
+    }
+    pub fn attach(&mut self, pid: i32) -> AnyhowResult<bool> {
+        Ok(matches!(
+            self.attach_detailed(pid)?,
+            AttachOutcome::Succeed { .. }
+        ))
+    }
+    /// same as `attach`, keeping the hooks reported by the daemon
+    pub fn attach_detailed(&mut self, pid: i32) -> AnyhowResult<AttachOutcome> {
+        self.attach_within(pid, self.max_total_wait)
+    }
+    /// `attach_detailed` waiting `timeout` instead of `max_total_wait` for this one pid,
+    /// for a large JVM known to take longer than the rest
+    pub fn attach_within(&mut self, pid: i32, timeout: Duration) -> AnyhowResult<AttachOutcome> {
+        let events = self.events.clone();
+        let resolver = self.container_id_resolver.clone();
+        let operation = CommOperation::Attach;
+        with_operation_events(&events, &resolver, operation, "ebpf", pid, None, || {
+            self.attach_until(pid, Instant::now() + timeout)
+        })
+    }
+    fn attach_until(&mut self, pid: i32, deadline: Instant) -> AnyhowResult<AttachOutcome> {
+        self.restart_if_exited()?;
+        if let Some(outcome) = self.check_duplicate(pid)? {
+            return Ok(outcome);
+        }
+        let routed = self.router.register(pid);
+        let result = self.attach_routed(pid, &routed, deadline);
+        self.router.unregister(pid);
+        // answered after the wait gave up, before `unregister`
+        for response in routed.try_iter() {
+            if response != ProbeResponse::Pending {
+                self.late_attaches.reconcile(pid, &response.into());
+            }
+        }
+        result
+    }
+    /// the outcome to report for an already attached `pid`, `None` to attach it
+    fn check_duplicate(&mut self, pid: i32) -> AnyhowResult<Option<AttachOutcome>> {
+        if !self.late_attaches.is_attached(pid) {
+            return Ok(None);
+        }
+        match self.on_duplicate {
+            OnDuplicate::Ignore => {
+                comm_log!(self.log_sink, Debug, "ebpf already attached: {}", pid);
+                Ok(Some(AttachOutcome::Succeed { hooks: Vec::new() }))
+            }
+            OnDuplicate::Error => Err(CommError::AlreadyAttached { pid }.into()),
+            // the daemon has no detach command, attaching again re-installs the hooks
+            OnDuplicate::Reattach => {
+                self.late_attaches.attached.lock().unwrap().remove(&pid);
+                Ok(None)
+            }
+        }
+    }
+    fn attach_routed(
+        &mut self,
+        pid: i32,
+        routed: &Receiver<ProbeResponse>,
+        deadline: Instant,
+    ) -> AnyhowResult<AttachOutcome> {
+        if let Err(e) = self.write_stdin(pid) {
+            self.check_daemon_died(&e);
+            return Err(e);
+        }
+        self.requested.insert(pid);
+        match self.read_probe_before(pid, routed, deadline) {
+            Ok(response) => {
+                let outcome = response.into();
+                self.late_attaches.record(pid, &outcome);
+                Ok(outcome)
+            }
+            Err(e) => Err(self.attach_failed(pid, e)),
+        }
+    }
+    /// a timed out attach stays pending with `resume_timed_out`, anything else but a dead
+    /// daemon stops `ctrl`
+    fn attach_failed(&mut self, pid: i32, e: anyhow::Error) -> anyhow::Error {
+        if self.check_daemon_died(&e) {
+            return e;
+        }
+        if let Some(CommError::AttachTimeout { .. }) = e.downcast_ref() {
+            if self.resume_timed_out {
+                comm_log!(self.log_sink, Warn, "ebpf attach pending: {}", pid);
+                self.late_attaches.add_pending(pid, self.events.clone());
+                self.requested.remove(&pid);
+                return e;
+            }
+        }
+        // several pids of `attach_many` may fail on the same timeout, logged once
+        if let Ok(true) = self
+            .ctrl
+            .stop_with_reason(format!("ebpf running abnormally: {}", e))
+        {
+            comm_log!(self.log_sink, Error, "ebpf running abnormally: {}, quiting.", e);
+        }
+        e
+    }
+    /// `attach` for agents on tokio, never blocking a runtime thread: a restart and the
+    /// pid's write run on the blocking pool, the answer is awaited with
+    /// `tokio::time::timeout`. no `CommEvent`s or tracing spans.
+    ///
+    /// the daemon is not a `tokio::process` child with async stdout: `attach`, `attach_many`
+    /// and the restart poller read the same daemon, and a second reader would split its
+    /// lines between them. the shared reader thread routes this pid's answer to a tokio
+    /// channel instead, so nothing here waits on the thread
+    #[cfg(feature = "tokio")]
+    pub async fn attach_async(&mut self, pid: i32) -> AnyhowResult<bool> {
+        self.restart_if_exited_async().await?;
+        if let Some(outcome) = self.check_duplicate(pid)? {
+            return Ok(matches!(outcome, AttachOutcome::Succeed { .. }));
+        }
+        let mut routed = self.router.register_async(pid);
+        let started = Instant::now();
+        let result = self.attach_routed_async(pid, &mut routed, started).await;
+        self.router.unregister(pid);
+        while let Ok(response) = routed.try_recv() {
+            if response != ProbeResponse::Pending {
+                self.late_attaches.reconcile(pid, &response.into());
+            }
+        }
+        Ok(matches!(result?, AttachOutcome::Succeed { .. }))
+    }
+    #[cfg(feature = "tokio")]
+    async fn attach_routed_async(
+        &mut self,
+        pid: i32,
+        routed: &mut tokio::sync::mpsc::UnboundedReceiver<ProbeResponse>,
+        started: Instant,
+    ) -> AnyhowResult<AttachOutcome> {
+        let deadline = started + self.max_total_wait;
+        let command = DaemonCommand::Attach {
+            pid,
+            options: Vec::new(),
+        };
+        if let Err(e) = self.write_wire_async(command.to_wire(), deadline).await {
+            self.check_daemon_died(&e);
+            return Err(e);
+        }
+        self.requested.insert(pid);
+        match self.read_probe_async(pid, routed, started, deadline).await {
+            Ok(response) => {
+                let outcome = response.into();
+                self.late_attaches.record(pid, &outcome);
+                Ok(outcome)
+            }
+            Err(e) => Err(self.attach_failed(pid, e)),
+        }
+    }
+    /// `write_wire_before` on the blocking pool, with its own handle on stdin
+    #[cfg(feature = "tokio")]
+    async fn write_wire_async(&self, wire: String, deadline: Instant) -> AnyhowResult<()> {
+        let stdin = match self.stdin.as_ref() {
+            Some(stdin) => std::fs::File::from(stdin.as_fd().try_clone_to_owned()?),
+            None => {
+                return Err(CommError::DaemonDied {
+                    reason: String::from("stdin not available"),
+                }
+                .into())
+            }
+        };
+        let stall_timeout = self.stdin_stall_timeout;
+        let log_sink = self.log_sink.clone();
+        tokio::task::spawn_blocking(move || {
+            Self::write_wire(&stdin, wire.as_bytes(), deadline, stall_timeout, &log_sink)
+        })
+        .await?
+    }
+    /// `read_probe_before` on tokio. answers for `pid` are routed and awaited, lines for
+    /// other pids are drained from stdout once the answer is in. the wait reported on a
+    /// timeout counts from `started`, the write on the blocking pool included
+    #[cfg(feature = "tokio")]
+    async fn read_probe_async(
+        &mut self,
+        pid: i32,
+        routed: &mut tokio::sync::mpsc::UnboundedReceiver<ProbeResponse>,
+        started: Instant,
+        deadline: Instant,
+    ) -> AnyhowResult<ProbeResponse> {
+        let mut attempts = 0;
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            let response = match tokio::time::timeout(wait, routed.recv()).await {
+                Ok(Some(response)) => response,
+                // cleared when the stdout reader ends, the daemon waited on is gone
+                Ok(None) => {
+                    return Err(CommError::DaemonDied {
+                        reason: String::from("stdout closed"),
+                    }
+                    .into())
+                }
+                Err(_) => {
+                    let e = CommError::AttachTimeout {
+                        pid,
+                        diagnostics: Box::new(self.timeout_diagnostics()),
+                    };
+                    return Err(Self::with_wait_context(e.into(), started, attempts));
+                }
+            };
+            attempts += 1;
+            if response == ProbeResponse::Pending {
+                comm_log!(self.log_sink, Debug, "ebpf attach pending: {}", pid);
+                continue;
+            }
+            self.requested.remove(&pid);
+            if let Err(e) = self.drain_stdout(pid) {
+                comm_log!(self.log_sink, Debug, "ebpf stdout not drained: {}", e);
+            }
+            return Ok(response);
+        }
+    }
+    /// handle the lines waiting on stdout without blocking, returns how many were read
+    #[cfg(feature = "tokio")]
+    fn drain_stdout(&mut self, pid: i32) -> AnyhowResult<u32> {
+        let mut read = 0;
+        loop {
+            let stdout = match self.stdout.clone() {
+                Some(stdout) => stdout,
+                None => return Err(Self::stdout_not_available()),
+            };
+            let line = match stdout.try_recv() {
+                Ok(line) => self.take_line(pid, Ok(line))?,
+                Err(TryRecvError::Empty) => return Ok(read),
+                Err(TryRecvError::Disconnected) => {
+                    return Err(CommError::DaemonDied {
+                        reason: String::from("stdout closed"),
+                    }
+                    .into())
+                }
+            };
+            read += 1;
+            let (pid_from_server, response) = Self::parse_server_response(&line)?;
+            if response == ProbeResponse::Pending {
+                comm_log!(self.log_sink, Debug, "ebpf attach pending: {}", pid_from_server);
+                continue;
+            }
+            let outcome = response.into();
+            if self.late_attaches.reconcile(pid_from_server, &outcome) {
+                continue;
+            }
+            self.push_unsolicited(pid, pid_from_server, outcome);
+        }
+    }
+    /// mark daemon as dead instead of stopping the whole world,
+    /// attach keeps failing fast with `CommError::DaemonDied` until the daemon is started again
+    fn check_daemon_died(&mut self, e: &anyhow::Error) -> bool {
+        if let Some(CommError::DaemonDied { reason }) = e.downcast_ref::<CommError>() {
+            if self.daemon_alive {
+                comm_log!(self.log_sink, Error, "golang ebpf daemon died: {}", reason);
+            }
+            self.daemon_alive = false;
+            self.stdin = None;
+            self.stdout = None;
+            return true;
+        }
+        false
+    }
+    pub fn write_stdin(&mut self, pid: i32) -> AnyhowResult<()> {
+        self.write_command(&DaemonCommand::Attach {
+            pid,
+            options: Vec::new(),
+        })
+    }
+    pub fn write_command(&mut self, command: &DaemonCommand) -> AnyhowResult<()> {
+        let deadline = Instant::now() + self.max_total_wait;
+        self.write_wire_before(command.to_wire().as_bytes(), deadline)
+    }
+    /// write `wire` in `EBPF_STDIN_CHUNK` chunks, waiting for the daemon to drain stdin
+    /// until `deadline`. a command cut short leaves a partial line in the pipe, the
+    /// daemon should be rotated after a `StdinSlow` or `StdinStalled` with `written > 0`
+    fn write_wire_before(&self, wire: &[u8], deadline: Instant) -> AnyhowResult<()> {
+        let stdin = if let Some(stdin) = self.stdin.as_ref() {
+            stdin
+        } else {
+            return Err(CommError::DaemonDied {
+                reason: String::from("stdin not available"),
+            }
+            .into());
+        };
+        Self::write_wire(stdin, wire, deadline, self.stdin_stall_timeout, &self.log_sink)
+    }
+    fn write_wire(
+        mut stdin: impl Write + AsFd,
+        wire: &[u8],
+        deadline: Instant,
+        stall_timeout: Duration,
+        log_sink: &Option<LogSink>,
+    ) -> AnyhowResult<()> {
+        let total = wire.len();
+        let mut written = 0;
+        let mut last_progress = Instant::now();
+        while written < total {
+            let now = Instant::now();
+            let stalled_for = now - last_progress;
+            if stalled_for >= stall_timeout {
+                return Err(CommError::StdinStalled {
+                    written,
+                    total,
+                    stalled_for,
+                }
+                .into());
+            }
+            if now >= deadline {
+                return Err(CommError::StdinSlow { written, total }.into());
+            }
+            let end = total.min(written + EBPF_STDIN_CHUNK);
+            match stdin.write(&wire[written..end]) {
+                Ok(n) => {
+                    written += n;
+                    last_progress = Instant::now();
+                    if written < total {
+                        comm_log!(
+                            log_sink,
+                            Debug,
+                            "ebpf stdin written: {}/{}",
+                            written,
+                            total
+                        );
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    let wait = (deadline - now).min(stall_timeout - stalled_for);
+                    let mut poll_fd = libc::pollfd {
+                        fd: stdin.as_fd().as_raw_fd(),
+                        events: libc::POLLOUT,
+                        revents: 0,
+                    };
+                    // EINTR or timeout, both are rechecked at the top of the loop
+                    unsafe { libc::poll(&mut poll_fd, 1, wait.as_millis().max(1) as i32) };
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+                    return Err(CommError::DaemonDied {
+                        reason: String::from("stdin closed"),
+                    }
+                    .into());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+    pub fn read_stdout(&mut self, pid: i32) -> AnyhowResult<String> {
+        self.read_stdout_before(pid, Instant::now() + self.max_total_wait)
+    }
+    fn read_stdout_before(&mut self, pid: i32, deadline: Instant) -> AnyhowResult<String> {
+        Ok(match self.read_probe_before(pid, &never(), deadline)? {
+            ProbeResponse::Succeeded { .. } => String::new(),
+            ProbeResponse::Failed(Some(reason)) => {
+                format!("target pid: {} attach failed: {}", pid, reason)
+            }
+            ProbeResponse::Unknown(status) => {
+                format!("target pid: {} attach status unknown: {}", pid, status)
+            }
+            ProbeResponse::Failed(None) | ProbeResponse::Pending => {
+                format!("target pid: {} attach failed", pid)
+            }
+        })
+    }
+    /// final answer for `pid`, never `Pending`, either routed to `routed` or read from stdout
+    fn read_probe_before(
+        &mut self,
+        pid: i32,
+        routed: &Receiver<ProbeResponse>,
+        deadline: Instant,
+    ) -> AnyhowResult<ProbeResponse> {
+        let started = Instant::now();
+        let mut attempts = 0;
+        loop {
+            let (pid_from_server, response) = self
+                .read_probe_response_before(pid, routed, deadline)
+                .map_err(|e| Self::with_wait_context(e, started, attempts))?;
+            attempts += 1;
+            if pid_from_server == pid {
+                self.requested.remove(&pid);
+                return Ok(response);
+            }
+            let outcome = response.into();
+            // answered between its timeout and being marked pending
+            if self.late_attaches.reconcile(pid_from_server, &outcome) {
+                continue;
+            }
+            self.push_unsolicited(pid, pid_from_server, outcome);
+        }
+    }
+    fn push_unsolicited(&mut self, expect: i32, pid: i32, outcome: AttachOutcome) {
+        if self.requested.remove(&pid) {
+            comm_log!(
+                self.log_sink,
+                Debug,
+                "unsolicited ebpf response: expect: {} response: {}",
+                expect,
+                pid
+            );
+        } else {
+            comm_log!(
+                self.log_sink,
+                Warn,
+                "ebpf response for pid never requested: expect: {} response: {}",
+                expect,
+                pid
+            );
+        }
+        self.unsolicited.push((pid, outcome));
+    }
+    /// pid of the running daemon, for monitoring its usage. `None` before `start_server`
+    /// and once the daemon is known dead
+    pub fn server_pid(&self) -> Option<u32> {
+        self.server_pid
+            .filter(|_| self.daemon_alive)
+            .map(|pid| pid as u32)
+    }
+    /// pids the daemon instruments, sorted. late attaches included, detached pids removed.
+    /// the set lives in `late_attaches`, shared with the stdout reader thread
+    pub fn attached_pids(&self) -> Vec<i32> {
+        let mut pids: Vec<i32> = self
+            .late_attaches
+            .attached
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect();
+        pids.sort_unstable();
+        pids
+    }
+    /// take responses the daemon sent for pids nobody was waiting on
+    pub fn drain_unsolicited(&mut self) -> Vec<(i32, AttachOutcome)> {
+        std::mem::take(&mut self.unsolicited)
+    }
+    /// next `(pid, outcome)` response from daemon, `pid` is only used for the timeout error
+    fn read_response_before(
+        &mut self,
+        pid: i32,
+        deadline: Instant,
+    ) -> AnyhowResult<(i32, AttachOutcome)> {
+        let (pid_from_server, response) =
+            self.read_probe_response_before(pid, &never::<ProbeResponse>(), deadline)?;
+        Ok((pid_from_server, response.into()))
+    }
+    /// next final `(pid, response)` from daemon, `Pending` answers are logged and skipped.
+    /// answers routed to `routed` untagged are for `pid`
+    fn read_probe_response_before<T: RoutedResponse>(
+        &mut self,
+        pid: i32,
+        routed: &Receiver<T>,
+        deadline: Instant,
+    ) -> AnyhowResult<(i32, ProbeResponse)> {
+        loop {
+            let stdout = match self.stdout.clone() {
+                Some(stdout) => stdout,
+                None => return Err(Self::stdout_not_available()),
+            };
+            let received = select! {
+                recv(routed) -> response => match response {
+                    Ok(response) => Received::Routed(response.tagged(pid)),
+                    // cleared by `spawn_server`, the daemon waited on is gone
+                    Err(_) => Received::Line(Err(RecvTimeoutError::Disconnected)),
+                },
+                recv(stdout) -> line => {
+                    Received::Line(line.map_err(|_| RecvTimeoutError::Disconnected))
+                }
+                default(deadline.saturating_duration_since(Instant::now())) => {
+                    Received::Line(Err(RecvTimeoutError::Timeout))
+                }
+            };
+            let (pid_from_server, response) = match received {
+                Received::Routed(response) => response,
+                Received::Line(line) => {
+                    let line = self.take_line(pid, line)?;
+                    Self::parse_server_response(&line)?
+                }
+            };
+            match &response {
+                ProbeResponse::Pending => {
+                    comm_log!(self.log_sink, Debug, "ebpf attach pending: {}", pid_from_server);
+                    continue;
+                }
+                ProbeResponse::Failed(Some(reason)) => {
+                    comm_log!(
+                        self.log_sink,
+                        Warn,
+                        "ebpf attach failed: {} {}",
+                        pid_from_server,
+                        reason
+                    );
+                }
+                ProbeResponse::Unknown(status) => {
+                    comm_log!(
+                        self.log_sink,
+                        Warn,
+                        "ebpf attach status unknown, taken as failed: {} {}",
+                        pid_from_server,
+                        status
+                    );
+                }
+                _ => {}
+            }
+            return Ok((pid_from_server, response));
+        }
+    }
+    fn read_line_before(&mut self, pid: i32, deadline: Instant) -> AnyhowResult<String> {
+        let received = match self.stdout.as_ref() {
+            Some(receiver) => receiver.recv_deadline(deadline),
+            None => return Err(Self::stdout_not_available()),
+        };
+        self.take_line(pid, received)
+    }
+    fn stdout_not_available() -> anyhow::Error {
+        CommError::DaemonDied {
+            reason: String::from("stdout not available"),
+        }
+        .into()
+    }
+    /// line received from the stdout reader, `pid` is only used for the timeout error
+    fn take_line(
+        &mut self,
+        pid: i32,
+        received: Result<Result<String, CommError>, RecvTimeoutError>,
+    ) -> AnyhowResult<String> {
+        let read_from_server = match received {
+            Ok(Ok(line)) => line,
+            Ok(Err(e)) => return Err(e.into()),
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(CommError::AttachTimeout {
+                    pid,
+                    diagnostics: Box::new(self.timeout_diagnostics()),
+                }
+                .into());
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(CommError::DaemonDied {
+                    reason: String::from("stdout closed"),
+                }
+                .into());
+            }
+        };
+        self.last_raw_response = Some(read_from_server.trim_end().to_string());
+        Ok(read_from_server)
+    }
+    /// everything known about the daemon right now, `elapsed` and `attempts` are left to
+    /// the caller waiting on it, see `with_wait_context`
+    pub fn timeout_diagnostics(&self) -> TimeoutDiagnostics {
+        let pending_stdin_bytes = self.stdin.as_ref().and_then(|stdin| {
+            let mut pending: libc::c_int = 0;
+            // FIONREAD on either end of a pipe counts the bytes not read yet
+            match unsafe { libc::ioctl(stdin.as_raw_fd(), libc::FIONREAD, &mut pending) } {
+                0 => Some(pending as usize),
+                _ => None,
+            }
+        });
+        TimeoutDiagnostics {
+            elapsed: Duration::ZERO,
+            attempts: 0,
+            last_raw_response: self.last_raw_response.clone(),
+            daemon_alive: self.server_pid.map(|pid| !Self::server_exited(pid)),
+            stderr_tail: self.stderr_tail.lock().unwrap().iter().cloned().collect(),
+            pending_stdin_bytes,
+        }
+    }
+    fn with_wait_context(mut e: anyhow::Error, started: Instant, attempts: u32) -> anyhow::Error {
+        if let Some(CommError::AttachTimeout { diagnostics, .. }) = e.downcast_mut() {
+            diagnostics.elapsed = started.elapsed();
+            diagnostics.attempts = attempts;
+        }
+        e
+    }
+    /// whether the daemon answers within `ping_timeout`, false for one alive but wedged,
+    /// an error for one gone. daemons without `FeatureSet::ping` get an attach of the
+    /// reserved pid 0, any answer for it counts
+    pub fn ping(&mut self) -> AnyhowResult<bool> {
+        let command = if self.supported_features().ping {
+            DaemonCommand::Ping
+        } else {
+            DaemonCommand::Attach {
+                pid: 0,
+                options: Vec::new(),
+            }
+        };
+        let timeout = self.ping_timeout;
+        self.answered_within(&command, timeout, "ping")
+    }
+    /// front-load the daemon's cold start, program loading and map setup, with a no-op
+    /// attach of the reserved pid 0 so the first real attach does not pay for it.
+    /// not ready when the daemon does not answer within `max_total_wait`
+    pub fn warmup(&mut self) -> AnyhowResult<WarmupReport> {
+        let command = DaemonCommand::Attach {
+            pid: 0,
+            options: Vec::new(),
+        };
+        let started = Instant::now();
+        let timeout = self.max_total_wait;
+        let ready = self.answered_within(&command, timeout, "warmup")?;
+        let elapsed = started.elapsed();
+        comm_log!(self.log_sink, Info, "ebpf warmup ready: {} {:?}", ready, elapsed);
+        Ok(WarmupReport { ready, elapsed })
+    }
+    /// write `command` and wait for `pong` or any answer for pid 0
+    fn answered_within(
+        &mut self,
+        command: &DaemonCommand,
+        timeout: Duration,
+        what: &str,
+    ) -> AnyhowResult<bool> {
+        let deadline = Instant::now() + timeout;
+        if let Err(e) = self.write_wire_before(command.to_wire().as_bytes(), deadline) {
+            if self.check_daemon_died(&e) {
+                return Err(e);
+            }
+            return match e.downcast_ref::<CommError>() {
+                Some(CommError::StdinSlow { .. }) | Some(CommError::StdinStalled { .. }) => {
+                    comm_log!(self.log_sink, Warn, "ebpf {} not written: {}", what, e);
+                    Ok(false)
+                }
+                _ => Err(e),
+            };
+        }
+        loop {
+            let line = match self.read_line_before(0, deadline) {
+                Ok(line) => line,
+                Err(e) => {
+                    if let Some(CommError::AttachTimeout { .. }) = e.downcast_ref() {
+                        comm_log!(self.log_sink, Warn, "ebpf {} timeout: {:?}", what, timeout);
+                        return Ok(false);
+                    }
+                    self.check_daemon_died(&e);
+                    return Err(e);
+                }
+            };
+            if let Ok(DaemonResponse::Pong) = DaemonResponse::from_wire(&line) {
+                return Ok(true);
+            }
+            match Self::parse_attach_response(&line) {
+                Ok((0, _)) => return Ok(true),
+                Ok((pid, outcome)) => {
+                    if !self.late_attaches.reconcile(pid, &outcome) {
+                        self.push_unsolicited(0, pid, outcome);
+                    }
+                }
+                Err(_) => comm_log!(
+                    self.log_sink,
+                    Debug,
+                    "ebpf response ignored during {}: {}",
+                    what,
+                    line.trim()
+                ),
+            }
+        }
+    }
+    /// remove the probe from `pid`, other attached processes are left alone. false when
+    /// the daemon answered `detach_failed`. needs a daemon built with `FeatureSet::detach`
+    pub fn detach(&mut self, pid: i32) -> AnyhowResult<bool> {
+        if !self.supported_features().detach {
+            return Err(anyhow!("golang ebpf daemon does not support detach: {}", pid));
+        }
+        if let Err(e) = self.write_command(&DaemonCommand::Detach { pid }) {
+            self.check_daemon_died(&e);
+            return Err(e);
+        }
+        let started = Instant::now();
+        let deadline = started + self.max_total_wait;
+        let mut attempts = 0;
+        loop {
+            let line = match self.read_line_before(pid, deadline) {
+                Ok(line) => line,
+                Err(e) => {
+                    self.check_daemon_died(&e);
+                    return Err(Self::with_wait_context(e, started, attempts));
+                }
             };
-            let result = if let Some(result) = caps.get(2) {
-                match result.as_str() {
-                    "succeed" => true,
-                    "failed" => false,
-                    _ => {
-                        return Err(anyhow!("response format can not parse: {}", response));
+            attempts += 1;
+            match DaemonResponse::from_wire(&line) {
+                Ok(DaemonResponse::Detach {
+                    pid: pid_from_server,
+                    detached,
+                }) if pid_from_server == pid => {
+                    if detached {
+                        self.late_attaches.attached.lock().unwrap().remove(&pid);
+                    }
+                    comm_log!(self.log_sink, Info, "ebpf detach: {} {}", pid, detached);
+                    return Ok(detached);
+                }
+                Ok(DaemonResponse::Attach {
+                    pid: pid_from_server,
+                    response,
+                }) if response != ProbeResponse::Pending => {
+                    let outcome = AttachOutcome::from(response);
+                    if !self.late_attaches.reconcile(pid_from_server, &outcome) {
+                        self.push_unsolicited(pid, pid_from_server, outcome);
+                    }
+                }
+                _ => comm_log!(
+                    self.log_sink,
+                    Debug,
+                    "ebpf response ignored while detaching {}: {}",
+                    pid,
+                    line.trim()
+                ),
+            }
+        }
+    }
+    /// attach pids one by one within a total `budget`. each pid gets a fair share of
+    /// the remaining budget, time left over by fast pids rolls over to the next ones.
+    /// pids not attempted or not answered in time are reported as `TimedOut`.
+    /// `ctrl` is checked between pids, once stopped the rest are reported as `Aborted`.
+    /// a daemon draining stdin too slowly for the budget leaves the rest `NotAttempted`,
+    /// one not draining it at all fails the batch with `StdinStalled`
+    pub fn attach_batch(
+        &mut self,
+        pids: &[i32],
+        budget: Duration,
+    ) -> AnyhowResult<HashMap<i32, AttachOutcome>> {
+        self.attach_batch_with_progress(pids, budget, None)
+    }
+    /// `attach_batch` calling `progress` once per pid, in `pids` order, as soon as its
+    /// outcome is known. it runs on the attaching thread and must not block
+    pub fn attach_batch_with_progress(
+        &mut self,
+        pids: &[i32],
+        budget: Duration,
+        progress: Option<&dyn Fn(&AttachProgress)>,
+    ) -> AnyhowResult<HashMap<i32, AttachOutcome>> {
+        let deadline = Instant::now() + budget;
+        let mut outcomes = HashMap::new();
+        let report = |index: usize, pid: i32, outcome: &AttachOutcome| {
+            if let Some(progress) = progress {
+                progress(&AttachProgress {
+                    processed: index + 1,
+                    total: pids.len(),
+                    pid,
+                    last_outcome: outcome.clone(),
+                });
+            }
+        };
+        let abort = |from: usize, outcomes: &mut HashMap<i32, AttachOutcome>| {
+            for (index, pid) in pids.iter().enumerate().skip(from) {
+                report(index, *pid, &AttachOutcome::Aborted);
+                outcomes.insert(*pid, AttachOutcome::Aborted);
+            }
+        };
+        for (index, pid) in pids.iter().enumerate() {
+            if !self.ctrl.check() {
+                comm_log!(
+                    self.log_sink,
+                    Info,
+                    "ebpf attach batch aborted, {} of {} pids not attempted",
+                    pids.len() - index,
+                    pids.len()
+                );
+                abort(index, &mut outcomes);
+                break;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                report(index, *pid, &AttachOutcome::TimedOut);
+                outcomes.insert(*pid, AttachOutcome::TimedOut);
+                continue;
+            }
+            let command = DaemonCommand::Attach {
+                pid: *pid,
+                options: Vec::new(),
+            };
+            if let Err(e) = self.write_wire_before(command.to_wire().as_bytes(), deadline) {
+                if let Some(CommError::StdinSlow { .. }) = e.downcast_ref() {
+                    comm_log!(
+                        self.log_sink,
+                        Warn,
+                        "ebpf daemon drains stdin too slowly, {} of {} pids not written",
+                        pids.len() - index,
+                        pids.len()
+                    );
+                    for (index, pid) in pids.iter().enumerate().skip(index) {
+                        report(index, *pid, &AttachOutcome::NotAttempted);
+                        outcomes.insert(*pid, AttachOutcome::NotAttempted);
+                    }
+                    break;
+                }
+                self.check_daemon_died(&e);
+                // stopping kills the daemon under the batch, what is done so far stands
+                if !self.ctrl.check() {
+                    comm_log!(self.log_sink, Info, "ebpf attach batch aborted on write: {}", e);
+                    abort(index, &mut outcomes);
+                    break;
+                }
+                return Err(e);
+            }
+            self.requested.insert(*pid);
+            let now = Instant::now();
+            let share = deadline.saturating_duration_since(now) / (pids.len() - index) as u32;
+            let outcome = loop {
+                match self.read_response_before(*pid, now + share) {
+                    Ok((pid_from_server, outcome)) => {
+                        if pid_from_server == *pid {
+                            self.requested.remove(pid);
+                            break outcome;
+                        }
+                        // late response of a pid timed out earlier in this batch, pids
+                        // timed out before being written never were requested
+                        if outcomes.get(&pid_from_server) == Some(&AttachOutcome::TimedOut)
+                            && self.requested.remove(&pid_from_server)
+                        {
+                            comm_log!(
+                                self.log_sink,
+                                Debug,
+                                "late ebpf attach response: {} {:?}",
+                                pid_from_server,
+                                outcome
+                            );
+                            self.late_attaches.record(pid_from_server, &outcome);
+                            outcomes.insert(pid_from_server, outcome);
+                            continue;
+                        }
+                        self.push_unsolicited(*pid, pid_from_server, outcome);
+                    }
+                    Err(e) => {
+                        if let Some(CommError::AttachTimeout { .. }) = e.downcast_ref() {
+                            break AttachOutcome::TimedOut;
+                        }
+                        self.check_daemon_died(&e);
+                        if !self.ctrl.check() {
+                            break AttachOutcome::Aborted;
+                        }
+                        return Err(e);
                     }
                 }
+            };
+            if outcome == AttachOutcome::Aborted {
+                self.requested.remove(pid);
+                comm_log!(
+                    self.log_sink,
+                    Info,
+                    "ebpf attach batch aborted, {} of {} pids not answered",
+                    pids.len() - index,
+                    pids.len()
+                );
+                abort(index, &mut outcomes);
+                break;
+            }
+            self.late_attaches.record(*pid, &outcome);
+            report(index, *pid, &outcome);
+            outcomes.insert(*pid, outcome);
+        }
+        Ok(outcomes)
+    }
+    /// attach `pids` in one round-trip, all of them are written at once and the answers
+    /// taken in whatever order the daemon sends them. false for a pid failed or not
+    /// answered within `max_total_wait`, which stays pending with `resume_timed_out` and
+    /// stops `ctrl` otherwise, see `attach_failed`
+    pub fn attach_many(&mut self, pids: &[i32]) -> AnyhowResult<HashMap<i32, bool>> {
+        self.restart_if_exited()?;
+        let mut attached = HashMap::new();
+        let mut waiting = Vec::new();
+        for pid in pids {
+            if attached.contains_key(pid) || waiting.contains(pid) {
+                continue;
+            }
+            match self.check_duplicate(*pid) {
+                Ok(Some(outcome)) => {
+                    attached.insert(*pid, matches!(outcome, AttachOutcome::Succeed { .. }));
+                }
+                Ok(None) => waiting.push(*pid),
+                // one pid attached before must not fail the others
+                Err(e) => {
+                    comm_log!(self.log_sink, Warn, "ebpf attach skipped: {}", e);
+                    attached.insert(*pid, false);
+                }
+            }
+        }
+        if waiting.is_empty() {
+            return Ok(attached);
+        }
+        let routed = self.router.register_many(&waiting);
+        let result = self.attach_many_routed(&waiting, &routed, &mut attached);
+        for pid in waiting.iter() {
+            self.router.unregister(*pid);
+        }
+        for (pid, response) in routed.try_iter() {
+            if response != ProbeResponse::Pending {
+                self.late_attaches.reconcile(pid, &response.into());
+            }
+        }
+        result.map(|_| attached)
+    }
+    fn attach_many_routed(
+        &mut self,
+        waiting: &[i32],
+        routed: &Receiver<(i32, ProbeResponse)>,
+        attached: &mut HashMap<i32, bool>,
+    ) -> AnyhowResult<()> {
+        let deadline = Instant::now() + self.max_total_wait;
+        let wire: String = waiting
+            .iter()
+            .map(|pid| {
+                DaemonCommand::Attach {
+                    pid: *pid,
+                    options: Vec::new(),
+                }
+                .to_wire()
+            })
+            .collect();
+        if let Err(e) = self.write_wire_before(wire.as_bytes(), deadline) {
+            self.check_daemon_died(&e);
+            return Err(e);
+        }
+        self.requested.extend(waiting.iter().copied());
+        let mut left: HashSet<i32> = waiting.iter().copied().collect();
+        while !left.is_empty() {
+            // the pid only names an untagged answer or the timeout, neither happens here
+            let (pid, response) = match self.read_probe_response_before(0, routed, deadline) {
+                Ok(received) => received,
+                Err(e) => match e.downcast_ref::<CommError>() {
+                    Some(CommError::AttachTimeout { .. }) => break,
+                    _ => return Err(self.attach_failed(0, e)),
+                },
+            };
+            let outcome = response.into();
+            if left.remove(&pid) {
+                self.requested.remove(&pid);
+                self.late_attaches.record(pid, &outcome);
+                attached.insert(pid, matches!(outcome, AttachOutcome::Succeed { .. }));
+                continue;
+            }
+            if self.late_attaches.reconcile(pid, &outcome) {
+                continue;
+            }
+            self.push_unsolicited(0, pid, outcome);
+        }
+        if !left.is_empty() {
+            comm_log!(
+                self.log_sink,
+                Warn,
+                "ebpf attach many timeout, {} of {} pids not answered",
+                left.len(),
+                waiting.len()
+            );
+        }
+        let mut left: Vec<i32> = left.into_iter().collect();
+        left.sort_unstable();
+        for pid in left {
+            // pending with `resume_timed_out`, `ctrl` stopped otherwise, as for `attach`
+            let e = CommError::AttachTimeout {
+                pid,
+                diagnostics: Box::new(self.timeout_diagnostics()),
+            };
+            self.attach_failed(pid, e.into());
+            attached.insert(pid, false);
+        }
+        Ok(())
+    }
+    /// attach every process in the pid namespace of `pid`, the container of a pid when
+    /// cgroups do not group it. each member gets `max_total_wait` of the batch budget
+    pub fn attach_pid_namespace(&mut self, pid: i32) -> AnyhowResult<HashMap<i32, AttachOutcome>> {
+        let members = pid_namespace_members(&self.proc_root, pid)?;
+        comm_log!(
+            self.log_sink,
+            Info,
+            "ebpf attach pid namespace of {}: {} processes",
+            pid,
+            members.len()
+        );
+        let budget = self.max_total_wait * members.len() as u32;
+        self.attach_batch(&members, budget)
+    }
+    /// SIGTERM the daemon so it can detach its programs, SIGKILL it if it is still around
+    /// after `grace`. programs of a daemon killed outright stay loaded until reboot.
+    /// a zero `grace` sends SIGKILL right away
+    pub fn kill_server(pid: i32, grace: Duration) -> KillOutcome {
+        if !grace.is_zero() {
+            let outcome = Self::signal_server(pid, SIGTERM);
+            if outcome != KillOutcome::Signaled {
+                Self::log_kill_outcome(pid, outcome);
+                return outcome;
+            }
+            let deadline = Instant::now() + grace;
+            while Instant::now() < deadline {
+                if Self::server_exited(pid) {
+                    Self::log_kill_outcome(pid, KillOutcome::Terminated);
+                    return KillOutcome::Terminated;
+                }
+                thread::sleep(EBPF_KILL_POLL);
+            }
+            warn!("golang ebpf daemon still running {:?} after SIGTERM: {}", grace, pid);
+        }
+        let outcome = Self::signal_server(pid, SIGKILL);
+        Self::log_kill_outcome(pid, outcome);
+        outcome
+    }
+    fn signal_server(pid: i32, signal: i32) -> KillOutcome {
+        let errno = |result: i32| {
+            if result == 0 {
+                0
+            } else {
+                std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+            }
+        };
+        // the daemon is not a group leader unless spawned as one, ESRCH from killpg is common
+        let group = errno(unsafe { killpg(pid, signal) });
+        let process = errno(unsafe { kill(pid, signal) });
+        KillOutcome::classify(&[group, process])
+    }
+    fn log_kill_outcome(pid: i32, outcome: KillOutcome) {
+        match outcome {
+            KillOutcome::Terminated => debug!("golang ebpf daemon terminated: {}", pid),
+            KillOutcome::Signaled => debug!("golang ebpf daemon killed: {}", pid),
+            KillOutcome::AlreadyGone => info!("golang ebpf daemon already gone: {}", pid),
+            KillOutcome::Error { errno } => error!(
+                "kill golang ebpf daemon {} failed: {}",
+                pid,
+                std::io::Error::from_raw_os_error(errno)
+            ),
+        }
+    }
+    /// gone, or a zombie `ebpf_server_wait` has not reaped yet
+    fn server_exited(pid: i32) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            // state follows the parenthesized comm, which may itself contain `)`
+            Ok(stat) => match stat.rsplit_once(')') {
+                Some((_, rest)) => rest.trim_start().starts_with('Z'),
+                None => false,
+            },
+            Err(_) => true,
+        }
+    }
+    /// the `DaemonResponse::Attach` of a line, any other response is unexpected
+    pub fn parse_server_response(response: &str) -> Result<(i32, ProbeResponse), ProbeError> {
+        match DaemonResponse::from_wire(response)? {
+            DaemonResponse::Attach { pid, response } => Ok((pid, response)),
+            _ => Err(ProbeError::Unexpected {
+                line: response.trim().to_string(),
+            }),
+        }
+    }
+    /// `pid:succeed`, `pid:failed`, or `pid:succeed:hook_a,hook_b` from daemons reporting hooks.
+    /// an unknown status counts as failed, a pending one is an error
+    pub fn parse_attach_response(response: &String) -> AnyhowResult<(i32, AttachOutcome)> {
+        match Self::parse_server_response(response)? {
+            (pid, ProbeResponse::Pending) => Err(anyhow!("ebpf attach still pending: {}", pid)),
+            (pid, response) => Ok((pid, response.into())),
+        }
+    }
+}
+
+/// a mode dropped without `stop`, e.g. while unwinding, must not leave its daemon behind.
+/// best effort: stop `server_ctrl` and SIGTERM the daemon, the `ebpf_server_wait` thread
+/// woken by it SIGKILLs it after `kill_grace` and reaps it
+impl Drop for EbpfMode {
+    fn drop(&mut self) {
+        let server_pid = match self.server_pid.take() {
+            Some(server_pid) => server_pid,
+            None => return,
+        };
+        let server_wait = match self.server_wait.take() {
+            Some(server_wait) => server_wait,
+            None => return,
+        };
+        // reaped already, the pid may belong to another process by now
+        if server_wait.is_finished() {
+            return;
+        }
+        // `ctrl` is shared with the rest of the agent, only this daemon goes away
+        if let Some(mut server_ctrl) = self.server_ctrl.take() {
+            let _ = server_ctrl.stop_with_reason(String::from("ebpf mode dropped"));
+        }
+        comm_log!(self.log_sink, Info, "golang ebpf daemon dropped: {}", server_pid);
+        Self::signal_server(server_pid, SIGTERM);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::EPERM;
+
+    #[test]
+    fn control_group_stops_every_control() {
+        let group = ControlGroup::new();
+        assert!(group.is_empty());
+        let controls = vec![Control::new(), Control::new(), Control::new()];
+        for ctrl in controls.iter() {
+            group.register(ctrl.clone());
+        }
+        assert_eq!(group.len(), 3);
+        assert_eq!(group.stop_all(), vec![Ok(true), Ok(true), Ok(true)]);
+        for mut ctrl in controls {
+            assert!(!ctrl.check());
+        }
+        assert_eq!(group.stop_all(), vec![Ok(false), Ok(false), Ok(false)]);
+    }
+
+    #[test]
+    fn control_wakes_waiters_on_stop() {
+        let ctrl = Control::new();
+        assert!(!ctrl.wait_stopped_timeout(Duration::from_millis(50)));
+        let mut stopper = ctrl.clone();
+        let waiter = thread::spawn(move || {
+            let start = Instant::now();
+            assert!(ctrl.wait_stopped_timeout(Duration::from_secs(10)));
+            ctrl.wait_until_stopped(Duration::from_secs(10));
+            start.elapsed()
+        });
+        thread::sleep(Duration::from_millis(100));
+        stopper.stop().unwrap();
+        assert!(waiter.join().unwrap() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn control_stop_publishes_prior_writes() {
+        // state written before `stop` is seen by whoever observes the stop, even when
+        // written Relaxed itself
+        for _ in 0..100 {
+            let published = Arc::new(AtomicUsize::new(0));
+            let mut ctrl = Control::new();
+            let mut observer = ctrl.clone();
+            let seen = published.clone();
+            let reader = thread::spawn(move || {
+                while observer.check() {
+                    std::hint::spin_loop();
+                }
+                seen.load(Ordering::Relaxed)
+            });
+            published.store(42, Ordering::Relaxed);
+            ctrl.stop().unwrap();
+            assert_eq!(reader.join().unwrap(), 42);
+        }
+    }
+
+    #[test]
+    fn control_keeps_the_first_reason() {
+        let mut ctrl = Control::new();
+        assert_eq!(ctrl.reason(), None);
+        let mut observer = ctrl.clone();
+        assert_eq!(ctrl.stop_with_reason(String::from("probe gone")), Ok(true));
+        assert!(!observer.check());
+        assert_eq!(observer.reason().as_deref(), Some("probe gone"));
+        assert_eq!(observer.stop_with_reason(String::from("later")), Ok(false));
+        assert_eq!(ctrl.reason().as_deref(), Some("probe gone"));
+
+        let mut bare = Control::new();
+        bare.stop().unwrap();
+        bare.stop_with_reason(String::from("too late")).unwrap();
+        assert_eq!(bare.reason(), None);
+    }
+
+    #[test]
+    fn control_stop_reports_who_stopped_it() {
+        let mut ctrl = Control::new();
+        let mut other = ctrl.clone();
+        assert_eq!(ctrl.stop(), Ok(true));
+        assert_eq!(other.stop(), Ok(false));
+        assert!(!other.check());
+    }
+
+    fn mock_ebpf_mode(script: &str) -> EbpfMode {
+        let mut ebpf_mode = EbpfMode::new(Control::new()).unwrap();
+        ebpf_mode
+            .spawn_server(Command::new("sh").args(&["-c", script]))
+            .unwrap();
+        ebpf_mode
+    }
+
+    #[test]
+    fn daemon_closes_stdout_after_one_attach() {
+        let mut ebpf_mode = mock_ebpf_mode("read pid; echo \"$pid:succeed\"");
+        assert!(ebpf_mode.attach(1234).unwrap());
+        let e = ebpf_mode.attach(1235).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::DaemonDied { .. })
+        ));
+        assert!(!ebpf_mode.daemon_alive);
+        // daemon death must not stop the whole world
+        assert!(ebpf_mode.ctrl.check());
+        // later attaches fail fast
+        let e = ebpf_mode.attach(1236).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::DaemonDied { .. })
+        ));
+    }
+
+    #[test]
+    fn lifecycle_events_carry_outcome() {
+        let (sender, receiver) = bounded(10);
+        let mut process_mode = ProcessMode::new(String::from("info"), Control::new());
+        process_mode.events = Some(sender);
+        let mnt_namespace = String::from("mnt:[4026531840]");
+        assert!(process_mode.stop_comm(1234, &mnt_namespace).is_err());
+        match receiver.try_recv().unwrap() {
+            CommEvent::OperationStarted {
+                operation,
+                pid,
+                mnt_namespace: ns,
+                container_id,
+            } => {
+                assert_eq!(operation, CommOperation::StopComm);
+                assert_eq!(pid, 1234);
+                assert_eq!(ns, Some(mnt_namespace.clone()));
+                assert_eq!(container_id, None);
+            }
+            e => panic!("unexpected event: {:?}", e),
+        }
+        match receiver.try_recv().unwrap() {
+            CommEvent::OperationCompleted {
+                operation, outcome, ..
+            } => {
+                assert_eq!(operation, CommOperation::StopComm);
+                assert!(outcome.is_err());
+            }
+            e => panic!("unexpected event: {:?}", e),
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn attach_emits_events() {
+        let (sender, receiver) = bounded(10);
+        let mut ebpf_mode = mock_ebpf_mode("read pid; echo \"$pid:succeed\"; sleep 5");
+        ebpf_mode.events = Some(sender);
+        assert!(ebpf_mode.attach(1234).unwrap());
+        assert!(matches!(
+            receiver.try_recv().unwrap(),
+            CommEvent::OperationStarted {
+                operation: CommOperation::Attach,
+                pid: 1234,
+                ..
+            }
+        ));
+        assert!(matches!(
+            receiver.try_recv().unwrap(),
+            CommEvent::OperationCompleted {
+                operation: CommOperation::Attach,
+                pid: 1234,
+                outcome: Ok(()),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn attach_batch_respects_budget() {
+        let mut ebpf_mode =
+            mock_ebpf_mode("while read pid; do sleep 1; echo \"$pid:succeed\"; done");
+        let pids = [1001, 1002, 1003, 1004];
+        let start = Instant::now();
+        let outcomes = ebpf_mode
+            .attach_batch(&pids, Duration::from_millis(1500))
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(2000));
+        assert_eq!(outcomes.len(), pids.len());
+        assert!(outcomes.values().any(|o| *o == AttachOutcome::TimedOut));
+    }
+
+    #[test]
+    fn attach_batch_all_responsive() {
+        let mut ebpf_mode = mock_ebpf_mode(
+            "while read pid; do \
+             if [ $pid -eq 1002 ]; then echo \"$pid:failed\"; else echo \"$pid:succeed\"; fi; \
+             done",
+        );
+        let outcomes = ebpf_mode
+            .attach_batch(&[1001, 1002, 1003], Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(outcomes[&1001], AttachOutcome::Succeed { hooks: vec![] });
+        assert_eq!(outcomes[&1002], AttachOutcome::Failed);
+        assert_eq!(outcomes[&1003], AttachOutcome::Succeed { hooks: vec![] });
+    }
+
+    #[test]
+    fn attach_batch_reports_progress_in_order() {
+        let mut ebpf_mode = mock_ebpf_mode(
+            "while read pid; do \
+             if [ $pid -eq 1002 ]; then echo \"$pid:failed\"; else echo \"$pid:succeed\"; fi; \
+             done",
+        );
+        let seen = Mutex::new(Vec::new());
+        let progress = |progress: &AttachProgress| seen.lock().unwrap().push(progress.clone());
+        let outcomes = ebpf_mode
+            .attach_batch_with_progress(
+                &[1001, 1002, 1003],
+                Duration::from_secs(5),
+                Some(&progress),
+            )
+            .unwrap();
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(
+            seen.iter()
+                .map(|progress| (progress.processed, progress.total, progress.pid))
+                .collect::<Vec<_>>(),
+            vec![(1, 3, 1001), (2, 3, 1002), (3, 3, 1003)]
+        );
+        assert_eq!(seen[1].last_outcome, AttachOutcome::Failed);
+        for progress in seen {
+            assert_eq!(outcomes[&progress.pid], progress.last_outcome);
+        }
+    }
+
+    #[test]
+    fn stdin_writes_tell_slow_from_stalled() {
+        let junk = vec![b'x'; 256 * 1024];
+        // one PIPE_BUF every 50ms, the pipe never empties in time
+        let mut slow = mock_ebpf_mode(
+            "while :; do dd bs=4096 count=1 of=/dev/null 2>/dev/null; sleep 0.05; done",
+        );
+        slow.stdin_stall_timeout = Duration::from_secs(1);
+        let err = slow
+            .write_wire_before(&junk, Instant::now() + Duration::from_millis(500))
+            .unwrap_err();
+        match err.downcast_ref() {
+            Some(CommError::StdinSlow { written, total }) => {
+                assert!(*written > 0 && *written < *total);
+            }
+            other => panic!("unexpected: {:?}", other),
+        }
+        let mut stuck = mock_ebpf_mode("exec sleep 30");
+        stuck.stdin_stall_timeout = Duration::from_millis(200);
+        let start = Instant::now();
+        let err = stuck
+            .write_wire_before(&junk, start + Duration::from_secs(5))
+            .unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        match err.downcast_ref() {
+            Some(CommError::StdinStalled { written, .. }) => assert!(*written > 0),
+            other => panic!("unexpected: {:?}", other),
+        }
+        // the pipe is full now, the batch budget runs out before any pid is written
+        stuck.stdin_stall_timeout = Duration::from_secs(5);
+        let outcomes = stuck
+            .attach_batch(&[1001, 1002], Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.values().all(|o| *o == AttachOutcome::NotAttempted));
+        stuck.ctrl.stop().unwrap();
+        slow.ctrl.stop().unwrap();
+    }
+
+    #[test]
+    fn link_retried_on_enoent_only() {
+        let not_found = || std::io::Error::from(ErrorKind::NotFound).into();
+        let mut calls = 0;
+        let result = retry_link(3, Duration::from_millis(10), || {
+            calls += 1;
+            if calls == 1 {
+                return Err(not_found());
+            }
+            Ok(())
+        });
+        assert!(result.is_ok());
+        assert_eq!(calls, 2);
+        let mut calls = 0;
+        let (attempts, _) = retry_link(2, Duration::from_millis(1), || {
+            calls += 1;
+            Err(not_found())
+        })
+        .unwrap_err();
+        assert_eq!((attempts, calls), (3, 3));
+        let (attempts, _) = retry_link(2, Duration::from_millis(1), || {
+            Err(std::io::Error::from(ErrorKind::PermissionDenied).into())
+        })
+        .unwrap_err();
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn mount_retried_until_it_succeeds() {
+        let mut calls = 0;
+        let retried = Arc::new(Mutex::new(Vec::new()));
+        let sink_retried = retried.clone();
+        let log_sink: Option<LogSink> = Some(Arc::new(move |level, message: &str| {
+            sink_retried.lock().unwrap().push((level, message.to_string()));
+        }));
+        retry_mount(2, Duration::from_millis(1), &log_sink, || {
+            calls += 1;
+            if calls < 3 {
+                return Err(anyhow!("mount script execute failed: exit status: 1"));
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(calls, 3);
+        // every retry goes to the mode's sink
+        let retried = retried.lock().unwrap();
+        assert_eq!(retried.len(), 2);
+        assert!(retried
+            .iter()
+            .all(|(level, message)| *level == LogLevel::Warn && message.contains("retry")));
+        let mut calls = 0;
+        assert!(retry_mount(2, Duration::from_millis(1), &None, || {
+            calls += 1;
+            Err(anyhow!("mount script execute failed: exit status: 1"))
+        })
+        .is_err());
+        assert_eq!(calls, 3);
+        let mut calls = 0;
+        assert!(retry_mount(2, Duration::from_millis(1), &None, || {
+            calls += 1;
+            Err(CommError::MountSourceMissing {
+                path: String::from("/not/exist"),
+            }
+            .into())
+        })
+        .is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn attach_within_overrides_max_total_wait() {
+        let mut ebpf_mode =
+            mock_ebpf_mode("while read pid; do sleep 0.3; echo \"$pid:succeed\"; done");
+        ebpf_mode.max_total_wait = Duration::from_millis(100);
+        assert!(matches!(
+            ebpf_mode.attach_within(1001, Duration::from_secs(2)).unwrap(),
+            AttachOutcome::Succeed { .. }
+        ));
+        let e = ebpf_mode.attach(1002).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::AttachTimeout { pid: 1002, .. })
+        ));
+    }
+
+    #[test]
+    fn exited_daemon_restarted_with_backoff() {
+        let answer_once = "read pid; echo \"$pid:succeed\"";
+        let mut ebpf_mode = mock_ebpf_mode(answer_once);
+        ebpf_mode.restart_policy = Some(RestartPolicy {
+            max_restarts: 1,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(60),
+        });
+        let respawn = |ebpf_mode: &mut EbpfMode| {
+            ebpf_mode.spawn_server(Command::new("sh").args(&["-c", answer_once]))
+        };
+        // running daemon is left alone
+        assert!(!ebpf_mode.restart_if_exited_with(respawn).unwrap());
+        assert!(ebpf_mode.attach(1234).unwrap());
+        let wait_exit = |ebpf_mode: &EbpfMode| {
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while !EbpfMode::server_exited(ebpf_mode.server_pid.unwrap()) {
+                assert!(Instant::now() < deadline);
+                thread::sleep(Duration::from_millis(20));
+            }
+        };
+        wait_exit(&ebpf_mode);
+        let first = ebpf_mode.server_pid;
+        assert!(ebpf_mode.restart_if_exited_with(respawn).unwrap());
+        assert_ne!(ebpf_mode.server_pid, first);
+        // attached again by the new daemon, which then exits too
+        assert_eq!(ebpf_mode.attached_pids(), vec![1234]);
+        wait_exit(&ebpf_mode);
+        // out of restarts
+        assert!(!ebpf_mode.restart_if_exited_with(respawn).unwrap());
+        assert!(ebpf_mode.restart_given_up);
+        assert_eq!(ebpf_mode.restarts, 1);
+        ebpf_mode.ctrl.stop().unwrap();
+    }
+
+    fn mock_proc_root(namespaces: &[(i32, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "librasp-proc-{}-{}",
+            std::process::id(),
+            namespaces.len()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        for (pid, namespace) in namespaces {
+            let ns = root.join(pid.to_string()).join("ns");
+            create_dir_all(&ns).unwrap();
+            fs::symlink(namespace, ns.join("pid")).unwrap();
+        }
+        // neither a pid nor a process with a readable namespace
+        create_dir_all(root.join("self")).unwrap();
+        create_dir_all(root.join("300")).unwrap();
+        root
+    }
+
+    #[test]
+    fn pid_namespace_members_from_proc() {
+        let root = mock_proc_root(&[
+            (1, "pid:[4026531836]"),
+            (100, "pid:[4026532500]"),
+            (101, "pid:[4026532500]"),
+            (105, "pid:[4026532500]"),
+            (200, "pid:[4026532600]"),
+        ]);
+        assert_eq!(pid_namespace_members(&root, 101).unwrap(), vec![100, 101, 105]);
+        assert_eq!(pid_namespace_members(&root, 200).unwrap(), vec![200]);
+        // the root namespace is every process
+        assert!(pid_namespace_members(&root, 1).is_err());
+        assert!(pid_namespace_members(&root, 300).is_err());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn attach_pid_namespace_batches_members() {
+        let root = mock_proc_root(&[
+            (1, "pid:[4026531836]"),
+            (100, "pid:[4026532500]"),
+            (101, "pid:[4026532500]"),
+            (200, "pid:[4026532600]"),
+        ]);
+        let mut ebpf_mode = mock_ebpf_mode("while read pid; do echo \"$pid:succeed\"; done");
+        ebpf_mode.proc_root = root.clone();
+        let outcomes = ebpf_mode.attach_pid_namespace(100).unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert!(matches!(outcomes[&101], AttachOutcome::Succeed { .. }));
+        assert_eq!(ebpf_mode.attached_pids(), vec![100, 101]);
+        ebpf_mode.ctrl.stop().unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn daemon_lines_are_capped() {
+        let mut input = vec![b'a'; 1 << 20];
+        input.extend_from_slice(b"\n1234:succeed\n");
+        let mut reader = std::io::Cursor::new(input);
+        let mut line = Vec::new();
+        assert_eq!(
+            read_line_capped(&mut reader, &mut line, 1024).unwrap(),
+            CappedLine::TooLong {
+                length: (1 << 20) + 1
+            }
+        );
+        assert_eq!(line.len(), 1024);
+        assert!(line.capacity() < 1 << 20);
+        line.clear();
+        assert_eq!(read_line_capped(&mut reader, &mut line, 1024).unwrap(), CappedLine::Line);
+        assert_eq!(line, b"1234:succeed\n");
+        line.clear();
+        assert_eq!(read_line_capped(&mut reader, &mut line, 1024).unwrap(), CappedLine::Eof);
+
+        let mut ebpf_mode = EbpfMode::new(Control::new()).unwrap();
+        ebpf_mode.max_line_length = 1024;
+        let script = "read pid; head -c 200000 /dev/zero | tr '\\0' a; echo; exec sleep 5";
+        ebpf_mode
+            .spawn_server(Command::new("sh").args(&["-c", script]))
+            .unwrap();
+        let e = ebpf_mode.attach(1234).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::ResponseTooLong {
+                length: 200001,
+                limit: 1024
+            })
+        ));
+        ebpf_mode.ctrl.stop().unwrap();
+    }
+
+    #[test]
+    fn responses_routed_to_their_waiter() {
+        let router = ResponseRouter::default();
+        let first = router.register(1001);
+        let second = router.register(1002);
+        assert!(router.route(1002, ProbeResponse::Pending));
+        assert!(router.route(1001, ProbeResponse::Failed(None)));
+        assert!(!router.route(1003, ProbeResponse::Failed(None)));
+        assert_eq!(first.try_recv().unwrap(), ProbeResponse::Failed(None));
+        assert_eq!(second.try_recv().unwrap(), ProbeResponse::Pending);
+        router.unregister(1001);
+        assert!(!router.route(1001, ProbeResponse::Failed(None)));
+
+        // answered out of order, the waiter of 1001 gets its answer past the attach of 1002
+        let mut ebpf_mode = mock_ebpf_mode(
+            "read a; read b; echo \"$b:succeed\"; echo \"$a:failed\"; exec sleep 5",
+        );
+        let routed = ebpf_mode.router.register(1001);
+        ebpf_mode.write_stdin(1001).unwrap();
+        assert!(ebpf_mode.attach(1002).unwrap());
+        assert_eq!(
+            routed.recv_timeout(Duration::from_secs(2)).unwrap(),
+            ProbeResponse::Failed(None)
+        );
+        assert!(ebpf_mode.drain_unsolicited().is_empty());
+        ebpf_mode.ctrl.stop().unwrap();
+    }
+
+    #[test]
+    fn concurrent_attaches_get_their_own_response() {
+        // odd pids fail, so a response handed to the wrong caller shows up
+        let ebpf_mode = Arc::new(Mutex::new(mock_ebpf_mode(
+            "while read pid; do \
+                 if [ $((pid % 2)) -eq 0 ]; then echo \"$pid:succeed\"; \
+                 else echo \"$pid:failed\"; fi; \
+             done",
+        )));
+        let handles: Vec<_> = (1000..1008)
+            .map(|pid| {
+                let ebpf_mode = ebpf_mode.clone();
+                thread::spawn(move || (pid, ebpf_mode.lock().unwrap().attach(pid).unwrap()))
+            })
+            .collect();
+        for handle in handles {
+            let (pid, attached) = handle.join().unwrap();
+            assert_eq!(attached, pid % 2 == 0, "pid: {}", pid);
+        }
+        let mut ebpf_mode = ebpf_mode.lock().unwrap();
+        assert_eq!(ebpf_mode.attached_pids(), vec![1000, 1002, 1004, 1006]);
+        assert!(ebpf_mode.drain_unsolicited().is_empty());
+        ebpf_mode.ctrl.stop().unwrap();
+    }
+
+    /// spans and the span each event fell in, fields rendered with `{:?}`
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct SpanRecorder {
+        spans: Mutex<Vec<(String, HashMap<String, String>)>>,
+        current: Mutex<Vec<u64>>,
+        events: Mutex<Vec<(Option<u64>, HashMap<String, String>)>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    struct Recording(Arc<SpanRecorder>);
+
+    #[cfg(feature = "tracing")]
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Recording {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = HashMap::new();
+            span.record(&mut FieldVisitor(&mut fields));
+            let mut spans = self.0.spans.lock().unwrap();
+            spans.push((span.metadata().name().to_string(), fields));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut fields = HashMap::new();
+            event.record(&mut FieldVisitor(&mut fields));
+            let current = self.0.current.lock().unwrap().last().copied();
+            self.0.events.lock().unwrap().push((current, fields));
+        }
+        fn enter(&self, span: &tracing::span::Id) {
+            self.0.current.lock().unwrap().push(span.into_u64());
+        }
+        fn exit(&self, _: &tracing::span::Id) {
+            self.0.current.lock().unwrap().pop();
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn attach_runs_in_a_span() {
+        let recorder = Arc::new(SpanRecorder::default());
+        let mut ebpf_mode = mock_ebpf_mode("read pid; echo \"$pid:failed:no go symbols\"");
+        tracing::subscriber::with_default(Recording(recorder.clone()), || {
+            assert!(!ebpf_mode.attach(1234).unwrap());
+        });
+        let spans = recorder.spans.lock().unwrap();
+        let (id, (_, fields)) = spans
+            .iter()
+            .enumerate()
+            .find(|(_, (name, _))| name == "comm_operation")
+            .unwrap();
+        assert_eq!(fields["pid"], "1234");
+        assert_eq!(fields["mode"], "\"ebpf\"");
+        assert_eq!(fields["operation"], "Attach");
+        assert!(fields.contains_key("corr_id"));
+        let events = recorder.events.lock().unwrap();
+        let in_span: Vec<_> = events
+            .iter()
+            .filter(|(span, _)| *span == Some(id as u64 + 1))
+            .map(|(_, fields)| fields["message"].clone())
+            .collect();
+        assert!(in_span.contains(&String::from("ebpf attach failed: 1234 no go symbols")));
+        assert!(in_span.contains(&String::from("comm operation completed")));
+        ebpf_mode.ctrl.stop().unwrap();
+    }
+
+    #[test]
+    fn attach_timeout_carries_diagnostics() {
+        let mut ebpf_mode = mock_ebpf_mode(
+            "read pid; echo 'uprobe attach slow' >&2; echo '999:succeed'; exec sleep 30",
+        );
+        let start = Instant::now();
+        let e = ebpf_mode.attach_within(1234, Duration::from_millis(300)).unwrap_err();
+        let diagnostics = match e.downcast_ref::<CommError>() {
+            Some(CommError::AttachTimeout { pid: 1234, diagnostics }) => diagnostics,
+            other => panic!("unexpected error: {:?}", other),
+        };
+        // the wait starts once the pid is written, a little after the deadline was set
+        assert!(diagnostics.elapsed >= Duration::from_millis(250));
+        assert!(diagnostics.elapsed <= start.elapsed());
+        assert_eq!(diagnostics.attempts, 1);
+        assert_eq!(diagnostics.last_raw_response.as_deref(), Some("999:succeed"));
+        assert_eq!(diagnostics.daemon_alive, Some(true));
+        assert_eq!(diagnostics.stderr_tail, vec![String::from("uprobe attach slow")]);
+        assert_eq!(diagnostics.pending_stdin_bytes, Some(0));
+        ebpf_mode.ctrl.stop().unwrap();
+    }
+
+    #[test]
+    fn root_mnt_namespace_read_again_after_failed_init() {
+        let link = std::env::temp_dir().join(format!("librasp-root-mnt-{}", std::process::id()));
+        let _ = remove_file(&link);
+        let root = RootMntNamespace::init(link.clone(), 2, Duration::from_millis(1));
+        assert!(root.cached.lock().unwrap().is_none());
+        assert!(root.get().is_err());
+        let target = std::env::current_exe().unwrap();
+        let id = namespace_id(&target).unwrap();
+        fs::symlink(&target, &link).unwrap();
+        assert_eq!(root.get().unwrap(), id);
+        // the first stat that succeeds is kept
+        remove_file(&link).unwrap();
+        assert_eq!(root.get().unwrap(), id);
+        fs::symlink(&target, &link).unwrap();
+        let cached = RootMntNamespace::init(link.clone(), 2, Duration::from_millis(1));
+        remove_file(&link).unwrap();
+        assert_eq!(cached.get().unwrap(), id);
+    }
+
+    #[test]
+    fn check_need_mount_compares_namespace_inodes() {
+        let own = std::process::id() as i32;
+        let own_mnt = namespace_id(std::path::Path::new("/proc/self/ns/mnt")).unwrap();
+        // the test runs in the namespace of pid 1 exactly when their inodes match
+        if let Ok(root_mnt) = namespace_id(std::path::Path::new(ROOT_MNT_NAMESPACE_LINK)) {
+            assert_eq!(check_need_mount(own).unwrap(), root_mnt != own_mnt);
+        }
+        assert!(check_need_mount(i32::MAX).is_err());
+    }
+
+    #[test]
+    fn detach_removes_one_pid() {
+        let mut ebpf_mode = mock_ebpf_mode(
+            "while read cmd pid; do \
+               if [ \"$cmd\" = detach ]; then echo \"$pid:detached\"; \
+               else echo \"$cmd:succeed\"; fi; \
+             done",
+        );
+        // released daemons do not know the command
+        assert!(ebpf_mode.detach(1001).is_err());
+        for band in ebpf_mode.version_matrix.iter_mut() {
+            band.features.detach = true;
+        }
+        assert!(ebpf_mode.attach(1001).unwrap());
+        assert!(ebpf_mode.attach(1002).unwrap());
+        assert_eq!(ebpf_mode.attached_pids(), vec![1001, 1002]);
+        assert!(ebpf_mode.detach(1001).unwrap());
+        assert_eq!(ebpf_mode.attached_pids(), vec![1002]);
+    }
+
+    #[test]
+    fn server_pid_follows_the_daemon() {
+        let mut ebpf_mode = EbpfMode::new(Control::new()).unwrap();
+        assert_eq!(ebpf_mode.server_pid(), None);
+        ebpf_mode
+            .spawn_server(Command::new("sh").args(&["-c", "exec sleep 30"]))
+            .unwrap();
+        let server_pid = ebpf_mode.server_pid().unwrap();
+        assert_eq!(Some(server_pid as i32), ebpf_mode.server_pid);
+        assert!(std::path::Path::new(&format!("/proc/{}", server_pid)).exists());
+        EbpfMode::kill_server(server_pid as i32, Duration::ZERO);
+        assert!(ebpf_mode.attach(1234).is_err());
+        assert_eq!(ebpf_mode.server_pid(), None);
+    }
+
+    #[test]
+    fn stop_tears_the_daemon_down() {
+        let mut ebpf_mode = mock_ebpf_mode("while read pid; do echo \"$pid:succeed\"; done");
+        assert!(ebpf_mode.attach(1234).unwrap());
+        let server_pid = ebpf_mode.server_pid().unwrap();
+        let start = Instant::now();
+        ebpf_mode.stop().unwrap();
+        // the wait thread is woken instead of finishing its poll
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!std::path::Path::new(&format!("/proc/{}", server_pid)).exists());
+        assert!(!ebpf_mode.ctrl.check());
+        assert_eq!(ebpf_mode.server_pid(), None);
+        assert!(ebpf_mode.stdout_reader.is_none() && ebpf_mode.server_wait.is_none());
+        ebpf_mode.stop().unwrap();
+        assert!(ebpf_mode.attach(1235).is_err());
+    }
+
+    #[test]
+    fn drop_terminates_the_daemon() {
+        let ebpf_mode = mock_ebpf_mode("exec sleep 30");
+        let server_pid = ebpf_mode.server_pid().unwrap();
+        let mut ctrl = ebpf_mode.ctrl.clone();
+        let mut server_ctrl = ebpf_mode.server_ctrl.clone().unwrap();
+        drop(ebpf_mode);
+        // the agent-wide control keeps running
+        assert!(ctrl.check());
+        assert!(!server_ctrl.check());
+        assert_eq!(server_ctrl.reason().as_deref(), Some("ebpf mode dropped"));
+        let proc_dir = format!("/proc/{}", server_pid);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while std::path::Path::new(&proc_dir).exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(!std::path::Path::new(&proc_dir).exists());
+
+        // stopped modes have nothing left to signal
+        let mut ebpf_mode = mock_ebpf_mode("exec sleep 30");
+        ebpf_mode.stop().unwrap();
+        assert!(ebpf_mode.server_pid.is_none() && ebpf_mode.server_wait.is_none());
+        drop(ebpf_mode);
+    }
+
+    #[test]
+    fn ping_tells_wedged_from_dead() {
+        let mut ebpf_mode = mock_ebpf_mode(
+            "while read cmd; do \
+               if [ \"$cmd\" = ping ]; then echo pong; \
+               elif [ \"$cmd\" = 0 ]; then echo '0:failed'; fi; \
+             done",
+        );
+        ebpf_mode.ping_timeout = Duration::from_millis(500);
+        // released daemons answer the reserved pid
+        assert!(ebpf_mode.ping().unwrap());
+        for band in ebpf_mode.version_matrix.iter_mut() {
+            band.features.ping = true;
+        }
+        assert!(ebpf_mode.ping().unwrap());
+        ebpf_mode.ctrl.stop().unwrap();
+
+        let mut wedged = mock_ebpf_mode("exec sleep 30");
+        wedged.ping_timeout = Duration::from_millis(200);
+        let start = Instant::now();
+        assert!(!wedged.ping().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        EbpfMode::kill_server(wedged.server_pid.unwrap(), Duration::ZERO);
+        assert!(wedged.ping().is_err());
+        assert!(!wedged.daemon_alive);
+    }
+
+    #[test]
+    fn warmup_pays_the_cold_start() {
+        // the first command loads the programs
+        let mut ebpf_mode = mock_ebpf_mode(
+            "read pid; sleep 0.4; echo \"$pid:failed\"; \
+             while read pid; do echo \"$pid:succeed\"; done",
+        );
+        let report = ebpf_mode.warmup().unwrap();
+        assert!(report.ready);
+        assert!(report.elapsed >= Duration::from_millis(400));
+        assert!(ebpf_mode.late_attaches.attached.lock().unwrap().is_empty());
+        let start = Instant::now();
+        assert!(ebpf_mode.attach(1234).unwrap());
+        assert!(start.elapsed() < report.elapsed);
+        ebpf_mode.ctrl.stop().unwrap();
+
+        let mut wedged = mock_ebpf_mode("exec sleep 30");
+        wedged.max_total_wait = Duration::from_millis(200);
+        assert!(!wedged.warmup().unwrap().ready);
+        EbpfMode::kill_server(wedged.server_pid.unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn attach_batch_aborts_when_stopped() {
+        let mut ebpf_mode =
+            mock_ebpf_mode("while read pid; do sleep 0.3; echo \"$pid:succeed\"; done");
+        let mut ctrl = ebpf_mode.ctrl.clone();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(450));
+            ctrl.stop().unwrap();
+        });
+        let pids: Vec<i32> = (1001..1011).collect();
+        let start = Instant::now();
+        let outcomes = ebpf_mode
+            .attach_batch(&pids, Duration::from_secs(10))
+            .unwrap();
+        stopper.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(outcomes.len(), pids.len());
+        assert_eq!(outcomes[&1001], AttachOutcome::Succeed { hooks: vec![] });
+        assert_eq!(outcomes[&1010], AttachOutcome::Aborted);
+    }
+
+    #[test]
+    fn report_subscribers_select_with_timer() {
+        let (report_sender, report_receiver) = bounded(10);
+        let reports = ReportBuffer::new(10);
+        reports.forward_to(report_sender).unwrap();
+        let first = reports.subscribe(10);
+        let second = reports.subscribe(10);
+        let report = |data_type| {
+            let mut record = plugins::Record::new();
+            record.set_data_type(data_type);
+            record
+        };
+        reports.send(report(2001)).unwrap();
+        let timer = crossbeam::channel::after(Duration::from_secs(1));
+        for subscriber in [&first, &second] {
+            crossbeam::select! {
+                recv(subscriber) -> record => assert_eq!(record.unwrap().get_data_type(), 2001),
+                recv(timer) -> _ => panic!("report not broadcast"),
+            }
+        }
+        assert!(report_receiver.recv_timeout(Duration::from_secs(1)).is_ok());
+        crossbeam::select! {
+            recv(first) -> _ => panic!("unexpected report"),
+            recv(crossbeam::channel::after(Duration::from_millis(100))) -> _ => {}
+        }
+        // a dropped subscriber does not disturb the others, resize keeps subscriptions
+        drop(second);
+        reports.resize(20).unwrap();
+        reports.send(report(2002)).unwrap();
+        assert_eq!(
+            first.recv_timeout(Duration::from_secs(1)).unwrap().get_data_type(),
+            2002
+        );
+        assert!(report_receiver.recv_timeout(Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn expired_servers_are_recycled_one_by_one() {
+        let (events, event_receiver) = bounded(10);
+        let mut process_mode = ProcessMode::new(String::from("info"), Control::new());
+        process_mode.events = Some(events);
+        process_mode.max_server_lifetime = Some(Duration::from_millis(300));
+        process_mode.recycle_min_interval = Duration::from_millis(200);
+        for (mnt_namespace, pid) in [("mnt:[1]", 1001), ("mnt:[2]", 1002)] {
+            // never spawned, so `kill` has nothing to signal
+            let (record_sender, _) = bounded(1);
+            let (_, message_receiver) = bounded(1);
+            let server = RASPServerProcess::new(
+                pid,
+                record_sender,
+                message_receiver,
+                String::from("info"),
+                HashMap::new(),
+                libraspserver::utils::Control::new(),
+            )
+            .unwrap();
+            process_mode
+                .mnt_namesapce_server_map
+                .insert(mnt_namespace.to_string(), server);
+            process_mode
+                .server_started
+                .insert(mnt_namespace.to_string(), Instant::now());
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut respawned = Vec::new();
+        let mut respawn = |server: &mut RASPServerProcess| {
+            respawned.push(server.pid);
+            Ok(())
+        };
+        assert_eq!(process_mode.reap_expired_servers_with(&mut respawn), None);
+        thread::sleep(Duration::from_millis(320));
+        assert_eq!(
+            process_mode.reap_expired_servers_with(&mut respawn),
+            Some(String::from("mnt:[1]"))
+        );
+        // staggered, the second expired server waits for `recycle_min_interval`
+        assert_eq!(process_mode.reap_expired_servers_with(&mut respawn), None);
+        // unless a reset lifts the cooldown
+        assert_eq!(process_mode.reset_protections(), vec!["recycle cooldown"]);
+        assert!(process_mode.reset_protections().is_empty());
+        assert_eq!(
+            process_mode.reap_expired_servers_with(&mut respawn),
+            Some(String::from("mnt:[2]"))
+        );
+        assert_eq!(respawned, vec![1001, 1002]);
+        assert_eq!(process_mode.recycle_count, 2);
+        assert!(matches!(
+            event_receiver.try_recv().unwrap(),
+            CommEvent::OperationStarted {
+                operation: CommOperation::Recycle,
+                pid: 1001,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn namespace_locks_serialize_start_stop() {
+        let dir = std::env::temp_dir().join(format!("rasp-ns-stress-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server_bin = dir.join("rasp_server");
+        // records the pid of every server spawned, `exec` keeps it
+        std::fs::write(
+            &server_bin,
+            format!("#!/bin/sh\necho $$ >> {}\nexec sleep 30\n", dir.join("pids").display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&server_bin, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let mut process_mode = ProcessMode::new(String::from("info"), Control::new());
+        process_mode.server_bin = server_bin.display().to_string();
+        process_mode.drain_grace = Duration::from_millis(10);
+        process_mode.stop_grace = Duration::from_secs(1);
+        let process_mode = Arc::new(Mutex::new(process_mode));
+        let (report_sender, _report_receiver) = bounded(100);
+        let mnt_namespace = String::from("mnt:[4026531840]");
+        let mut workers = Vec::new();
+        for worker in 0..8 {
+            let process_mode = process_mode.clone();
+            let report_sender = report_sender.clone();
+            let mnt_namespace = mnt_namespace.clone();
+            workers.push(thread::spawn(move || {
+                // each worker a pid of its own sharing the namespace server
+                let pid = 1000 + worker;
+                for _ in 0..10 {
+                    process_mode
+                        .lock()
+                        .unwrap()
+                        .start_comm(pid, &mnt_namespace, report_sender.clone(), HashMap::new())
+                        .unwrap();
+                    thread::yield_now();
+                    process_mode
+                        .lock()
+                        .unwrap()
+                        .stop_comm(pid, &mnt_namespace)
+                        .unwrap();
+                    thread::yield_now();
+                }
+            }));
+        }
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let process_mode = process_mode.lock().unwrap();
+        assert_eq!(process_mode.namespace_locks.held(), 0);
+        assert!(process_mode.mnt_namesapce_server_map.is_empty());
+        assert!(process_mode.namespace_pids.is_empty());
+        assert!(process_mode.mnt_namespace_comm_pair.is_empty());
+        // no server outlives the last stop
+        let pids = std::fs::read_to_string(dir.join("pids")).unwrap();
+        assert!(!pids.is_empty());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        for pid in pids.lines().map(|pid| pid.parse::<i32>().unwrap()) {
+            while unsafe { kill(pid, 0) } == 0 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_ne!(unsafe { kill(pid, 0) }, 0, "server leaked: {}", pid);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn report_buffer_drains_within_grace() {
+        let record = || plugins::Record::new();
+        // slow consumer, keeps up within the grace
+        let (report_sender, report_receiver) = bounded(1);
+        let reports = ReportBuffer::new(10);
+        reports.forward_to(report_sender).unwrap();
+        for _ in 0..5 {
+            reports.send(record()).unwrap();
+        }
+        let consumer = thread::spawn(move || {
+            let mut received = 0;
+            while report_receiver.recv_timeout(Duration::from_millis(500)).is_ok() {
+                received += 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+            received
+        });
+        let report = reports.drain(Duration::from_secs(2));
+        assert_eq!(report, DrainReport { drained: 5, dropped: 0 });
+        assert!(reports.send(record()).is_err());
+        assert_eq!(consumer.join().unwrap(), 5);
+
+        // stuck consumer, whatever is left after the grace is dropped
+        let (report_sender, _report_receiver) = bounded(1);
+        let reports = ReportBuffer::new(10);
+        reports.forward_to(report_sender).unwrap();
+        for _ in 0..5 {
+            reports.send(record()).unwrap();
+        }
+        let start = Instant::now();
+        let report = reports.drain(Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(report.drained, 1);
+        // 3 buffered, 1 held by the forwarder
+        assert_eq!(report.dropped, 4);
+        assert_eq!(report.drained + report.dropped, 5);
+    }
+
+    #[test]
+    fn namespace_stats_survive_recycle() {
+        let mnt_namespace = String::from("mnt:[4026532281]");
+        let mut process_mode = ProcessMode::new(String::from("info"), Control::new());
+        let add_server = |process_mode: &mut ProcessMode| {
+            let (record_sender, _) = bounded(1);
+            let (_, message_receiver) = bounded(1);
+            process_mode.mnt_namesapce_server_map.insert(
+                mnt_namespace.clone(),
+                RASPServerProcess::new(
+                    1234,
+                    record_sender,
+                    message_receiver,
+                    String::from("info"),
+                    HashMap::new(),
+                    libraspserver::utils::Control::new(),
+                )
+                .unwrap(),
+            );
+            process_mode
+                .namespace_stats
+                .insert(mnt_namespace.clone(), NamespaceStats::new());
+        };
+        add_server(&mut process_mode);
+        process_mode.record_attach(1235, &mnt_namespace);
+        let started_at = process_mode.namespace_stats[&mnt_namespace].started_at;
+        process_mode
+            .recycle_with(&mnt_namespace, |_| Ok(()))
+            .unwrap();
+        process_mode.record_attach(1236, &mnt_namespace);
+        let managed = process_mode.managed();
+        assert_eq!(managed.len(), 1);
+        assert_eq!(
+            managed[0],
+            (
+                mnt_namespace.clone(),
+                NamespaceStats {
+                    started_at,
+                    attach_count: 3,
+                    recycle_count: 1,
+                }
+            )
+        );
+        // a real stop and start begins from scratch
+        process_mode.stop_comm(1234, &mnt_namespace).unwrap();
+        assert!(process_mode.managed().is_empty());
+        add_server(&mut process_mode);
+        let stats = &process_mode.managed()[0].1;
+        assert_eq!((stats.attach_count, stats.recycle_count), (1, 0));
+        assert!(stats.started_at > started_at);
+    }
+
+    #[test]
+    fn namespace_server_shared_by_its_pids() {
+        let dir = std::env::temp_dir().join(format!("rasp-server-shared-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server = dir.join("rasp_server");
+        std::fs::write(&server, "#!/bin/sh\nexec cat\n").unwrap();
+        std::fs::set_permissions(&server, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let mnt_namespace = String::from("mnt:[4026532281]");
+        let mut process_mode = ProcessMode::new(String::from("info"), Control::new());
+        process_mode.stop_grace = Duration::from_millis(500);
+        let (record_sender, _) = bounded(1);
+        let (_, message_receiver) = bounded(1);
+        let mut runner = RASPServerProcess::new(
+            1234,
+            record_sender.clone(),
+            message_receiver,
+            String::from("info"),
+            HashMap::new(),
+            libraspserver::utils::Control::new(),
+        )
+        .unwrap();
+        runner.spawn(server.to_str().unwrap()).unwrap();
+        let child_id = runner.child_id;
+        process_mode
+            .mnt_namesapce_server_map
+            .insert(mnt_namespace.clone(), runner);
+        process_mode
+            .namespace_stats
+            .insert(mnt_namespace.clone(), NamespaceStats::new());
+        process_mode.record_attach(1234, &mnt_namespace);
+        // the running server is reused, nothing is spawned for the second pid
+        process_mode
+            .start_comm(1235, &mnt_namespace, record_sender, HashMap::new())
+            .unwrap();
+        assert_eq!(process_mode.mnt_namesapce_server_map[&mnt_namespace].child_id, child_id);
+        assert_eq!(process_mode.namespace_pids[&mnt_namespace].len(), 2);
+        process_mode.stop_comm(1234, &mnt_namespace).unwrap();
+        assert!(process_mode.mnt_namesapce_server_map[&mnt_namespace].is_running());
+        process_mode.stop_comm(1235, &mnt_namespace).unwrap();
+        assert!(!process_mode.mnt_namesapce_server_map.contains_key(&mnt_namespace));
+        assert!(!process_mode.namespace_pids.contains_key(&mnt_namespace));
+        assert!(process_mode.stop_comm(1235, &mnt_namespace).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn ping_probe_round_trip() {
+        let (report_sender, report_receiver) = bounded(10);
+        let pings = ProbePings::new();
+        let reports = ReportBuffer::new(10);
+        reports.forward_to(report_sender).unwrap();
+        let tapped = pings.tap(reports).unwrap();
+        // plain reports pass through
+        let mut record = plugins::Record::new();
+        record
+            .mut_data()
+            .mut_fields()
+            .insert("RASP_DATA".to_string(), "{\"message_type\": 2}".to_string());
+        tapped.send(record).unwrap();
+        assert!(report_receiver.recv_timeout(Duration::from_secs(1)).is_ok());
+        // fake probe answering PING
+        let rtt = pings
+            .ping(1234, Duration::from_secs(1), |message| {
+                let ping: serde_json::Value = serde_json::from_str(message)?;
+                assert_eq!(ping["pid"], 1234);
+                let mut pong = plugins::Record::new();
+                pong.mut_data().mut_fields().insert(
+                    "RASP_DATA".to_string(),
+                    serde_json::json!({
+                        "message_type": PROBE_PING_MESSAGE_TYPE,
+                        "data": { "uuid": ping["data"]["uuid"] },
+                    })
+                    .to_string(),
+                );
+                tapped.send(pong)?;
+                Ok(())
+            })
+            .unwrap();
+        assert!(rtt < Duration::from_secs(1));
+        // PONG never reaches the report consumer
+        assert!(report_receiver.try_recv().is_err());
+        // silent probe
+        assert!(pings
+            .ping(1234, Duration::from_millis(100), |_| Ok(()))
+            .is_err());
+        assert!(pings.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn log_sink_receives_comm_logs() {
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let captured = logs.clone();
+        let mut ebpf_mode = mock_ebpf_mode("exit 0");
+        ebpf_mode.log_sink = Some(Arc::new(move |level, message: &str| {
+            captured.lock().unwrap().push((level, message.to_string()));
+        }));
+        thread::sleep(Duration::from_millis(200));
+        assert!(ebpf_mode.attach(1234).is_err());
+        let logs = logs.lock().unwrap();
+        assert!(logs.iter().any(|(level, message)| {
+            *level == LogLevel::Error && message.starts_with("golang ebpf daemon died")
+        }));
+    }
+
+    #[test]
+    fn daemon_stderr_is_logged() {
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let captured = logs.clone();
+        let mut ebpf_mode = EbpfMode::new(Control::new()).unwrap();
+        ebpf_mode.log_sink = Some(Arc::new(move |level, message: &str| {
+            captured.lock().unwrap().push((level, message.to_string()));
+        }));
+        let script = "echo 'loading uprobes' >&2; \
+                      echo 'failed to load program: EPERM' >&2; exec sleep 5";
+        ebpf_mode
+            .spawn_server(Command::new("sh").args(&["-c", script]))
+            .unwrap();
+        let daemon_lines = || {
+            let logs = logs.lock().unwrap();
+            let daemon = logs.iter().filter(|(_, message)| message.starts_with("[ebpf-daemon]"));
+            daemon.cloned().collect::<Vec<_>>()
+        };
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while daemon_lines().len() < 2 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(20));
+        }
+        let logs = daemon_lines();
+        assert!(logs.contains(&(LogLevel::Warn, String::from("[ebpf-daemon] loading uprobes"))));
+        assert!(logs.contains(&(
+            LogLevel::Error,
+            String::from("[ebpf-daemon] failed to load program: EPERM")
+        )));
+        ebpf_mode.ctrl.stop().unwrap();
+    }
+
+    #[test]
+    fn mount_rejects_missing_source() {
+        let pid = std::process::id() as i32;
+        let e = mount(pid, "/not/exist/rasp/bind", "/tmp/rasp", false).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::MountSourceMissing { path }) if path == "/not/exist/rasp/bind"
+        ));
+        assert!(check_mount_paths(pid, "/tmp", "relative/path").is_err());
+        assert!(check_mount_paths(i32::MAX, "/tmp", "/tmp/rasp").is_err());
+        assert!(check_mount_paths(pid, "/tmp", "/tmp/rasp").is_ok());
+    }
+
+    #[test]
+    fn mountinfo_mountpoints() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+36 22 0:31 / /proc rw,nosuid - proc proc rw
+612 22 8:1 /opt/rasp /var/run/elkeid\\040rasp rw - ext4 /dev/sda1 rw
+";
+        assert_eq!(
+            parse_mountinfo_mountpoints(mountinfo),
+            vec!["/", "/proc", "/var/run/elkeid rasp"]
+        );
+        let pid = std::process::id() as i32;
+        assert!(check_mount_visible(pid, &format!("/proc/{}/root/proc", pid)).is_ok());
+        let e = check_mount_visible(pid, "/not/a/mountpoint/").unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::MountNotVisible { path, .. }) if path == "/not/a/mountpoint"
+        ));
+    }
+
+    #[test]
+    fn parse_attach_response_with_hooks() {
+        let parse = |response: &str| EbpfMode::parse_attach_response(&response.to_string());
+        assert_eq!(
+            parse("1234:succeed\n").unwrap(),
+            (1234, AttachOutcome::Succeed { hooks: vec![] })
+        );
+        assert_eq!(
+            parse("1234:succeed:net_http,os_exec,sql\n").unwrap(),
+            (
+                1234,
+                AttachOutcome::Succeed {
+                    hooks: vec![
+                        String::from("net_http"),
+                        String::from("os_exec"),
+                        String::from("sql"),
+                    ]
+                }
+            )
+        );
+        assert_eq!(
+            parse("1234:succeed:\n").unwrap(),
+            (1234, AttachOutcome::Succeed { hooks: vec![] })
+        );
+        assert_eq!(parse("1234:failed\n").unwrap(), (1234, AttachOutcome::Failed));
+        assert!(parse("garbage\n").is_err());
+    }
+
+    #[test]
+    fn parse_server_response_tells_failure_from_garbage() {
+        let parse = |response: &str| EbpfMode::parse_server_response(&response.to_string());
+        assert_eq!(
+            parse("1234:succeed:sql").unwrap(),
+            (
+                1234,
+                ProbeResponse::Succeeded {
+                    hooks: vec![String::from("sql")]
+                }
+            )
+        );
+        assert_eq!(parse("1234:failed\n").unwrap(), (1234, ProbeResponse::Failed(None)));
+        assert_eq!(
+            parse("1234:failed:symbol not found: runtime.main\n").unwrap(),
+            (
+                1234,
+                ProbeResponse::Failed(Some(String::from("symbol not found: runtime.main")))
+            )
+        );
+        assert_eq!(parse("1234:pending").unwrap(), (1234, ProbeResponse::Pending));
+        assert_eq!(
+            parse("1234:throttled").unwrap(),
+            (1234, ProbeResponse::Unknown(String::from("throttled")))
+        );
+        assert!(matches!(parse("garbage"), Err(ProbeError::Malformed { .. })));
+        assert!(matches!(parse("1234:detached"), Err(ProbeError::Unexpected { .. })));
+    }
+
+    #[test]
+    fn attach_waits_past_pending_and_keeps_failure_reason() {
+        let mut ebpf_mode = mock_ebpf_mode(
+            "while read pid; do echo \"$pid:pending\"; echo \"$pid:failed:no go symbols\"; done",
+        );
+        assert!(!ebpf_mode.attach(1234).unwrap());
+        ebpf_mode.write_stdin(1235).unwrap();
+        assert_eq!(
+            ebpf_mode.read_stdout(1235).unwrap(),
+            "target pid: 1235 attach failed: no go symbols"
+        );
+        ebpf_mode.ctrl.stop().unwrap();
+    }
+
+    #[test]
+    fn daemon_protocol_round_trip() {
+        let commands = vec![
+            DaemonCommand::Attach {
+                pid: 1234,
+                options: vec![],
+            },
+            DaemonCommand::Attach {
+                pid: 1234,
+                options: vec![
+                    (String::from("hooks"), String::from("sql")),
+                    (String::from("uprobe_limit"), String::from("64")),
+                ],
+            },
+            DaemonCommand::Detach { pid: 1234 },
+            DaemonCommand::List,
+            DaemonCommand::Version,
+            DaemonCommand::Ping,
+        ];
+        for command in commands {
+            assert_eq!(DaemonCommand::from_wire(&command.to_wire()).unwrap(), command);
+        }
+        // what released daemons read
+        assert_eq!(
+            DaemonCommand::Attach {
+                pid: 1234,
+                options: vec![]
+            }
+            .to_wire(),
+            "1234\n"
+        );
+        assert!(DaemonCommand::from_wire("attach me\n").is_err());
+        assert!(DaemonCommand::from_wire("1234 no_value\n").is_err());
+
+        let responses = vec![
+            DaemonResponse::Attach {
+                pid: 1234,
+                response: ProbeResponse::Succeeded { hooks: vec![] },
+            },
+            DaemonResponse::Attach {
+                pid: 1234,
+                response: ProbeResponse::Succeeded {
+                    hooks: vec![String::from("sql"), String::from("os_exec")],
+                },
+            },
+            DaemonResponse::Attach {
+                pid: 1234,
+                response: ProbeResponse::Failed(None),
+            },
+            DaemonResponse::Attach {
+                pid: 1234,
+                response: ProbeResponse::Failed(Some(String::from("no go symbols"))),
+            },
+            DaemonResponse::Attach {
+                pid: 1234,
+                response: ProbeResponse::Pending,
+            },
+            DaemonResponse::Detach {
+                pid: 1234,
+                detached: true,
+            },
+            DaemonResponse::Detach {
+                pid: 1234,
+                detached: false,
+            },
+            DaemonResponse::List {
+                pids: vec![1234, 1235],
+            },
+            DaemonResponse::List { pids: vec![] },
+            DaemonResponse::Version {
+                version: String::from("1.2.0"),
+            },
+            DaemonResponse::Pong,
+            DaemonResponse::Ready,
+        ];
+        for response in responses {
+            assert_eq!(
+                DaemonResponse::from_wire(&response.to_wire()).unwrap(),
+                response
+            );
+        }
+        assert!(DaemonResponse::from_wire("garbage\n").is_err());
+    }
+
+    #[test]
+    fn attach_detailed_reports_hooks() {
+        let mut ebpf_mode =
+            mock_ebpf_mode("while read pid; do echo \"$pid:succeed:os_exec\"; done");
+        assert_eq!(
+            ebpf_mode.attach_detailed(1234).unwrap(),
+            AttachOutcome::Succeed {
+                hooks: vec![String::from("os_exec")]
+            }
+        );
+        assert!(ebpf_mode.attach(1235).unwrap());
+    }
+
+    #[test]
+    fn duplicate_attach_policies() {
+        // answers every pid once, a second write for the same pid is never answered
+        let script = "seen=''; while read pid; do case \" $seen \" in *\" $pid \"*) ;; \
+                      *) seen=\"$seen $pid\"; echo \"$pid:succeed:os_exec\";; esac; done";
+        let mut ebpf_mode = mock_ebpf_mode(script);
+        assert!(ebpf_mode.attach(1234).unwrap());
+        // default policy does not contact the daemon
+        assert_eq!(ebpf_mode.on_duplicate, OnDuplicate::Ignore);
+        assert_eq!(
+            ebpf_mode.attach_detailed(1234).unwrap(),
+            AttachOutcome::Succeed { hooks: Vec::new() }
+        );
+        ebpf_mode.on_duplicate = OnDuplicate::Error;
+        let e = ebpf_mode.attach(1234).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::AlreadyAttached { pid: 1234 })
+        ));
+        // reattach reaches the daemon and gets fresh hooks back
+        let mut ebpf_mode =
+            mock_ebpf_mode("while read pid; do echo \"$pid:succeed:os_exec\"; done");
+        ebpf_mode.on_duplicate = OnDuplicate::Reattach;
+        assert!(ebpf_mode.attach(1234).unwrap());
+        assert_eq!(
+            ebpf_mode.attach_detailed(1234).unwrap(),
+            AttachOutcome::Succeed {
+                hooks: vec![String::from("os_exec")]
+            }
+        );
+        assert!(ebpf_mode.late_attaches.is_attached(1234));
+    }
+
+    #[test]
+    fn readiness_probe_sees_stdin_reads() {
+        let reading = mock_ebpf_mode("exec cat");
+        let sleeping = mock_ebpf_mode("exec sleep 30");
+        thread::sleep(Duration::from_millis(200));
+        let reading_pid = reading.server_pid.unwrap();
+        let sleeping_pid = sleeping.server_pid.unwrap();
+        let ready = EbpfMode::waiting_on_stdin(reading_pid);
+        let not_ready = EbpfMode::waiting_on_stdin(sleeping_pid);
+        EbpfMode::kill_server(reading_pid, Duration::ZERO);
+        EbpfMode::kill_server(sleeping_pid, Duration::ZERO);
+        // /proc/pid/task/*/syscall is missing without CONFIG_HAVE_ARCH_TRACEHOOK
+        if ready.is_some() {
+            assert_eq!(ready, Some(true));
+            assert_eq!(not_ready, Some(false));
+        }
+    }
+
+    #[test]
+    fn server_receives_initial_config() {
+        use libraspserver::process_mode::{
+            read_initial_config, RASPServerProcess, INITIAL_CONFIG_ENV,
+        };
+        use libraspserver::proto::{PidMissingProbeConfig, ProbeConfigData};
+        let dir = std::env::temp_dir().join(format!("rasp-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let received = dir.join("received.json");
+        // stand-in server keeps a copy of what it was handed, then reads stdin forever
+        let server = dir.join("rasp_server");
+        std::fs::write(
+            &server,
+            format!(
+                "#!/bin/sh\ncp \"${}\" {1}.tmp && mv {1}.tmp {1}\nexec cat\n",
+                INITIAL_CONFIG_ENV,
+                received.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&server, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let (record_sender, _record_receiver) = bounded(10);
+        let (_message_sender, message_receiver) = bounded(10);
+        let mut server_process = RASPServerProcess::new(
+            1234,
+            record_sender,
+            message_receiver,
+            String::from("info"),
+            HashMap::new(),
+            libraspserver::utils::Control::new(),
+        )
+        .unwrap();
+        server_process.set_initial_config(vec![PidMissingProbeConfig {
+            message_type: 6,
+            data: ProbeConfigData {
+                rule_version: Some(7),
+                ..Default::default()
+            },
+        }]);
+        server_process.spawn(server.to_str().unwrap()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !received.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        let configs = read_initial_config(&received).unwrap();
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].message_type, 6);
+        assert_eq!(configs[0].data.rule_version, Some(7));
+        // the agent side copy is cleaned up once the server is gone
+        server_process.kill();
+        let leftovers = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .flatten()
+            .filter(|entry| {
+                entry.file_name().to_string_lossy().starts_with(&format!(
+                    "rasp_server_config_{}_",
+                    std::process::id()
+                ))
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn server_stopped_gracefully_before_killed() {
+        let dir = std::env::temp_dir().join(format!("rasp-server-stop-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let terminated = dir.join("terminated");
+        let spawn = |script: String| {
+            let server = dir.join("rasp_server");
+            let _ = std::fs::remove_file(&server);
+            std::fs::write(&server, script).unwrap();
+            std::fs::set_permissions(&server, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+                .unwrap();
+            let (record_sender, _) = bounded(10);
+            let (_, message_receiver) = bounded(10);
+            let mut server_process = RASPServerProcess::new(
+                1234,
+                record_sender,
+                message_receiver,
+                String::from("info"),
+                HashMap::new(),
+                libraspserver::utils::Control::new(),
+            )
+            .unwrap();
+            server_process.spawn(server.to_str().unwrap()).unwrap();
+            // give the shell time to set its trap
+            thread::sleep(Duration::from_millis(200));
+            server_process
+        };
+        let mut server_process = spawn(format!(
+            "#!/bin/sh\ntrap 'touch {}; exit 0' TERM\nwhile :; do sleep 0.05; done\n",
+            terminated.display()
+        ));
+        assert!(server_process.stop_gracefully(Duration::from_secs(5)));
+        assert!(terminated.exists());
+        // deaf to SIGTERM, killed once the grace is over
+        let mut server_process = spawn(String::from("#!/bin/sh\ntrap '' TERM\nexec sleep 30\n"));
+        let start = Instant::now();
+        assert!(!server_process.stop_gracefully(Duration::from_millis(300)));
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !EbpfMode::server_exited(server_process.child_id as i32) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(EbpfMode::server_exited(server_process.child_id as i32));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn linking_to_template_expansion() {
+        let mnt_namespace = String::from("mnt:[4026532281]");
+        assert_eq!(
+            expand_linking_to("/var/run/rasp", 1234, &mnt_namespace).unwrap(),
+            "/var/run/rasp"
+        );
+        assert_eq!(
+            expand_linking_to("/var/run/rasp-{mnt_namespace}/{pid}", 1234, &mnt_namespace)
+                .unwrap(),
+            "/var/run/rasp-4026532281/1234"
+        );
+        // unknown markers and escapes are rejected
+        assert!(expand_linking_to("/var/run/{tenant}", 1234, &mnt_namespace).is_err());
+        assert!(expand_linking_to("/var/run/../../etc", 1234, &mnt_namespace).is_err());
+        assert!(expand_linking_to("var/run/rasp", 1234, &mnt_namespace).is_err());
+    }
+
+    #[test]
+    fn thread_mode_server_health() {
+        let wait_for = |thread_mode: &ThreadMode, healthy: bool| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while thread_mode.server_healthy() != healthy && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(20));
+            }
+            thread_mode.server_healthy() == healthy
+        };
+        let bind_dir = std::env::temp_dir().join(format!("rasp-health-{}", std::process::id()));
+        let (record_sender, _record_receiver) = bounded(10);
+        let thread_mode = ThreadMode::new(
+            String::from("info"),
+            Control::new(),
+            record_sender.clone(),
+            bind_dir.join("rasp.sock").display().to_string(),
+            Vec::new(),
+            false,
+        )
+        .unwrap();
+        assert!(wait_for(&thread_mode, true));
+        let _ = std::fs::remove_dir_all(&bind_dir);
+        // longer than sun_path, binding fails and the server thread exits
+        let thread_mode = ThreadMode::new(
+            String::from("info"),
+            Control::new(),
+            record_sender,
+            bind_dir.join("s".repeat(120)).display().to_string(),
+            Vec::new(),
+            false,
+        )
+        .unwrap();
+        assert!(wait_for(&thread_mode, false));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !thread_mode.server_thread.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(thread_mode.server_thread.is_finished());
+        let _ = std::fs::remove_dir_all(&bind_dir);
+    }
+
+    #[test]
+    fn bind_path_checked_at_new() {
+        assert!(ThreadMode::check_bind_dir("rasp/rasp.sock")
+            .unwrap_err()
+            .to_string()
+            .contains("rasp/rasp.sock"));
+        assert!(ThreadMode::check_bind_dir("/").is_err());
+        let bind_dir = std::env::temp_dir().join(format!("rasp-bind-dir-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&bind_dir);
+        let bind_path = bind_dir.join("nested").join("rasp.sock");
+        ThreadMode::check_bind_dir(&bind_path.display().to_string()).unwrap();
+        assert!(bind_dir.join("nested").is_dir());
+        std::fs::remove_dir_all(&bind_dir).unwrap();
+    }
+
+    #[test]
+    fn lazy_mount_links_on_first_send() {
+        let dir = std::env::temp_dir().join(format!("rasp-lazy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pid = std::process::id() as i32;
+        let mnt_namespace = read_link("/proc/self/ns/mnt")
+            .unwrap()
+            .display()
+            .to_string();
+        let (record_sender, _record_receiver) = bounded(10);
+        let mut thread_mode = ThreadMode::new(
+            String::from("info"),
+            Control::new(),
+            record_sender,
+            dir.join("rasp.sock").display().to_string(),
+            vec![dir.join("{pid}.sock").display().to_string()],
+            false,
+        )
+        .unwrap();
+        thread_mode.lazy_mount = true;
+        let link = dir.join(format!("{}.sock", pid));
+        let (report_sender, _) = bounded(1);
+        thread_mode
+            .start_comm(pid, &mnt_namespace, report_sender.clone(), HashMap::new())
+            .unwrap();
+        assert!(symlink_metadata(&link).is_err());
+        thread_mode
+            .send_message_to_probe(pid, &mnt_namespace, &String::from("{}"))
+            .unwrap();
+        assert!(symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert!(thread_mode.pending_mounts.is_empty());
+        assert_eq!(
+            thread_mode.links.get(&pid),
+            Some(&(mnt_namespace.clone(), vec![link.display().to_string()]))
+        );
+        thread_mode.links.clear();
+
+        // failure of the deferred link reaches the send, which stays pending
+        std::fs::remove_file(&link).unwrap();
+        std::fs::create_dir_all(&link).unwrap();
+        thread_mode
+            .start_comm(pid, &mnt_namespace, report_sender.clone(), HashMap::new())
+            .unwrap();
+        assert!(thread_mode
+            .send_message_to_probe(pid, &mnt_namespace, &String::from("{}"))
+            .is_err());
+        assert_eq!(thread_mode.pending_mounts.get(&mnt_namespace), Some(&vec![pid]));
+
+        // every pid of the namespace waits for its own link, stopping one keeps the rest
+        let mut other = Command::new("sleep").arg("5").spawn().unwrap();
+        let other_pid = other.id() as i32;
+        thread_mode
+            .start_comm(other_pid, &mnt_namespace, report_sender, HashMap::new())
+            .unwrap();
+        assert_eq!(
+            thread_mode.pending_mounts.get(&mnt_namespace),
+            Some(&vec![pid, other_pid])
+        );
+        thread_mode.stop_comm(pid, &mnt_namespace).unwrap();
+        assert_eq!(thread_mode.pending_mounts.get(&mnt_namespace), Some(&vec![other_pid]));
+        std::fs::remove_dir_all(&link).unwrap();
+        thread_mode
+            .send_message_to_probe(pid, &mnt_namespace, &String::from("{}"))
+            .unwrap();
+        assert!(thread_mode.pending_mounts.is_empty());
+        assert!(symlink_metadata(dir.join(format!("{}.sock", other_pid)))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        let _ = other.kill();
+        let _ = other.wait();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stop_comm_unmounts_what_start_comm_mounted() {
+        let dir = std::env::temp_dir().join(format!("rasp-umount-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pid = std::process::id() as i32;
+        let mnt_namespace = read_link("/proc/self/ns/mnt")
+            .unwrap()
+            .display()
+            .to_string();
+        let (record_sender, _record_receiver) = bounded(10);
+        let mut thread_mode = ThreadMode::new(
+            String::from("info"),
+            Control::new(),
+            record_sender,
+            dir.join("rasp.sock").display().to_string(),
+            Vec::new(),
+            false,
+        )
+        .unwrap();
+        let nsenter = dir.join("nsenter");
+        let called = dir.join("called");
+        std::fs::write(
+            &nsenter,
+            format!("#!/bin/sh\necho \"$@\" >> {}\n", called.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&nsenter, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        thread_mode.nsenter_bin = nsenter.display().to_string();
+        thread_mode
+            .mounts
+            .insert(mnt_namespace.clone(), (pid, String::from("/var/run/rasp")));
+        // the pid stopped may have exited already, the one mounted through is tried next
+        thread_mode.stop_comm(-1, &mnt_namespace).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&called).unwrap(),
+            format!("-t {} -m umount -l /var/run/rasp\n", pid)
+        );
+        assert!(thread_mode.mounts.is_empty());
+        // unmounted once only
+        thread_mode.stop_comm(pid, &mnt_namespace).unwrap();
+        assert_eq!(std::fs::read_to_string(&called).unwrap().lines().count(), 1);
+
+        // nobody left in the namespace, nothing to run
+        let mut child = Command::new("true").spawn().unwrap();
+        let gone = child.id() as i32;
+        child.wait().unwrap();
+        thread_mode
+            .mounts
+            .insert(String::from("mnt:[1]"), (gone, String::from("/var/run/rasp")));
+        thread_mode
+            .stop_comm(gone, &String::from("mnt:[1]"))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&called).unwrap().lines().count(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stop_comm_removes_only_its_links() {
+        let dir = std::env::temp_dir().join(format!("rasp-unlink-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pid = std::process::id() as i32;
+        let mnt_namespace = read_link("/proc/self/ns/mnt")
+            .unwrap()
+            .display()
+            .to_string();
+        let (record_sender, _record_receiver) = bounded(10);
+        let mut thread_mode = ThreadMode::new(
+            String::from("info"),
+            Control::new(),
+            record_sender,
+            dir.join("rasp.sock").display().to_string(),
+            vec![dir.join("{pid}.sock").display().to_string()],
+            false,
+        )
+        .unwrap();
+        let nsenter = dir.join("nsenter");
+        // drops `-t <pid> -m` and runs the rest here
+        std::fs::write(&nsenter, "#!/bin/sh\nshift 3\nexec \"$@\"\n").unwrap();
+        std::fs::set_permissions(&nsenter, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        thread_mode.nsenter_bin = nsenter.display().to_string();
+        let (report_sender, _) = bounded(1);
+        thread_mode
+            .start_comm(pid, &mnt_namespace, report_sender.clone(), HashMap::new())
+            .unwrap();
+        let link = dir.join(format!("{}.sock", pid));
+        assert!(symlink_metadata(&link).is_ok());
+        thread_mode.stop_comm(pid, &mnt_namespace).unwrap();
+        assert!(symlink_metadata(&link).is_err());
+        assert!(thread_mode.links.is_empty());
+
+        // replaced by someone else after linking
+        thread_mode
+            .start_comm(pid, &mnt_namespace, report_sender, HashMap::new())
+            .unwrap();
+        std::fs::remove_file(&link).unwrap();
+        fs::symlink("/elsewhere", &link).unwrap();
+        thread_mode.stop_comm(pid, &mnt_namespace).unwrap();
+        assert_eq!(read_link(&link).unwrap(), PathBuf::from("/elsewhere"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn start_comm_links_every_target() {
+        let dir = std::env::temp_dir().join(format!("rasp-links-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pid = std::process::id() as i32;
+        let mnt_namespace = read_link("/proc/self/ns/mnt")
+            .unwrap()
+            .display()
+            .to_string();
+        let (record_sender, _record_receiver) = bounded(10);
+        let mut thread_mode = ThreadMode::new(
+            String::from("info"),
+            Control::new(),
+            record_sender,
+            dir.join("rasp.sock").display().to_string(),
+            vec![
+                dir.join("taken.sock").display().to_string(),
+                dir.join("{pid}.sock").display().to_string(),
+            ],
+            false,
+        )
+        .unwrap();
+        // a dir in the way of the first link, the second is made anyway
+        std::fs::create_dir_all(dir.join("taken.sock")).unwrap();
+        let (report_sender, _) = bounded(1);
+        let err = thread_mode
+            .start_comm(pid, &mnt_namespace, report_sender, HashMap::new())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommError>(),
+            Some(CommError::LinkFailed { .. })
+        ));
+        let link = dir.join(format!("{}.sock", pid));
+        assert!(symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(
+            thread_mode.links.get(&pid),
+            Some(&(mnt_namespace.clone(), vec![link.display().to_string()]))
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn mount_without_link_follows_policy() {
+        let dir = std::env::temp_dir().join(format!("librasp-unlinked-{}", std::process::id()));
+        let (record_sender, _record_receiver) = bounded(10);
+        let mut thread_mode = ThreadMode::new(
+            String::from("info"),
+            Control::new(),
+            record_sender,
+            dir.join("rasp.sock").display().to_string(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        // not the root mnt namespace, so the socket dir would be mounted
+        let mnt_namespace = String::from("mnt:[1]");
+        let pid = std::process::id() as i32;
+        let (report_sender, _) = bounded(1);
+        thread_mode.unlinked_mount = UnlinkedMount::Refuse;
+        let e = thread_mode
+            .start_comm(pid, &mnt_namespace, report_sender.clone(), HashMap::new())
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::IncoherentConfig { .. })
+        ));
+        thread_mode.unlinked_mount = UnlinkedMount::Skip;
+        thread_mode
+            .start_comm(pid, &mnt_namespace, report_sender, HashMap::new())
+            .unwrap();
+        thread_mode.ctrl.stop().unwrap();
+    }
+
+    #[test]
+    fn read_only_paths_refused_upfront() {
+        let read_only = |_: &std::path::Path| Err(std::io::Error::from_raw_os_error(libc::EROFS));
+        let checked = Mutex::new(Vec::new());
+        let dir = std::env::temp_dir();
+        let e = check_writable_with(&dir.join("rasp-not-created/rasp.sock"), |existing| {
+            checked.lock().unwrap().push(existing.to_path_buf());
+            read_only(existing)
+        })
+        .unwrap_err();
+        // checked where the socket dir would be created
+        assert_eq!(checked.into_inner().unwrap(), vec![dir.clone()]);
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::ReadOnlyPath { path }) if path == &dir.display().to_string()
+        ));
+        let e = check_writable_with(&dir, |_| Err(std::io::Error::from_raw_os_error(libc::EIO)))
+            .unwrap_err();
+        assert!(e.downcast_ref::<CommError>().is_none());
+        assert!(check_writable(&dir.join("rasp-not-created")).is_ok());
+    }
+
+    #[test]
+    fn transport_info_per_namespace() {
+        let mnt_namespace = String::from("mnt:[4026532281]");
+        let bind_dir = format!("/tmp/rasp-transport-test-{}", std::process::id());
+        let bind_path = format!("{}/rasp.sock", bind_dir);
+        let (record_sender, _record_receiver) = bounded(10);
+        let thread_mode = ThreadMode::new(
+            String::from("info"),
+            Control::new(),
+            record_sender.clone(),
+            bind_path.clone(),
+            vec![String::from("/var/run/rasp-{mnt_namespace}/{pid}.sock")],
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            thread_mode.transport_info(&mnt_namespace),
+            Some(TransportInfo {
+                bind_path,
+                link_targets: vec![String::from("/var/run/rasp-4026532281/{pid}.sock")],
+                transport: Transport::Thread,
+            })
+        );
+        let mut process_mode = ProcessMode::new(String::from("info"), Control::new());
+        assert_eq!(process_mode.transport_info(&mnt_namespace), None);
+        let (_message_sender, message_receiver) = bounded(10);
+        process_mode.mnt_namesapce_server_map.insert(
+            mnt_namespace.clone(),
+            libraspserver::process_mode::RASPServerProcess::new(
+                1234,
+                record_sender,
+                message_receiver,
+                String::from("info"),
+                HashMap::new(),
+                libraspserver::utils::Control::new(),
+            )
+            .unwrap(),
+        );
+        let info = process_mode.transport_info(&mnt_namespace).unwrap();
+        assert_eq!(info.transport, Transport::Process);
+        assert_eq!(info.bind_path, PROCESS_MODE_BIND_PATH);
+        let _ = std::fs::remove_dir_all(&bind_dir);
+    }
+
+    #[test]
+    fn kill_outcome_classification() {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        assert_eq!(
+            EbpfMode::kill_server(child.id() as i32, Duration::ZERO),
+            KillOutcome::AlreadyGone
+        );
+        let ebpf_mode = mock_ebpf_mode("exec sleep 30");
+        assert_eq!(
+            EbpfMode::kill_server(ebpf_mode.server_pid.unwrap(), Duration::ZERO),
+            KillOutcome::Signaled
+        );
+        let trapping = mock_ebpf_mode("trap 'exit 0' TERM; while :; do sleep 0.1; done");
+        assert_eq!(
+            EbpfMode::kill_server(trapping.server_pid.unwrap(), Duration::from_secs(2)),
+            KillOutcome::Terminated
+        );
+        let ignoring = mock_ebpf_mode("trap '' TERM; exec sleep 30");
+        // let the shell install the trap first
+        thread::sleep(Duration::from_millis(200));
+        let start = Instant::now();
+        assert_eq!(
+            EbpfMode::kill_server(ignoring.server_pid.unwrap(), Duration::from_millis(300)),
+            KillOutcome::Signaled
+        );
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(KillOutcome::classify(&[ESRCH, 0]), KillOutcome::Signaled);
+        assert_eq!(KillOutcome::classify(&[ESRCH, ESRCH]), KillOutcome::AlreadyGone);
+        assert_eq!(
+            KillOutcome::classify(&[ESRCH, EPERM]),
+            KillOutcome::Error { errno: EPERM }
+        );
+    }
+
+    #[test]
+    fn attach_waits_up_to_max_total_wait() {
+        let script = "while read pid; do sleep 0.3; echo \"$pid:succeed\"; done";
+        for (max_total_wait, answered) in [
+            (Duration::from_millis(100), false),
+            (Duration::from_millis(1500), true),
+            (Duration::from_secs(15), true),
+        ] {
+            let mut ebpf_mode = mock_ebpf_mode(script);
+            ebpf_mode.resume_timed_out = true;
+            ebpf_mode.max_total_wait = max_total_wait;
+            let start = Instant::now();
+            let result = ebpf_mode.attach(1234);
+            if answered {
+                assert!(result.unwrap());
+                // answered as soon as the daemon writes, not at the end of the wait
+                assert!(start.elapsed() < Duration::from_millis(1000));
             } else {
-                return Err(anyhow!("response format can not parse: {}", response));
-            };
-            return Ok((pid, result));
+                assert!(matches!(
+                    result.unwrap_err().downcast_ref::<CommError>(),
+                    Some(CommError::AttachTimeout { pid: 1234, .. })
+                ));
+                assert!(start.elapsed() < Duration::from_millis(300));
+            }
+        }
+    }
+
+    #[test]
+    fn nsenter_version_probe() {
+        assert_eq!(
+            parse_nsenter_version("nsenter from util-linux 2.38.1\n"),
+            Some((2, 38))
+        );
+        assert_eq!(parse_nsenter_version("nsenter from util-linux 2.23"), Some((2, 23)));
+        assert_eq!(parse_nsenter_version("BusyBox v1.36.1 multi-call binary."), None);
+        let dir = std::env::temp_dir().join(format!("rasp-nsenter-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let nsenter = dir.join("nsenter");
+        std::fs::write(&nsenter, "#!/bin/sh\necho 'nsenter from util-linux 2.22.2'\n").unwrap();
+        std::fs::set_permissions(&nsenter, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let e = check_nsenter_version(nsenter.to_str().unwrap()).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::NsEnterTooOld { found, minimum })
+                if found == "2.22" && minimum == "2.23"
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn version_matrix_validation() {
+        let version = procfs::sys::kernel::Version::new;
+        let matrix = default_version_matrix();
+        check_version_matrix(&matrix).unwrap();
+        assert_eq!(
+            bpf_suffix_for_version(&matrix, &version(5, 4, 0)).unwrap().suffix,
+            "_5.2"
+        );
+        for (kernel, suffix) in [
+            (version(4, 14, 0), "_4.14"),
+            (version(4, 16, 0), "_4.16"),
+            (version(5, 2, 0), "_5.2"),
+            (version(5, 8, 0), "_5.8"),
+            (version(5, 19, 0), "_5.8"),
+            (version(6, 1, 0), "_6.0"),
+        ] {
+            assert_eq!(bpf_suffix_for_version(&matrix, &kernel).unwrap().suffix, suffix);
+        }
+        assert!(bpf_suffix_for_version(&matrix, &version(4, 9, 0)).is_none());
+        let band = |min, max, suffix: &str| VersionBand {
+            min,
+            max,
+            suffix: suffix.to_string(),
+            features: FeatureSet::default(),
+        };
+        // gaps are allowed
+        check_version_matrix(&[
+            band(version(4, 14, 0), Some(version(4, 15, 0)), "_a"),
+            band(version(5, 0, 0), None, "_b"),
+        ])
+        .unwrap();
+        let overlapping = [
+            band(version(4, 14, 0), Some(version(5, 2, 0)), "_a"),
+            band(version(5, 0, 0), None, "_b"),
+        ];
+        assert!(check_version_matrix(&overlapping).is_err());
+        let descending = [
+            band(version(5, 8, 0), Some(version(5, 10, 0)), "_a"),
+            band(version(4, 14, 0), Some(version(4, 16, 0)), "_b"),
+        ];
+        assert!(check_version_matrix(&descending).is_err());
+        let open_in_middle = [
+            band(version(4, 14, 0), None, "_a"),
+            band(version(5, 8, 0), None, "_b"),
+        ];
+        assert!(check_version_matrix(&open_in_middle).is_err());
+        let empty = [band(version(5, 8, 0), Some(version(5, 8, 0)), "_a")];
+        assert!(check_version_matrix(&empty).is_err());
+        let missing_binary = vec![band(version(4, 14, 0), None, "_not_installed")];
+        assert!(EbpfMode::with_version_matrix(Control::new(), missing_binary).is_err());
+    }
+
+    #[test]
+    fn kernel_version_falls_back_to_uname() {
+        let procfs_failed = || Err(anyhow!("osrelease: permission denied"));
+        let version = EbpfMode::detect_kernel_version_with(procfs_failed, || {
+            Ok(String::from("5.10.0-21-amd64"))
+        })
+        .unwrap();
+        assert_eq!(version, procfs::sys::kernel::Version::new(5, 10, 0));
+        let from_procfs = EbpfMode::detect_kernel_version_with(
+            || Ok(procfs::sys::kernel::Version::new(4, 19, 0)),
+            || panic!("uname called"),
+        )
+        .unwrap();
+        assert_eq!(from_procfs, procfs::sys::kernel::Version::new(4, 19, 0));
+        let e = EbpfMode::detect_kernel_version_with(procfs_failed, || Err(anyhow!("EFAULT")))
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("permission denied") && e.contains("EFAULT"), "{}", e);
+        assert!(EbpfMode::detect_kernel_version_with(procfs_failed, || Ok(String::from("linux")))
+            .is_err());
+        assert!(!uname_release().unwrap().is_empty());
+    }
+
+    #[test]
+    fn ebpf_features_follow_kernel_version() {
+        let mut ebpf_mode = EbpfMode::new(Control::new()).unwrap();
+        ebpf_mode.kernel_version = procfs::sys::kernel::Version::new(5, 4, 0);
+        let features = ebpf_mode.supported_features();
+        assert!(features.http_capture && features.http_headers);
+        assert!(!features.ring_buffer);
+        assert!(!features.detach);
+        let process_mode = ProcessMode::new(String::from("info"), Control::new());
+        assert!(process_mode.supported_features().ping);
+        assert!(!process_mode.supported_features().ring_buffer);
+    }
+
+    #[test]
+    fn ebpf_features_follow_btf() {
+        let mut ebpf_mode = EbpfMode::new(Control::new()).unwrap();
+        ebpf_mode.kernel_version = procfs::sys::kernel::Version::new(5, 10, 0);
+        let btf = std::env::temp_dir().join(format!("librasp-btf-{}", std::process::id()));
+        std::fs::write(&btf, b"").unwrap();
+        ebpf_mode.btf_path = btf.clone();
+        assert!(ebpf_mode.btf_available());
+        let features = ebpf_mode.supported_features();
+        assert!(features.btf && features.ring_buffer);
+        remove_file(&btf).unwrap();
+        assert!(!ebpf_mode.btf_available());
+        let features = ebpf_mode.supported_features();
+        assert!(!features.btf && !features.ring_buffer);
+        assert!(features.http_headers);
+    }
+
+    #[test]
+    fn start_server_runs_the_override() {
+        let daemon =
+            std::env::temp_dir().join(format!("librasp-ebpf-daemon-{}", std::process::id()));
+        std::fs::write(
+            &daemon,
+            "#!/bin/sh\nwhile read pid; do echo \"$pid:succeed\"; done\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&daemon, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        assert!(EbpfMode::with_bin_path(Control::new(), String::from("/nonexistent")).is_err());
+        let mut ebpf_mode =
+            EbpfMode::with_bin_path(Control::new(), daemon.display().to_string()).unwrap();
+        // no band covers it, the override skips the version suffix
+        ebpf_mode.kernel_version = procfs::sys::kernel::Version::new(2, 6, 0);
+        assert_eq!(ebpf_mode.daemon_bin_path().unwrap(), daemon.display().to_string());
+        ebpf_mode.start_server().unwrap();
+        assert!(ebpf_mode.attach(1234).unwrap());
+        EbpfMode::kill_server(ebpf_mode.server_pid.unwrap(), Duration::ZERO);
+        remove_file(&daemon).unwrap();
+    }
+
+    #[test]
+    fn start_server_waits_for_ready() {
+        let daemon =
+            std::env::temp_dir().join(format!("librasp-ebpf-ready-{}", std::process::id()));
+        std::fs::write(
+            &daemon,
+            "#!/bin/sh\nsleep 0.2\necho loading\necho ready\n\
+             while read pid; do echo \"$pid:succeed\"; done\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&daemon, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let mut ebpf_mode =
+            EbpfMode::with_bin_path(Control::new(), daemon.display().to_string()).unwrap();
+        for band in ebpf_mode.version_matrix.iter_mut() {
+            band.features.ready_handshake = true;
+        }
+        let start = Instant::now();
+        ebpf_mode.start_server().unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < EBPF_READY_SLEEP);
+        assert!(ebpf_mode.attach(1234).unwrap());
+        EbpfMode::kill_server(ebpf_mode.server_pid.unwrap(), Duration::ZERO);
+
+        // a daemon that never gets ready is not left behind
+        std::fs::write(&daemon, "#!/bin/sh\nexec sleep 30\n").unwrap();
+        ebpf_mode.ready_timeout = Duration::from_millis(200);
+        ebpf_mode.kill_grace = Duration::ZERO;
+        assert!(ebpf_mode.start_server().is_err());
+        assert_eq!(ebpf_mode.server_pid(), None);
+        remove_file(&daemon).unwrap();
+    }
+
+    #[test]
+    fn missing_btf_steps_down_from_ring_buffer() {
+        let mut ebpf_mode = EbpfMode::new(Control::new()).unwrap();
+        ebpf_mode.kernel_version = procfs::sys::kernel::Version::new(6, 1, 0);
+        let btf = std::env::temp_dir().join(format!("librasp-btf-band-{}", std::process::id()));
+        std::fs::write(&btf, b"").unwrap();
+        ebpf_mode.btf_path = btf.clone();
+        let selection = ebpf_mode.select_band().unwrap();
+        assert_eq!(selection.band.suffix, "_6.0");
+        assert_eq!(selection.stepped_down_from, None);
+        remove_file(&btf).unwrap();
+        let selection = ebpf_mode.select_band().unwrap();
+        assert_eq!(selection.band.suffix, "_5.2");
+        assert_eq!(selection.stepped_down_from.as_deref(), Some("_6.0"));
+        assert!(selection.reason.unwrap().contains("btf"));
+        assert_eq!(ebpf_mode.switch_bpf_main_process().unwrap(), "_5.2");
+        // bands without ring buffer never need it
+        ebpf_mode.kernel_version = procfs::sys::kernel::Version::new(5, 4, 0);
+        assert_eq!(ebpf_mode.select_band().unwrap().stepped_down_from, None);
+        // nothing to step down to
+        ebpf_mode.kernel_version = procfs::sys::kernel::Version::new(5, 10, 0);
+        ebpf_mode.version_matrix.retain(|band| band.features.ring_buffer);
+        assert!(ebpf_mode.select_band().is_err());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn attach_async_leaves_the_runtime_running() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let mut ebpf_mode = mock_ebpf_mode(
+            "while read pid; do echo \"4321:failed\"; sleep 0.3; echo \"$pid:succeed\"; done",
+        );
+        // the only runtime thread is not blocked while the daemon takes its time
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = ticks.clone();
+        runtime.spawn(async move {
+            for _ in 0..10 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                ticker.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        assert!(runtime.block_on(ebpf_mode.attach_async(1234)).unwrap());
+        assert_eq!(ticks.load(Ordering::Relaxed), 10);
+        assert_eq!(ebpf_mode.attached_pids(), vec![1234]);
+        assert_eq!(
+            ebpf_mode.drain_unsolicited(),
+            vec![(4321, AttachOutcome::Failed)]
+        );
+        ebpf_mode.ctrl.stop().unwrap();
+
+        let mut wedged = mock_ebpf_mode("exec sleep 30");
+        wedged.max_total_wait = Duration::from_millis(200);
+        wedged.resume_timed_out = true;
+        let e = runtime.block_on(wedged.attach_async(1234)).unwrap_err();
+        match e.downcast_ref::<CommError>() {
+            Some(CommError::AttachTimeout { pid, diagnostics }) => {
+                assert_eq!(*pid, 1234);
+                assert!(diagnostics.elapsed >= Duration::from_millis(200));
+            }
+            _ => panic!("unexpected error: {}", e),
+        }
+        assert!(wedged.ctrl.check());
+        EbpfMode::kill_server(wedged.server_pid.unwrap(), Duration::ZERO);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn attach_async_restarts_off_the_runtime_thread() {
+        let daemon =
+            std::env::temp_dir().join(format!("librasp-ebpf-async-{}", std::process::id()));
+        std::fs::write(
+            &daemon,
+            "#!/bin/sh\nsleep 0.3\necho ready\nwhile read pid; do echo \"$pid:succeed\"; done\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&daemon, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let mut ebpf_mode =
+            EbpfMode::with_bin_path(Control::new(), daemon.display().to_string()).unwrap();
+        for band in ebpf_mode.version_matrix.iter_mut() {
+            band.features.ready_handshake = true;
+        }
+        ebpf_mode.restart_policy = Some(RestartPolicy::default());
+        ebpf_mode.start_server().unwrap();
+        assert!(ebpf_mode.attach(1234).unwrap());
+        let first = ebpf_mode.server_pid.unwrap();
+        EbpfMode::kill_server(first, Duration::ZERO);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !EbpfMode::server_exited(first) {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(20));
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        // the new daemon takes 300ms to get ready, the runtime keeps ticking meanwhile
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = ticks.clone();
+        runtime.spawn(async move {
+            for _ in 0..10 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                ticker.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        assert!(runtime.block_on(ebpf_mode.attach_async(1235)).unwrap());
+        assert_eq!(ticks.load(Ordering::Relaxed), 10);
+        assert_ne!(ebpf_mode.server_pid, Some(first));
+        let mut attached = ebpf_mode.attached_pids();
+        attached.sort_unstable();
+        assert_eq!(attached, vec![1234, 1235]);
+        EbpfMode::kill_server(ebpf_mode.server_pid.unwrap(), Duration::ZERO);
+        remove_file(&daemon).unwrap();
+    }
+
+    #[test]
+    fn attach_many_writes_every_pid_at_once() {
+        // answers only once every pid is read, in reverse order
+        let mut ebpf_mode = mock_ebpf_mode(
+            "read a; read b; read c; \
+             echo \"$c:succeed\"; echo \"$b:failed\"; echo '4321:failed'; echo \"$a:succeed\"; \
+             exec sleep 30",
+        );
+        let attached = ebpf_mode.attach_many(&[1001, 1002, 1003, 1001]).unwrap();
+        assert_eq!(
+            attached,
+            HashMap::from([(1001, true), (1002, false), (1003, true)])
+        );
+        assert_eq!(ebpf_mode.attached_pids(), vec![1001, 1003]);
+        assert_eq!(
+            ebpf_mode.drain_unsolicited(),
+            vec![(4321, AttachOutcome::Failed)]
+        );
+        // already attached pids are not written again
+        ebpf_mode.max_total_wait = Duration::from_millis(200);
+        ebpf_mode.resume_timed_out = true;
+        let attached = ebpf_mode.attach_many(&[1001, 1004]).unwrap();
+        assert_eq!(attached, HashMap::from([(1001, true), (1004, false)]));
+        assert!(ebpf_mode.ctrl.check());
+        // a timeout not resumed stops `ctrl`, as for `attach`
+        ebpf_mode.resume_timed_out = false;
+        let attached = ebpf_mode.attach_many(&[1005, 1006]).unwrap();
+        assert_eq!(attached, HashMap::from([(1005, false), (1006, false)]));
+        assert!(!ebpf_mode.ctrl.check());
+        ebpf_mode.stop().unwrap();
+    }
+
+    #[test]
+    fn unsolicited_responses_are_queued() {
+        let mut ebpf_mode = mock_ebpf_mode(
+            "while read pid; do echo \"4321:failed\"; echo \"$pid:succeed\"; done",
+        );
+        assert!(ebpf_mode.attach(1234).unwrap());
+        assert_eq!(
+            ebpf_mode.drain_unsolicited(),
+            vec![(4321, AttachOutcome::Failed)]
+        );
+        assert!(ebpf_mode.drain_unsolicited().is_empty());
+        let outcomes = ebpf_mode
+            .attach_batch(&[1001, 1002], Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(ebpf_mode.drain_unsolicited().len(), 2);
+    }
+
+    #[test]
+    fn responses_for_unrequested_pids_are_not_trusted() {
+        // 1002 is answered before it is written, 1003 was never written at all
+        let mut ebpf_mode = mock_ebpf_mode(
+            "read pid; echo \"1002:succeed\"; echo \"1003:succeed\"; sleep 0.5; \
+             echo \"$pid:succeed\"; exec sleep 5",
+        );
+        let outcomes = ebpf_mode
+            .attach_batch(&[1001, 1002, 1003], Duration::from_millis(300))
+            .unwrap();
+        assert_eq!(outcomes[&1001], AttachOutcome::TimedOut);
+        assert!(outcomes.values().all(|o| *o == AttachOutcome::TimedOut));
+        assert!(!ebpf_mode.late_attaches.is_attached(1002));
+        assert!(!ebpf_mode.late_attaches.is_attached(1003));
+        let unsolicited: Vec<i32> = ebpf_mode
+            .drain_unsolicited()
+            .into_iter()
+            .map(|(pid, _)| pid)
+            .collect();
+        assert!(unsolicited.contains(&1002) && unsolicited.contains(&1003));
+        ebpf_mode.ctrl.stop().unwrap();
+    }
+
+    #[test]
+    fn daemon_runs_as_configured_user() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let mut ebpf_mode = EbpfMode::new(Control::new()).unwrap();
+        ebpf_mode.run_as = Some((65534, 65534));
+        ebpf_mode
+            .spawn_server(Command::new("sh").args(&["-c", "read pid; echo \"$(id -u):succeed\""]))
+            .unwrap();
+        assert!(ebpf_mode.attach(65534).unwrap());
+    }
+
+    #[test]
+    fn report_buffer_resize_keeps_records() {
+        let (report_sender, report_receiver) = bounded(1);
+        let reports = ReportBuffer::new(4);
+        reports.forward_to(report_sender).unwrap();
+        let producer = reports.clone();
+        let handle = thread::spawn(move || {
+            for seq in 0..200 {
+                let mut record = plugins::Record::new();
+                record
+                    .mut_data()
+                    .mut_fields()
+                    .insert("seq".to_string(), seq.to_string());
+                producer.send(record).unwrap();
+            }
+        });
+        let consumer = thread::spawn(move || {
+            (0..200)
+                .map(|_| {
+                    let record = report_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+                    record.get_data().get_fields()["seq"].parse::<i32>().unwrap()
+                })
+                .collect::<Vec<i32>>()
+        });
+        for capacity in [64, 8, 128, 2, 32].iter().cycle().take(20) {
+            // shrinking below what is buffered is refused, not lossy
+            let _ = reports.resize(*capacity);
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.join().unwrap();
+        let received = consumer.join().unwrap();
+        assert_eq!(received, (0..200).collect::<Vec<i32>>());
+        assert!(reports.resize(0).is_err());
+    }
+
+    #[test]
+    fn rotate_respects_min_interval() {
+        let script = "while read pid; do echo \"$pid:succeed\"; done";
+        let start = |ebpf_mode: &mut EbpfMode| {
+            ebpf_mode.spawn_server(Command::new("sh").args(&["-c", script]))
+        };
+        let mut ebpf_mode = mock_ebpf_mode(script);
+        let first_pid = ebpf_mode.server_pid;
+        ebpf_mode.rotate_with(false, start).unwrap();
+        assert_ne!(ebpf_mode.server_pid, first_pid);
+        assert!(ebpf_mode.attach(1234).unwrap());
+        let e = ebpf_mode.rotate_with(false, start).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::RotateTooSoon { .. })
+        ));
+        assert!(ebpf_mode.attach(1235).unwrap());
+        ebpf_mode.rotate_with(true, start).unwrap();
+        assert!(ebpf_mode.attach(1236).unwrap());
+        assert!(ebpf_mode.rotate_with(false, start).is_err());
+        assert_eq!(ebpf_mode.reset_protections(), vec!["rotate cooldown"]);
+        assert!(ebpf_mode.reset_protections().is_empty());
+        // a daemon out of restarts gets a new budget
+        ebpf_mode.restarts = 5;
+        ebpf_mode.next_restart = Some(Instant::now() + Duration::from_secs(60));
+        ebpf_mode.restart_given_up = true;
+        assert_eq!(ebpf_mode.reset_protections(), vec!["crash-loop guard"]);
+        assert_eq!(ebpf_mode.restarts, 0);
+        assert!(ebpf_mode.next_restart.is_none() && !ebpf_mode.restart_given_up);
+        assert!(ebpf_mode.reset_protections().is_empty());
+        ebpf_mode.rotate_with(false, start).unwrap();
+        assert!(ebpf_mode.attach(1237).unwrap());
+    }
+
+    #[test]
+    fn container_id_from_cgroup() {
+        let id = "3f1b0a4c5d6e7f8091a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d5e6f708";
+        let docker = format!("12:pids:/docker/{}\n0::/docker/{}\n", id, id);
+        assert_eq!(parse_cgroup_container_id(&docker), Some(id.to_string()));
+        let containerd = format!(
+            "0::/kubepods.slice/kubepods-pod1.slice/cri-containerd-{}.scope\n",
+            id
+        );
+        assert_eq!(parse_cgroup_container_id(&containerd), Some(id.to_string()));
+        let systemd = format!("0::/system.slice/docker-{}.scope\n", id);
+        assert_eq!(parse_cgroup_container_id(&systemd), Some(id.to_string()));
+        assert_eq!(parse_cgroup_container_id("0::/user.slice/session-1.scope\n"), None);
+    }
+
+    #[test]
+    fn events_carry_container_id() {
+        let (sender, receiver) = bounded(10);
+        let mut process_mode = ProcessMode::new(String::from("info"), Control::new());
+        process_mode.events = Some(sender);
+        process_mode.container_id_resolver =
+            Some(Arc::new(|_: &str, pid| Some(format!("container-{}", pid))));
+        assert!(process_mode
+            .stop_comm(1234, &String::from("mnt:[4026531840]"))
+            .is_err());
+        assert!(matches!(
+            receiver.try_recv().unwrap(),
+            CommEvent::OperationStarted { container_id: Some(id), .. } if id == "container-1234"
+        ));
+    }
+
+    #[test]
+    fn dead_server_detected() {
+        let mut process_mode = ProcessMode::new(String::from("info"), Control::new());
+        process_mode.stop_grace = Duration::ZERO;
+        let add_server = |process_mode: &mut ProcessMode, mnt_namespace: &str| {
+            let (record_sender, _) = bounded(1);
+            let comm_pair = bounded(1);
+            process_mode.mnt_namesapce_server_map.insert(
+                String::from(mnt_namespace),
+                RASPServerProcess::new(
+                    1234,
+                    record_sender,
+                    comm_pair.1.clone(),
+                    String::from("info"),
+                    HashMap::new(),
+                    libraspserver::utils::Control::new(),
+                )
+                .unwrap(),
+            );
+            process_mode
+                .mnt_namespace_comm_pair
+                .insert(String::from(mnt_namespace), comm_pair);
+        };
+        // never spawned, as good as dead
+        add_server(&mut process_mode, "mnt:[1]");
+        let mnt_namespace = String::from("mnt:[1]");
+        assert!(!process_mode.server_alive(&mnt_namespace));
+        assert!(process_mode
+            .send_message_to_probe(1234, &mnt_namespace, &String::from("{}"))
+            .is_err());
+        assert!(!process_mode.mnt_namesapce_server_map.contains_key(&mnt_namespace));
+        assert!(!process_mode.mnt_namespace_comm_pair.contains_key(&mnt_namespace));
+        // gone for good, later messages are refused too
+        assert!(process_mode
+            .send_message_to_probe(1234, &mnt_namespace, &String::from("{}"))
+            .unwrap_err()
+            .to_string()
+            .contains("no comm pair"));
+
+        add_server(&mut process_mode, "mnt:[1]");
+        add_server(&mut process_mode, "mnt:[2]");
+        let mut respawned = 0;
+        let restarted = process_mode.restart_dead_servers_with(|_| {
+            respawned += 1;
+            if respawned == 1 {
+                Ok(())
+            } else {
+                Err(anyhow!("spawn rasp server failed"))
+            }
+        });
+        assert_eq!(restarted, vec![String::from("mnt:[1]")]);
+        assert_eq!(process_mode.recycle_count, 1);
+        assert!(process_mode
+            .mnt_namesapce_server_map
+            .contains_key(&String::from("mnt:[1]")));
+        assert!(!process_mode
+            .mnt_namesapce_server_map
+            .contains_key(&String::from("mnt:[2]")));
+    }
+
+    #[test]
+    fn probe_messages_refused_when_backed_up() {
+        let mut process_mode = ProcessMode::new(String::from("info"), Control::new());
+        let mnt_namespace = String::from("mnt:[1]");
+        let comm_pair = bounded(1);
+        process_mode
+            .mnt_namespace_comm_pair
+            .insert(mnt_namespace.clone(), comm_pair.clone());
+        let message = String::from("{}");
+        process_mode
+            .send_message_to_probe(1234, &mnt_namespace, &message)
+            .unwrap();
+        assert_eq!(process_mode.queued_messages(&mnt_namespace), Some(1));
+        let err = process_mode
+            .send_message_to_probe(1234, &mnt_namespace, &message)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommError>(),
+            Some(CommError::Backpressure {
+                queued: 1,
+                capacity: 1
+            })
+        ));
+        // room again once the server takes one
+        comm_pair.1.try_recv().unwrap();
+        process_mode
+            .send_message_to_probe(1234, &mnt_namespace, &message)
+            .unwrap();
+        assert_eq!(process_mode.queued_messages(&String::from("mnt:[2]")), None);
+    }
+
+    #[test]
+    fn broadcast_reports_full_channels() {
+        let mut process_mode = ProcessMode::new(String::from("info"), Control::new());
+        let responsive = bounded(10);
+        let full = bounded(1);
+        full.0.send(String::from("pending")).unwrap();
+        let (closed_sender, _) = bounded::<String>(1);
+        let (_, unused_receiver) = bounded::<String>(1);
+        process_mode
+            .mnt_namespace_comm_pair
+            .insert(String::from("mnt:[1]"), responsive.clone());
+        process_mode
+            .mnt_namespace_comm_pair
+            .insert(String::from("mnt:[2]"), full);
+        process_mode
+            .mnt_namespace_comm_pair
+            .insert(String::from("mnt:[3]"), (closed_sender, unused_receiver));
+        let seen = Mutex::new(Vec::new());
+        let progress = |mnt_namespace: &String, status| {
+            seen.lock().unwrap().push((mnt_namespace.clone(), status));
+        };
+        let start = Instant::now();
+        let report = process_mode.broadcast_to_probes(
+            &String::from("{}"),
+            Duration::from_millis(100),
+            Some(&progress),
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(report.succeeded, vec![String::from("mnt:[1]")]);
+        assert_eq!(report.timed_out, vec![String::from("mnt:[2]")]);
+        assert_eq!(report.failed, vec![String::from("mnt:[3]")]);
+        assert_eq!(seen.lock().unwrap().len(), 3);
+        assert_eq!(responsive.1.try_recv().unwrap(), "{}");
+    }
+
+    #[test]
+    fn daemon_cpu_affinity() {
+        assert_eq!(parse_cpu_list("0-3,6\n").unwrap(), vec![0, 1, 2, 3, 6]);
+        assert_eq!(parse_cpu_list("0").unwrap(), vec![0]);
+        assert!(check_cpu_affinity(&[]).is_err());
+        assert!(check_cpu_affinity(&[100000]).is_err());
+        let mut ebpf_mode = EbpfMode::new(Control::new()).unwrap();
+        ebpf_mode.cpu_affinity = Some(vec![100000]);
+        assert!(ebpf_mode
+            .spawn_server(Command::new("sh").args(&["-c", "exit 0"]))
+            .is_err());
+        let cpu = online_cpus().unwrap()[0];
+        ebpf_mode.cpu_affinity = Some(vec![cpu]);
+        let mut command = Command::new("sh");
+        command.env("CPU", cpu.to_string()).args(&[
+            "-c",
+            "read pid; grep -q \"Cpus_allowed_list:\\s*$CPU$\" /proc/self/status \
+             && echo \"$pid:succeed\" || echo \"$pid:failed\"",
+        ]);
+        ebpf_mode.spawn_server(&mut command).unwrap();
+        assert!(ebpf_mode.attach(1234).unwrap());
+    }
+
+    #[test]
+    fn late_attach_is_reconciled() {
+        let (sender, receiver) = bounded(10);
+        let mut ebpf_mode = mock_ebpf_mode(
+            "read pid; sleep 0.3; echo \"$pid:succeed:os_exec\"; \
+             read pid; echo \"$pid:succeed\"; sleep 5",
+        );
+        ebpf_mode.events = Some(sender);
+        ebpf_mode.resume_timed_out = true;
+        let e = ebpf_mode
+            .attach_until(1234, Instant::now() + Duration::from_millis(100))
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::AttachTimeout { pid: 1234, .. })
+        ));
+        // a timeout in this mode keeps the world running
+        assert!(ebpf_mode.ctrl.check());
+        assert!(ebpf_mode.late_attaches.is_pending(1234));
+        // reconciled in the background, before anyone reads again
+        assert!(matches!(
+            receiver.recv_timeout(Duration::from_secs(1)).unwrap(),
+            CommEvent::LateAttachSucceeded { pid: 1234, hooks } if hooks == vec!["os_exec"]
+        ));
+        assert!(ebpf_mode.late_attaches.is_attached(1234));
+        assert!(!ebpf_mode.late_attaches.is_pending(1234));
+        // the late answer is not read as the answer for 1235
+        assert!(ebpf_mode.attach(1235).unwrap());
+        assert!(ebpf_mode.drain_unsolicited().is_empty());
+    }
+
+    #[test]
+    fn stdout_reader_is_joined() {
+        let mut ebpf_mode = mock_ebpf_mode("exec sleep 30");
+        // still reading from a live daemon
+        assert!(!ebpf_mode.stop_stdout_reader(Duration::from_millis(100)));
+        EbpfMode::kill_server(ebpf_mode.server_pid.unwrap(), Duration::ZERO);
+        let mut ebpf_mode = mock_ebpf_mode("exec sleep 30");
+        EbpfMode::kill_server(ebpf_mode.server_pid.unwrap(), Duration::ZERO);
+        assert!(ebpf_mode.stop_stdout_reader(Duration::from_secs(1)));
+        assert!(ebpf_mode.stdout_reader.is_none());
+    }
+
+    #[test]
+    fn link_in_namespace_fd() {
+        let pid = std::process::id() as i32;
+        let mnt_namespace = open_mnt_namespace(pid).unwrap();
+        assert_eq!(
+            mnt_namespace_of(&mnt_namespace).unwrap(),
+            std::fs::read_link("/proc/self/ns/mnt")
+                .unwrap()
+                .display()
+                .to_string()
+        );
+        let link = format!("/tmp/rasp-link-test-{}/nested/rasp", pid);
+        let key = LinkKey {
+            pid,
+            mnt_namespace: mnt_namespace_of(&mnt_namespace).unwrap(),
+            link: link.clone(),
+        };
+        let threads = LinkThreads::default();
+        symlink_in_namespace(&mnt_namespace, "/tmp", &link, LINK_TIMEOUT, &threads, key).unwrap();
+        assert_eq!(std::fs::read_link(&link).unwrap(), std::path::Path::new("/tmp"));
+        let _ = std::fs::remove_dir_all(format!("/tmp/rasp-link-test-{}", pid));
+    }
+
+    fn link_key(link: &str) -> LinkKey {
+        LinkKey {
+            pid: 1,
+            mnt_namespace: String::from("mnt:[1]"),
+            link: link.to_string(),
         }
-        return Err(anyhow!(
-            "can not found any proper format in response: {}",
-            response
+    }
+
+    #[test]
+    fn stuck_link_times_out() {
+        let threads = LinkThreads::default();
+        let err = link_within(
+            "rasp_link",
+            Duration::from_millis(20),
+            "/var/run/rasp",
+            &threads,
+            link_key("/var/run/rasp"),
+            || {
+                thread::sleep(Duration::from_millis(500));
+                Ok(())
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommError>(),
+            Some(CommError::LinkTimeout { .. })
+        ));
+        assert!(err.to_string().contains("timed out"));
+        // the error of a link returning in time is passed on as is
+        let err = link_within(
+            "rasp_link",
+            Duration::from_secs(1),
+            "/var/run/other",
+            &threads,
+            link_key("/var/run/other"),
+            || Err(std::io::Error::from(ErrorKind::PermissionDenied).into()),
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<CommError>().is_none());
+        assert!(!threads.stuck("mnt:[1]", "/var/run/other"));
+        // the first one is stuck until its thread returns, and made the link after all
+        assert!(threads.stuck("mnt:[1]", "/var/run/rasp"));
+        thread::sleep(Duration::from_millis(800));
+        assert!(!threads.stuck("mnt:[1]", "/var/run/rasp"));
+        assert_eq!(
+            threads.take_late(),
+            vec![(1, String::from("mnt:[1]"), String::from("/var/run/rasp"))]
+        );
+        assert!(threads.take_late().is_empty());
+    }
+
+    #[test]
+    fn late_link_removed_by_stop_comm() {
+        let dir = std::env::temp_dir().join(format!("rasp-late-link-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pid = std::process::id() as i32;
+        let mnt_namespace = read_link("/proc/self/ns/mnt")
+            .unwrap()
+            .display()
+            .to_string();
+        let (record_sender, _record_receiver) = bounded(10);
+        let mut thread_mode = ThreadMode::new(
+            String::from("info"),
+            Control::new(),
+            record_sender,
+            dir.join("rasp.sock").display().to_string(),
+            vec![dir.join("{pid}.sock").display().to_string()],
+            false,
+        )
+        .unwrap();
+        let nsenter = dir.join("nsenter");
+        // drops `-t <pid> -m` and runs the rest here
+        std::fs::write(&nsenter, "#!/bin/sh\nshift 3\nexec \"$@\"\n").unwrap();
+        std::fs::set_permissions(&nsenter, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        thread_mode.nsenter_bin = nsenter.display().to_string();
+        let link = dir.join(format!("{}.sock", pid)).display().to_string();
+        let key = LinkKey {
+            pid,
+            mnt_namespace: mnt_namespace.clone(),
+            link: link.clone(),
+        };
+        let (original, late) = (thread_mode.bind_path.clone(), link.clone());
+        let threads = thread_mode.link_threads.clone();
+        // the link is held until the test lets it go
+        let (release, released) = bounded::<()>(0);
+        let err = link_within(
+            "rasp_link",
+            Duration::from_millis(20),
+            &link,
+            &threads,
+            key,
+            move || {
+                let _ = released.recv();
+                fs::symlink(original, late).map_err(|e| e.into())
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommError>(),
+            Some(CommError::LinkTimeout { .. })
         ));
+        // no second thread while the first is stuck
+        let (report_sender, _) = bounded(1);
+        let err = thread_mode
+            .start_comm(pid, &mnt_namespace, report_sender, HashMap::new())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommError>(),
+            Some(CommError::LinkTimeout { .. })
+        ));
+        release.send(()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while threads.stuck(&mnt_namespace, &link) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(symlink_metadata(&link).unwrap().file_type().is_symlink());
+        thread_mode.stop_comm(pid, &mnt_namespace).unwrap();
+        assert!(symlink_metadata(&link).is_err());
+        assert!(thread_mode.links.is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
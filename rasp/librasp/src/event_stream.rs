@@ -0,0 +1,187 @@
+use std::io::{ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result as AnyhowResult;
+use crossbeam::channel::{bounded, RecvTimeoutError, Sender, TrySendError};
+use log::*;
+
+use crate::comm::{Control, LogLevel, LogSink};
+
+pub const DEFAULT_CLIENT_BUFFER: usize = 256;
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+/// one comm log message, streamed as a JSON line
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommLogEvent {
+    /// milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub level: LogLevel,
+    pub message: String,
+}
+
+impl CommLogEvent {
+    pub fn new(level: LogLevel, message: &str) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+        Self {
+            timestamp,
+            level,
+            message: message.to_string(),
+        }
+    }
+    pub fn to_json_line(&self) -> String {
+        let event = serde_json::json!({
+            "timestamp": self.timestamp,
+            "level": format!("{:?}", self.level).to_lowercase(),
+            "message": self.message,
+        });
+        format!("{}\n", event)
+    }
+}
+
+/// streams comm log events to every client connected on a unix socket, for watching
+/// comm activity live with `socat - UNIX-CONNECT:<path>`. each client has its own
+/// bounded buffer, events that do not fit are dropped for that client only
+#[derive(Clone)]
+pub struct EventStream {
+    pub path: PathBuf,
+    pub client_buffer: usize,
+    clients: Arc<Mutex<Vec<Sender<String>>>>,
+}
+
+impl EventStream {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            client_buffer: DEFAULT_CLIENT_BUFFER,
+            clients: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+    /// `LogSink` publishing into the stream, set it as a mode's `log_sink`
+    pub fn sink(&self) -> LogSink {
+        let stream = self.clone();
+        Arc::new(move |level, message| stream.publish(&CommLogEvent::new(level, message)))
+    }
+    pub fn publish(&self, event: &CommLogEvent) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return;
+        }
+        let line = event.to_json_line();
+        clients.retain(|client| match client.try_send(line.clone()) {
+            Ok(()) | Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+    /// clients currently connected, a disconnected one is noticed on the next publish
+    pub fn clients(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+    /// bind `path` and accept clients in a thread until `ctrl` is stopped. a stale socket
+    /// left at `path` is replaced, the socket is removed on stop
+    pub fn start(&self, ctrl: Control) -> AnyhowResult<JoinHandle<()>> {
+        let _ = std::fs::remove_file(&self.path);
+        let listener = UnixListener::bind(&self.path)?;
+        listener.set_nonblocking(true)?;
+        info!("event stream listening on: {}", self.path.display());
+        let stream = self.clone();
+        let handle = thread::Builder::new()
+            .name(String::from("event_stream"))
+            .spawn(move || stream.serve(listener, ctrl))?;
+        Ok(handle)
+    }
+    fn serve(&self, listener: UnixListener, mut ctrl: Control) {
+        while ctrl.check() {
+            match listener.accept() {
+                Ok((client, _)) => {
+                    if let Err(e) = self.add_client(client, ctrl.clone()) {
+                        debug!("event stream client setup failed: {}", e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+                Err(e) => {
+                    warn!("event stream accept failed: {}", e);
+                    thread::sleep(ACCEPT_POLL);
+                }
+            }
+        }
+        self.clients.lock().unwrap().clear();
+        let _ = std::fs::remove_file(&self.path);
+        info!("event stream stopped");
+    }
+    fn add_client(&self, mut client: UnixStream, mut ctrl: Control) -> AnyhowResult<()> {
+        client.set_nonblocking(false)?;
+        client.set_write_timeout(Some(Duration::from_secs(1)))?;
+        let (sender, receiver) = bounded::<String>(self.client_buffer);
+        // the writer exits on stop or on a write error, which drops the receiver and
+        // lets `publish` forget the client
+        thread::Builder::new()
+            .name(String::from("event_stream_client"))
+            .spawn(move || {
+                while ctrl.check() {
+                    match receiver.recv_timeout(ACCEPT_POLL) {
+                        Ok(line) => {
+                            if client.write_all(line.as_bytes()).is_err() {
+                                return;
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            })?;
+        self.clients.lock().unwrap().push(sender);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::time::Instant;
+
+    fn wait_for_clients(stream: &EventStream, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while stream.clients() != count {
+            assert!(Instant::now() < deadline, "clients: {}", stream.clients());
+            // disconnected clients are only forgotten on publish
+            stream.publish(&CommLogEvent::new(LogLevel::Trace, "poll"));
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn streams_log_events_as_json_lines() {
+        let path = std::env::temp_dir().join(format!("librasp-events-{}.sock", std::process::id()));
+        let stream = EventStream::new(path.clone());
+        let mut ctrl = Control::new();
+        let handle = stream.start(ctrl.clone()).unwrap();
+        let client = UnixStream::connect(&path).unwrap();
+        wait_for_clients(&stream, 1);
+        (stream.sink())(LogLevel::Info, "attach 1234");
+        let mut reader = BufReader::new(client);
+        let event = loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+            if event["level"] != "trace" {
+                break event;
+            }
+        };
+        assert_eq!(event["level"], "info");
+        assert_eq!(event["message"], "attach 1234");
+        assert!(event["timestamp"].as_u64().unwrap() > 0);
+        drop(reader);
+        wait_for_clients(&stream, 0);
+        ctrl.stop().unwrap();
+        handle.join().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -1,5 +1,7 @@
 pub mod comm;
 pub mod cpython;
+#[cfg(feature = "event-stream")]
+pub mod event_stream;
 pub mod golang;
 pub mod jvm;
 pub mod manager;
@@ -9,6 +11,8 @@ pub mod process;
 pub mod runtime;
 #[allow(non_snake_case)]
 pub mod settings;
+#[cfg(feature = "status-server")]
+pub mod status_server;
 
 pub mod async_command {
     use std::io::{BufRead, BufReader};
@@ -54,7 +54,7 @@ fn main() -> anyhow::Result<()> {
         ctrl.clone(),
         result_sender.clone(),
         format!("{}/smith_agent.sock", current_dir),
-        Some(String::from("/var/run/smith_agent.sock")),
+        vec![String::from("/var/run/smith_agent.sock")],
         false,
         BPFSelect::FIRST,
     )?;
@@ -1,8 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ffi::OsStr;
 use std::fs;
 use std::path::Path;
 use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result, Result as AnyhowResult};
 use crossbeam::channel::Sender;
@@ -17,7 +19,10 @@ use crate::jvm::{java_attach, java_detach, JVMProbe, JVMProbeState};
 use crate::nodejs::{nodejs_attach, NodeJSProbe};
 use crate::php::{php_attach, PHPProbeState};
 use crate::{
-    comm::{Control, EbpfMode, ProcessMode, RASPComm, ThreadMode, check_need_mount},
+    comm::{
+        cgroup_container_id, check_need_mount, check_nsenter_version, CommError, CommOperation,
+        Control, EbpfMode, KillOutcome, NamespaceStats, ProcessMode, RASPComm, ThreadMode,
+    },
     process::ProcessInfo,
     runtime::{ProbeCopy, ProbeState, ProbeStateInspect, RuntimeInspect},
     settings,
@@ -29,6 +34,129 @@ pub struct RASPManager {
     pub process_comm: Option<ProcessMode>,
     pub ebpf_comm: Option<EbpfMode>,
     pub runtime_dir: bool,
+    /// set by `stop_all`, messages to probes are refused afterwards
+    pub quiesced: bool,
+    pub mnt_namespace_cache: MntNamespaceCache,
+    /// asked before every `attach` and `start_comm`, see `AttachDecision`
+    pub pre_attach: Option<PreAttachHook>,
+    /// pids the hook deferred, refused without asking it again until the instant passes
+    pub deferred_attaches: HashMap<i32, Instant>,
+    /// golang backend pinned per mnt namespace, see `AttachDispatcher::set_namespace_mode`
+    pub namespace_modes: HashMap<String, GolangBackend>,
+    /// comm mode pinned per mnt namespace, see `set_namespace_comm_mode`
+    pub namespace_comm_modes: HashMap<String, CommMode>,
+    /// host wide cap on `attach`, across runtimes and namespaces. unlimited when `None`
+    pub attach_limiter: Option<AttachRateLimiter>,
+}
+
+/// what `pre_attach` knows about the process about to be attached or served
+#[derive(Debug, Clone)]
+pub struct AttachContext {
+    pub operation: CommOperation,
+    pub pid: i32,
+    pub exe_path: Option<String>,
+    pub mnt_namespace: Option<String>,
+    /// runtime name once inspected, e.g. `JVM`
+    pub runtime: Option<String>,
+    pub container_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttachDecision {
+    Proceed,
+    /// fails with `CommError::AttachSkipped`
+    Skip { reason: String },
+    /// fails with `CommError::AttachDeferred` until `retry_after` has passed
+    Defer { retry_after: Duration },
+}
+
+pub type PreAttachHook = Arc<dyn Fn(&AttachContext) -> AttachDecision + Send + Sync>;
+
+/// what `AttachRateLimiter` does with an attach over the rate
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RateLimitPolicy {
+    /// refuse the pid with `CommError::AttachDeferred` until a token is due
+    #[default]
+    Defer,
+    /// refuse with `CommError::AttachSkipped`, the next attach of the pid is tried as usual
+    Drop,
+}
+
+/// token bucket, `rate` attaches a second with bursts of up to `burst`. `rate` must be
+/// positive, leave `RASPManager::attach_limiter` unset for no limit
+pub struct AttachRateLimiter {
+    /// attaches per second, positive and finite, checked by `new`
+    rate: f64,
+    pub burst: u32,
+    pub policy: RateLimitPolicy,
+    tokens: f64,
+    /// set by the first `acquire_at`, the bucket starts full
+    last_refill: Option<Instant>,
+    /// attaches let through in the last second
+    granted: VecDeque<Instant>,
+    pub deferred: u64,
+    pub dropped: u64,
+}
+
+impl AttachRateLimiter {
+    pub fn new(rate: f64, burst: u32, policy: RateLimitPolicy) -> AnyhowResult<Self> {
+        if !(rate > 0.0 && rate.is_finite()) {
+            return Err(anyhow!("attach rate must be positive and finite: {}", rate));
+        }
+        Ok(Self {
+            rate,
+            burst,
+            policy,
+            tokens: burst as f64,
+            last_refill: None,
+            granted: VecDeque::new(),
+            deferred: 0,
+            dropped: 0,
+        })
+    }
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+    /// take a token at `now`, or the time until the next one is due
+    pub fn acquire_at(&mut self, now: Instant) -> std::result::Result<(), Duration> {
+        self.prune_granted(now);
+        if let Some(last_refill) = self.last_refill {
+            let elapsed = now.saturating_duration_since(last_refill);
+            self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst as f64);
+        }
+        self.last_refill = self.last_refill.max(Some(now));
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.granted.push_back(now);
+            return Ok(());
+        }
+        match self.policy {
+            RateLimitPolicy::Defer => self.deferred += 1,
+            RateLimitPolicy::Drop => self.dropped += 1,
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+    /// refill the bucket, the next `burst` attaches go through. false if it was full
+    pub fn reset(&mut self) -> bool {
+        let drained = self.tokens < self.burst as f64;
+        self.tokens = self.burst as f64;
+        self.last_refill = None;
+        drained
+    }
+    /// attaches let through in the second before `now`
+    pub fn current_rate(&mut self, now: Instant) -> usize {
+        self.prune_granted(now);
+        self.granted.len()
+    }
+    /// forget attaches older than a second, `granted` stays bounded by the rate
+    fn prune_granted(&mut self, now: Instant) {
+        while let Some(oldest) = self.granted.front() {
+            if now.saturating_duration_since(*oldest) < Duration::from_secs(1) {
+                break;
+            }
+            self.granted.pop_front();
+        }
+    }
 }
 
 impl RASPManager {
@@ -37,24 +165,64 @@ impl RASPManager {
         &mut self,
         process_info: &ProcessInfo,
         result_sender: Sender<plugins::Record>,
-        _server_log_level: String,
-        _server_ctrl: Control,
+        server_log_level: String,
+        server_ctrl: Control,
     ) -> AnyhowResult<()> {
         debug!("starting comm with probe, target pid: {}", process_info.pid);
-        let mnt_namespace = process_info.get_mnt_ns()?;
+        self.check_pre_attach(CommOperation::StartComm, process_info)?;
+        Self::warn_exe_deleted(process_info);
+        let start_time = procfs::process::Process::new(process_info.pid)
+            .map(|process| process.stat.starttime)
+            .ok();
+        // the start time tells a cached pid from a reused one
+        let cached = match start_time {
+            Some(start_time) => self
+                .mnt_namespace_cache
+                .get(process_info.pid, Some(start_time))
+                .ok(),
+            None => None,
+        };
+        let mnt_namespace = match cached {
+            Some(mnt_namespace) => mnt_namespace,
+            None => process_info.get_mnt_ns()?,
+        };
+        if self
+            .namespace_tracer
+            .evict_if_reused(&mnt_namespace, pid_alive_in)
+        {
+            warn!("mnt namespace reused, dropping stale state: {}", mnt_namespace);
+            if let Some(comm) = self.thread_comm.as_mut() {
+                let _ = comm.stop_comm(process_info.pid, &mnt_namespace);
+            }
+            if let Some(comm) = self.process_comm.as_mut() {
+                let _ = comm.stop_comm(process_info.pid, &mnt_namespace);
+            }
+        }
         let nspid = if let Some(nspid) = ProcessInfo::read_nspid(process_info.pid)? {
             nspid
         } else {
             process_info.pid
         };
-        if let Some(comm) = self.thread_comm.as_mut() {
+        let comm_mode = self.comm_mode(&mnt_namespace);
+        if comm_mode == Some(CommMode::Process) && self.process_comm.is_none() {
+            // pinned in a thread mode agent, process mode comes up on first use
+            info!("process comm mode started for pinned namespace: {}", mnt_namespace);
+            self.process_comm = Some(ProcessMode::new(server_log_level, server_ctrl));
+        }
+        if comm_mode == Some(CommMode::Thread) {
+            let comm = self
+                .thread_comm
+                .as_mut()
+                .ok_or_else(|| anyhow!("thread comm mode not init: {}", mnt_namespace))?;
             comm.start_comm(
                 process_info.pid,
                 &mnt_namespace,
                 result_sender,
                 HashMap::new(),
             )?;
-        } else if let Some(comm) = self.process_comm.as_mut() {
+        } else if let (Some(CommMode::Process), Some(comm)) =
+            (comm_mode, self.process_comm.as_mut())
+        {
             let mut patch_field = HashMap::new();
             let sid = process_info.sid;
             let pid = process_info.pid;
@@ -86,6 +254,7 @@ impl RASPManager {
                     if let Some(runner) = comm.mnt_namesapce_server_map.get_mut(&mnt_namespace) {
                         runner.update_patch_field(patch_field);
                     }
+                    comm.record_attach(process_info.pid, &mnt_namespace);
                     return Ok(());
                 }
             } else {
@@ -99,8 +268,11 @@ impl RASPManager {
         } else {
             return Err(anyhow!("both thread && process comm mode not init"));
         }
-        self.namespace_tracer
-            .add(mnt_namespace.clone(), process_info.pid);
+        self.namespace_tracer.add_with_start_time(
+            mnt_namespace.clone(),
+            process_info.pid,
+            start_time.unwrap_or_default(),
+        );
         self.namespace_tracer.server_state_on(mnt_namespace);
         Ok(())
     }
@@ -119,14 +291,25 @@ impl RASPManager {
         let kill_check = self
             .namespace_tracer
             .server_state_off(&mnt_namespace, process_info.pid);
-        // kill server
+        let comm_mode = self.comm_mode(&mnt_namespace);
         if kill_check {
-            if let Some(comm) = self.thread_comm.as_mut() {
-                comm.stop_comm(process_info.pid, &mnt_namespace)?;
+            // a pin outliving the namespace would apply to whatever reuses its inode
+            self.release_namespace(&mnt_namespace);
+        }
+        match comm_mode {
+            // kill server
+            Some(CommMode::Thread) if kill_check => {
+                if let Some(comm) = self.thread_comm.as_mut() {
+                    comm.stop_comm(process_info.pid, &mnt_namespace)?;
+                }
             }
-            if let Some(comm) = self.process_comm.as_mut() {
-                comm.stop_comm(process_info.pid, &mnt_namespace)?;
+            // counts the pids sharing each server itself, and stops it with the last one
+            Some(CommMode::Process) => {
+                if let Some(comm) = self.process_comm.as_mut() {
+                    comm.stop_comm(process_info.pid, &mnt_namespace)?;
+                }
             }
+            _ => {}
         }
         Ok(())
     }
@@ -196,6 +379,9 @@ impl RASPManager {
         mnt_namespace: &String,
         message: &String,
     ) -> AnyhowResult<()> {
+        if self.quiesced {
+            return Err(anyhow!("rasp manager stopped, drop message to probe: {}", pid));
+        }
         // try to write probe to dir
         let nspid = ProcessInfo::read_nspid(pid)?.ok_or(anyhow!("can not fetch nspid: {}", pid))?;
         debug!("send messages to probe: {} {} {}", pid, nspid, &message);
@@ -251,12 +437,16 @@ impl RASPManager {
                 }
             };
             debug!("sending message: {}", m_string);
-            if let Some(comm) = self.thread_comm.as_mut() {
-                comm.send_message_to_probe(pid, mnt_namespace, &m_string)?;
-            } else if let Some(comm) = self.process_comm.as_mut() {
-                comm.send_message_to_probe(pid, mnt_namespace, &m_string)?;
-            } else {
-                return Err(anyhow!("both thread && process comm mode not init"));
+            match self.comm_mode(mnt_namespace) {
+                Some(CommMode::Thread) => match self.thread_comm.as_mut() {
+                    Some(comm) => comm.send_message_to_probe(pid, mnt_namespace, &m_string)?,
+                    None => return Err(anyhow!("thread comm mode not init: {}", mnt_namespace)),
+                },
+                Some(CommMode::Process) => match self.process_comm.as_mut() {
+                    Some(comm) => comm.send_message_to_probe(pid, mnt_namespace, &m_string)?,
+                    None => return Err(anyhow!("process comm mode not init: {}", mnt_namespace)),
+                },
+                None => return Err(anyhow!("both thread && process comm mode not init")),
             }
         }
 
@@ -283,6 +473,195 @@ pub enum BPFSelect {
     DISABLE,
 }
 
+/// how the agent talks to the probes of a mnt namespace
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommMode {
+    /// `ThreadMode`, a server thread of the agent
+    Thread,
+    /// `ProcessMode`, one rasp_server process per namespace
+    Process,
+}
+
+/// golang instrumentation backends
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GolangBackend {
+    /// uprobes installed by the golang ebpf daemon
+    Ebpf,
+    /// probe injected by pangolin
+    Pangolin,
+}
+
+/// health of an attached pid in the view of one mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProbeHealth {
+    Healthy,
+    Dead,
+    Unknown,
+}
+
+/// single health of a pid, see `AttachDispatcher::probe_health`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    pub health: ProbeHealth,
+    /// mode whose view was taken
+    pub mode: Option<GolangBackend>,
+    /// modes whose view disagrees with `health`, their entry for the pid is stale
+    pub stale: Vec<GolangBackend>,
+}
+
+/// attach with `primary`, then with `fallback_mode` if `primary` failed for good
+pub struct AttachDispatcher {
+    pub primary: GolangBackend,
+    pub fallback_mode: Option<GolangBackend>,
+    /// extra tries of `primary` on transient errors, those never fall back since the
+    /// first attach may still land late
+    pub transient_retries: u32,
+    /// mirrors `EbpfMode::resume_timed_out`: only then is a timed out attach transient,
+    /// otherwise the daemon gave up on it and `fallback_mode` is tried
+    pub resume_timed_out: bool,
+    /// mnt namespace -> the only mode tried for its pids, whatever the selection
+    pub namespace_modes: HashMap<String, GolangBackend>,
+}
+
+impl AttachDispatcher {
+    pub fn new(primary: GolangBackend, fallback_mode: Option<GolangBackend>) -> Self {
+        Self {
+            primary,
+            fallback_mode,
+            transient_retries: 1,
+            resume_timed_out: false,
+            namespace_modes: HashMap::new(),
+        }
+    }
+    /// pin every pid of `mnt_namespace` to `mode`, no fallback is tried for them
+    pub fn set_namespace_mode(&mut self, mnt_namespace: &str, mode: GolangBackend) {
+        self.namespace_modes.insert(mnt_namespace.to_string(), mode);
+    }
+    /// `dispatch` honoring the mode pinned to `mnt_namespace`
+    pub fn dispatch_in<F>(
+        &self,
+        mnt_namespace: &str,
+        pid: i32,
+        attach: F,
+    ) -> AnyhowResult<GolangBackend>
+    where
+        F: FnMut(GolangBackend) -> AnyhowResult<bool>,
+    {
+        match self.namespace_modes.get(mnt_namespace) {
+            Some(mode) => Self {
+                primary: *mode,
+                fallback_mode: None,
+                transient_retries: self.transient_retries,
+                resume_timed_out: self.resume_timed_out,
+                namespace_modes: HashMap::new(),
+            }
+            .dispatch(pid, attach),
+            None => self.dispatch(pid, attach),
+        }
+    }
+    pub fn from_select(bpf: &BPFSelect) -> Self {
+        match bpf {
+            BPFSelect::FORCE => Self::new(GolangBackend::Ebpf, None),
+            BPFSelect::FIRST => Self::new(GolangBackend::Ebpf, Some(GolangBackend::Pangolin)),
+            BPFSelect::SECOND => Self::new(GolangBackend::Pangolin, Some(GolangBackend::Ebpf)),
+            BPFSelect::DISABLE => Self::new(GolangBackend::Pangolin, None),
+        }
+    }
+    /// the mode that attached `pid`, or the error of the last mode tried
+    pub fn dispatch<F>(&self, pid: i32, mut attach: F) -> AnyhowResult<GolangBackend>
+    where
+        F: FnMut(GolangBackend) -> AnyhowResult<bool>,
+    {
+        let mut retries = 0;
+        let primary_error = loop {
+            match attach(self.primary) {
+                Ok(true) => return Ok(self.primary),
+                Ok(false) => break anyhow!("{:?} attach failed: {}", self.primary, pid),
+                Err(e) if self.is_transient(&e) => {
+                    if retries >= self.transient_retries {
+                        return Err(e);
+                    }
+                    retries += 1;
+                    warn!("{:?} attach failed: {}, retrying: {}", self.primary, e, pid);
+                }
+                Err(e) => break e,
+            }
+        };
+        let fallback = match self.fallback_mode {
+            Some(fallback) => fallback,
+            None => return Err(primary_error),
+        };
+        warn!(
+            "{:?} attach failed: {}, trying {:?} attach: {}",
+            self.primary, primary_error, fallback, pid
+        );
+        if attach(fallback)? {
+            Ok(fallback)
+        } else {
+            Err(anyhow!("{:?} attach failed: {}", fallback, pid))
+        }
+    }
+    /// reconcile the health of `pid` seen by each mode into one. on conflict the view of
+    /// `owner`, the mode that attached `pid` last, wins, then `primary` and `fallback_mode`.
+    /// `Unknown` views never conflict
+    pub fn probe_health(
+        &self,
+        pid: i32,
+        owner: Option<GolangBackend>,
+        views: &[(GolangBackend, ProbeHealth)],
+    ) -> HealthReport {
+        let view_of = |mode: GolangBackend| {
+            views
+                .iter()
+                .find(|(m, health)| *m == mode && *health != ProbeHealth::Unknown)
+                .copied()
+        };
+        let authoritative = owner
+            .into_iter()
+            .chain(Some(self.primary))
+            .chain(self.fallback_mode)
+            .find_map(view_of)
+            .or_else(|| views.iter().find(|(_, h)| *h != ProbeHealth::Unknown).copied());
+        let (mode, health) = match authoritative {
+            Some(view) => view,
+            None => {
+                return HealthReport {
+                    health: ProbeHealth::Unknown,
+                    mode: None,
+                    stale: Vec::new(),
+                }
+            }
+        };
+        let mut stale = Vec::new();
+        for (other, other_health) in views {
+            if *other_health != ProbeHealth::Unknown
+                && *other_health != health
+                && !stale.contains(other)
+            {
+                stale.push(*other);
+            }
+        }
+        if !stale.is_empty() {
+            warn!(
+                "conflicting health of {}: {:?} per {:?}, stale in {:?}",
+                pid, health, mode, stale
+            );
+        }
+        HealthReport {
+            health,
+            mode: Some(mode),
+            stale,
+        }
+    }
+    fn is_transient(&self, e: &anyhow::Error) -> bool {
+        self.resume_timed_out
+            && matches!(
+                e.downcast_ref::<CommError>(),
+                Some(CommError::AttachTimeout { .. })
+            )
+    }
+}
+
 impl RASPManager {
     // Inspect
     pub fn inspect(&mut self, process_info: &ProcessInfo) -> Result<()> {
@@ -309,13 +688,105 @@ impl RASPManager {
         process_info.runtime = runtime;
         Ok(true)
     }
+    fn check_pre_attach(
+        &mut self,
+        operation: CommOperation,
+        process_info: &ProcessInfo,
+    ) -> AnyhowResult<()> {
+        let pid = process_info.pid;
+        let now = Instant::now();
+        if let Some(until) = self.deferred_attaches.get(&pid).copied() {
+            if now < until {
+                return Err(CommError::AttachDeferred {
+                    pid,
+                    retry_after: until - now,
+                }
+                .into());
+            }
+            self.deferred_attaches.remove(&pid);
+        }
+        let pre_attach = match self.pre_attach.as_ref() {
+            Some(pre_attach) => pre_attach,
+            None => return Ok(()),
+        };
+        let mnt_namespace = process_info.get_mnt_ns().ok();
+        let context = AttachContext {
+            operation,
+            pid,
+            exe_path: process_info.exe_path.clone(),
+            container_id: cgroup_container_id(
+                mnt_namespace.as_deref().unwrap_or_default(),
+                pid,
+            ),
+            mnt_namespace,
+            runtime: process_info.runtime.as_ref().map(|r| r.name.to_string()),
+        };
+        match pre_attach(&context) {
+            AttachDecision::Proceed => Ok(()),
+            AttachDecision::Skip { reason } => {
+                info!("pre attach hook skipped {}: {}", pid, reason);
+                Err(CommError::AttachSkipped { pid, reason }.into())
+            }
+            AttachDecision::Defer { retry_after } => {
+                info!("pre attach hook deferred {} for {:?}", pid, retry_after);
+                self.deferred_attaches.insert(pid, now + retry_after);
+                Err(CommError::AttachDeferred { pid, retry_after }.into())
+            }
+        }
+    }
+    fn check_attach_rate(&mut self, pid: i32) -> AnyhowResult<()> {
+        let limiter = match self.attach_limiter.as_mut() {
+            Some(limiter) => limiter,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let retry_after = match limiter.acquire_at(now) {
+            Ok(()) => return Ok(()),
+            Err(retry_after) => retry_after,
+        };
+        match limiter.policy {
+            RateLimitPolicy::Defer => {
+                debug!("attach rate limited, deferred {} for {:?}", pid, retry_after);
+                self.deferred_attaches.insert(pid, now + retry_after);
+                Err(CommError::AttachDeferred { pid, retry_after }.into())
+            }
+            RateLimitPolicy::Drop => {
+                debug!("attach rate limited, dropped: {}", pid);
+                Err(CommError::AttachSkipped {
+                    pid,
+                    reason: String::from("attach rate limit"),
+                }
+                .into())
+            }
+        }
+    }
+    /// `(attaches in the last second, deferred, dropped)` of `attach_limiter`
+    pub fn attach_rate_stats(&mut self) -> Option<(usize, u64, u64)> {
+        let limiter = self.attach_limiter.as_mut()?;
+        let rate = limiter.current_rate(Instant::now());
+        Some((rate, limiter.deferred, limiter.dropped))
+    }
+    /// attaching is not refused, the binary on disk just differs from the running one
+    pub fn warn_exe_deleted(process_info: &ProcessInfo) -> bool {
+        if process_info.exe_deleted {
+            warn!(
+                "process {} is running a deleted binary: {}",
+                process_info.pid,
+                process_info.exe_path.as_deref().unwrap_or_default()
+            );
+        }
+        process_info.exe_deleted
+    }
     // Attach
     pub fn attach(&mut self, process_info: &ProcessInfo, bpf: BPFSelect) -> Result<()> {
+        self.check_pre_attach(CommOperation::Attach, process_info)?;
+        self.check_attach_rate(process_info.pid)?;
         if process_info.runtime.is_none() {
             let msg = "attaching to unknow runtime process";
             error!("{}", msg);
             return Err(anyhow!(msg));
         }
+        Self::warn_exe_deleted(process_info);
         let environ = match process_info.environ.clone() {
             Some(e) => e,
             None => return Err(anyhow!("can not fetch envrion {}", process_info.pid)),
@@ -347,7 +818,7 @@ impl RASPManager {
                 }
                 ProbeState::AttachedVersionNotMatch => {
                     let mut diff_ns:bool = false;
-                    match check_need_mount(mnt_namespace) {
+                    match check_need_mount(pid) {
                         Ok(value) => {
                             diff_ns = value;
                             if diff_ns {
@@ -413,6 +884,12 @@ impl RASPManager {
                     Ok(true)
                 }
                 ProbeState::NotAttach => {
+                    let mut dispatcher = AttachDispatcher::from_select(&bpf);
+                    dispatcher.namespace_modes = self.namespace_modes.clone();
+                    dispatcher.resume_timed_out = self
+                        .ebpf_comm
+                        .as_ref()
+                        .map_or(false, |ebpf_comm| ebpf_comm.resume_timed_out);
                     let mut golang_attach = |pid: i32, bpf: bool| -> AnyhowResult<bool> {
                         if bpf {
                             if let Some(bpf_manager) = self.ebpf_comm.as_mut() {
@@ -434,38 +911,14 @@ impl RASPManager {
                             golang_attach(pid)
                         }
                     };
-                    match bpf {
-                        BPFSelect::FORCE => golang_attach(pid, true),
-                        BPFSelect::DISABLE => golang_attach(pid, false),
-                        BPFSelect::FIRST => {
-                            let bpf_result = golang_attach(pid, true);
-                            match bpf_result {
-                                Ok(true) => Ok(true),
-                                Ok(false) => {
-                                    warn!("FIRST BPF attach failed, trying golang attach");
-                                    golang_attach(pid, false)
-                                }
-                                Err(e) => {
-                                    warn!("FIRST BPF attach failed: {}, trying golang attach", e);
-                                    golang_attach(pid, false)
-                                }
-                            }
-                        }
-                        BPFSelect::SECOND => {
-                            let golang_attach_result = golang_attach(pid, false);
-                            match golang_attach_result {
-                                Ok(true) => Ok(true),
-                                Ok(false) => {
-                                    warn!("golang attach failed, trying BPF attach");
-                                    golang_attach(pid, true)
-                                }
-                                Err(e) => {
-                                    warn!("golang attach faild: {}, trying BPF attach", e);
-                                    golang_attach(pid, true)
-                                }
-                            }
-                        }
-                    }
+                    dispatcher
+                        .dispatch_in(mnt_namespace, pid, |mode| {
+                            golang_attach(pid, mode == GolangBackend::Ebpf)
+                        })
+                        .map(|mode| {
+                            info!("golang attached via {:?}: {}", mode, pid);
+                            true
+                        })
                 }
                 ProbeState::AttachedVersionNotMatch => {
                     let msg = format!("not support Golang update version now");
@@ -555,7 +1008,7 @@ impl RASPManager {
         ctrl: Control,
         message_sender: Sender<plugins::Record>,
         bind_path: String,
-        linking_to: Option<String>,
+        linking_to: Vec<String>,
         using_mount: bool,
         ebpf_mode: BPFSelect,
     ) -> AnyhowResult<Self> {
@@ -570,6 +1023,10 @@ impl RASPManager {
                 false
             }
         };
+        // only warn, nsenter is not needed when nothing runs in another namespace
+        if let Err(e) = check_nsenter_version(&settings::RASP_NS_ENTER_BIN()) {
+            warn!("nsenter check failed: {}", e);
+        }
         let ebpf_manager = |ebpf_mode: BPFSelect, ctrl: Control| -> Option<EbpfMode> {
             match ebpf_mode {
                 BPFSelect::DISABLE => None,
@@ -604,6 +1061,13 @@ impl RASPManager {
                 process_comm: None,
                 ebpf_comm: ebpf_manager(ebpf_mode, ctrl),
                 runtime_dir,
+                quiesced: false,
+                mnt_namespace_cache: MntNamespaceCache::new(MNT_NAMESPACE_CACHE_TTL),
+                pre_attach: None,
+                deferred_attaches: HashMap::new(),
+                namespace_modes: HashMap::new(),
+                namespace_comm_modes: HashMap::new(),
+                attach_limiter: None,
             }),
 
             "server" => Ok(RASPManager {
@@ -612,6 +1076,13 @@ impl RASPManager {
                 thread_comm: None,
                 ebpf_comm: ebpf_manager(ebpf_mode, ctrl),
                 runtime_dir,
+                quiesced: false,
+                mnt_namespace_cache: MntNamespaceCache::new(MNT_NAMESPACE_CACHE_TTL),
+                pre_attach: None,
+                deferred_attaches: HashMap::new(),
+                namespace_modes: HashMap::new(),
+                namespace_comm_modes: HashMap::new(),
+                attach_limiter: None,
             }),
             _ => Err(anyhow!("{} is not a vaild comm mode", comm_mode)),
         }
@@ -785,17 +1256,312 @@ impl RASPManager {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownStage {
+    QuiesceSends,
+    DetachProbes,
+    StopProcessServers,
+    StopEbpfDaemon,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShutdownStep {
+    pub stage: ShutdownStage,
+    pub duration: Duration,
+    /// stage ran past its timeout, remaining work of the stage was skipped
+    pub timed_out: bool,
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownSummary {
+    pub steps: Vec<ShutdownStep>,
+}
+
+impl ShutdownSummary {
+    pub fn is_clean(&self) -> bool {
+        self.steps
+            .iter()
+            .all(|step| !step.timed_out && step.errors.is_empty())
+    }
+}
+
+/// teardown hooks driven by `stop_all`, each stage must finish by `deadline`
+/// and returns the errors it hit
+trait ShutdownStages {
+    fn quiesce_sends(&mut self, deadline: Instant) -> Vec<String>;
+    fn detach_probes(&mut self, deadline: Instant) -> Vec<String>;
+    fn stop_process_servers(&mut self, deadline: Instant) -> Vec<String>;
+    fn stop_ebpf_daemon(&mut self, deadline: Instant) -> Vec<String>;
+}
+
+/// run shutdown stages in order, a failing or late stage is recorded and the next one still runs
+fn run_shutdown(target: &mut dyn ShutdownStages, timeout: Duration) -> ShutdownSummary {
+    let mut summary = ShutdownSummary::default();
+    for stage in [
+        ShutdownStage::QuiesceSends,
+        ShutdownStage::DetachProbes,
+        ShutdownStage::StopProcessServers,
+        ShutdownStage::StopEbpfDaemon,
+    ] {
+        let start = Instant::now();
+        let deadline = start + timeout;
+        let errors = match stage {
+            ShutdownStage::QuiesceSends => target.quiesce_sends(deadline),
+            ShutdownStage::DetachProbes => target.detach_probes(deadline),
+            ShutdownStage::StopProcessServers => target.stop_process_servers(deadline),
+            ShutdownStage::StopEbpfDaemon => target.stop_ebpf_daemon(deadline),
+        };
+        let step = ShutdownStep {
+            stage,
+            duration: start.elapsed(),
+            timed_out: Instant::now() > deadline,
+            errors,
+        };
+        if step.timed_out || !step.errors.is_empty() {
+            warn!("shutdown stage not clean: {:?}", step);
+        }
+        summary.steps.push(step);
+    }
+    summary
+}
+
+struct ManagerShutdown<'a> {
+    manager: &'a mut RASPManager,
+    processes: &'a [ProcessInfo],
+}
+
+impl ShutdownStages for ManagerShutdown<'_> {
+    fn quiesce_sends(&mut self, _deadline: Instant) -> Vec<String> {
+        self.manager.quiesced = true;
+        Vec::new()
+    }
+    fn detach_probes(&mut self, deadline: Instant) -> Vec<String> {
+        let mut errors = Vec::new();
+        // only JVM probes can be detached
+        for process_info in self.processes.iter().filter(|p| {
+            p.runtime
+                .as_ref()
+                .map(|runtime| runtime.name == "JVM")
+                .unwrap_or(false)
+        }) {
+            if Instant::now() > deadline {
+                errors.push(format!("detach skipped: {}", process_info.pid));
+                continue;
+            }
+            if let Err(e) = self.manager.detach(process_info) {
+                errors.push(format!("detach {} failed: {}", process_info.pid, e));
+            }
+        }
+        errors
+    }
+    fn stop_process_servers(&mut self, deadline: Instant) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Some(comm) = self.manager.thread_comm.as_ref() {
+            let drained = comm.drain_reports();
+            if drained.dropped > 0 {
+                errors.push(format!("reports dropped: {}", drained.dropped));
+            }
+        }
+        if let Some(comm) = self.manager.process_comm.as_mut() {
+            let dropped = comm.drain_totals.dropped;
+            let namespaces: Vec<String> = comm.mnt_namesapce_server_map.keys().cloned().collect();
+            for mnt_namespace in namespaces.iter() {
+                if Instant::now() > deadline {
+                    errors.push(format!("stop server skipped: {}", mnt_namespace));
+                    continue;
+                }
+                if let Err(e) = comm.stop_comm(0, mnt_namespace) {
+                    errors.push(e.to_string());
+                }
+                self.manager
+                    .namespace_tracer
+                    .delete_namespace(mnt_namespace.clone());
+            }
+            if comm.drain_totals.dropped > dropped {
+                errors.push(format!(
+                    "reports dropped: {}",
+                    comm.drain_totals.dropped - dropped
+                ));
+            }
+        }
+        errors
+    }
+    fn stop_ebpf_daemon(&mut self, deadline: Instant) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Some(mut ebpf_comm) = self.manager.ebpf_comm.take() {
+            if let Some(server_pid) = ebpf_comm.server_pid {
+                let grace = ebpf_comm
+                    .kill_grace
+                    .min(deadline.saturating_duration_since(Instant::now()));
+                if let KillOutcome::Error { errno } = EbpfMode::kill_server(server_pid, grace) {
+                    errors.push(format!(
+                        "kill ebpf daemon failed: {}",
+                        std::io::Error::from_raw_os_error(errno)
+                    ));
+                }
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if !ebpf_comm.stop_stdout_reader(timeout) {
+                errors.push(String::from("ebpf stdout reader not joined"));
+            }
+        }
+        errors
+    }
+}
+
+impl RASPManager {
+    /// tear everything down in a fixed order: refuse new messages, detach probes of
+    /// `processes`, stop process mode servers, then stop the eBPF daemon last so no
+    /// instrumented target is left talking to a dead daemon. each stage gets `timeout`
+    pub fn stop_all(&mut self, processes: &[ProcessInfo], timeout: Duration) -> ShutdownSummary {
+        run_shutdown(
+            &mut ManagerShutdown {
+                manager: self,
+                processes,
+            },
+            timeout,
+        )
+    }
+    /// process mode namespaces with their uptime, counters and pinned golang backend,
+    /// empty in thread mode. `None` when the `BPFSelect` of each attach decides
+    pub fn managed(&self) -> Vec<(String, NamespaceStats, Option<GolangBackend>)> {
+        match self.process_comm.as_ref() {
+            Some(comm) => comm
+                .managed()
+                .into_iter()
+                .map(|(namespace, stats)| {
+                    let mode = self.namespace_modes.get(&namespace).copied();
+                    (namespace, stats, mode)
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    /// see `AttachDispatcher::set_namespace_mode`, cleared once the namespace's last pid
+    /// is stopped
+    pub fn set_namespace_mode(&mut self, mnt_namespace: &str, mode: GolangBackend) {
+        info!("golang attach pinned to {:?}: {}", mode, mnt_namespace);
+        self.namespace_modes.insert(mnt_namespace.to_string(), mode);
+    }
+    /// serve every pid of `mnt_namespace` through `mode`, whatever the agent's comm mode.
+    /// process mode is started on demand, cleared once the namespace's last pid is stopped
+    pub fn set_namespace_comm_mode(&mut self, mnt_namespace: &str, mode: CommMode) {
+        info!("comm mode pinned to {:?}: {}", mode, mnt_namespace);
+        self.namespace_comm_modes.insert(mnt_namespace.to_string(), mode);
+    }
+    /// the pinned comm mode of `mnt_namespace`, otherwise the one the agent runs.
+    /// `None` when neither is set up
+    pub fn comm_mode(&self, mnt_namespace: &str) -> Option<CommMode> {
+        if let Some(mode) = self.namespace_comm_modes.get(mnt_namespace) {
+            return Some(*mode);
+        }
+        if self.thread_comm.is_some() {
+            Some(CommMode::Thread)
+        } else if self.process_comm.is_some() {
+            Some(CommMode::Process)
+        } else {
+            None
+        }
+    }
+    /// drop the pins of a namespace left by its last pid
+    fn release_namespace(&mut self, mnt_namespace: &str) {
+        self.namespace_modes.remove(mnt_namespace);
+        self.namespace_comm_modes.remove(mnt_namespace);
+    }
+    /// see `ProcessMode::reap_expired_servers`, call it periodically
+    pub fn reap_expired_servers(&mut self) -> Option<String> {
+        self.process_comm.as_mut()?.reap_expired_servers()
+    }
+    /// see `ProcessMode::restart_dead_servers`, call it periodically
+    pub fn restart_dead_servers(&mut self) -> Vec<String> {
+        match self.process_comm.as_mut() {
+            Some(comm) => comm.restart_dead_servers(),
+            None => Vec::new(),
+        }
+    }
+    /// housekeeping of the servers, polled by the plugin: restart an exited golang ebpf
+    /// daemon under its `RestartPolicy`, restart exited process mode servers, then
+    /// recycle the oldest one past its lifetime
+    pub fn maintain_servers(&mut self) {
+        if let Some(ebpf_comm) = self.ebpf_comm.as_mut() {
+            match ebpf_comm.restart_if_exited() {
+                Ok(true) => info!("golang ebpf daemon restarted"),
+                Ok(false) => {}
+                Err(e) => warn!("restart golang ebpf daemon failed: {}", e),
+            }
+        }
+        let restarted = self.restart_dead_servers();
+        if !restarted.is_empty() {
+            info!("exited servers restarted: {:?}", restarted);
+        }
+        if let Some(recycled) = self.reap_expired_servers() {
+            info!("expired server recycled: {}", recycled);
+        }
+    }
+    /// "try again now" after fixing the host, see `EbpfMode::reset_protections` and
+    /// `ProcessMode::reset_protections`
+    pub fn reset_protections(&mut self) -> Vec<&'static str> {
+        let mut reset = match self.ebpf_comm.as_mut() {
+            Some(ebpf_comm) => ebpf_comm.reset_protections(),
+            None => Vec::new(),
+        };
+        if let Some(process_comm) = self.process_comm.as_mut() {
+            reset.extend(process_comm.reset_protections());
+        }
+        if !self.deferred_attaches.is_empty() {
+            self.deferred_attaches.clear();
+            reset.push("attach deferrals");
+        }
+        if let Some(limiter) = self.attach_limiter.as_mut() {
+            if limiter.reset() {
+                reset.push("attach rate limit");
+            }
+        }
+        info!("protections reset: {:?}", reset);
+        reset
+    }
+}
+
 pub struct MntNamespaceTracer {
     /// {<mnt namespace>: ([<pid>, <pid>...], <server_start_or_not>)}
     tracer: HashMap<String, (Vec<i32>, bool)>,
+    /// start time of traced pids, tells a live namespace from a reused inode
+    start_times: HashMap<i32, u64>,
 }
 
 impl MntNamespaceTracer {
     pub fn new() -> Self {
         Self {
             tracer: HashMap::<String, (Vec<i32>, bool)>::new(),
+            start_times: HashMap::new(),
         }
     }
+    pub fn add_with_start_time(&mut self, mnt_namespace: String, pid: i32, start_time: u64) {
+        self.start_times.insert(pid, start_time);
+        self.add(mnt_namespace, pid);
+    }
+    /// mnt namespace inodes are reused once a namespace is gone. a traced namespace none
+    /// of whose pids is `alive` any more is such a leftover, it is dropped and true
+    /// returned so the caller cleans up what it keeps under the same key
+    pub fn evict_if_reused<F>(&mut self, mnt_namespace: &String, alive: F) -> bool
+    where
+        F: Fn(&str, i32, u64) -> bool,
+    {
+        let pids = match self.tracer.get(mnt_namespace) {
+            Some((pids, _)) => pids,
+            None => return false,
+        };
+        let start_times = &self.start_times;
+        let reused = !pids.iter().any(|pid| {
+            let start_time = start_times.get(pid).copied().unwrap_or_default();
+            alive(mnt_namespace, *pid, start_time)
+        });
+        if reused {
+            self.delete_namespace(mnt_namespace.clone());
+        }
+        reused
+    }
     pub fn add(&mut self, mnt_namespace: String, pid: i32) {
         if let Some(value) = self.tracer.get_mut(&mnt_namespace) {
             if value.0.contains(&pid) {
@@ -814,7 +1580,11 @@ impl MntNamespaceTracer {
     }
 
     pub fn delete_namespace(&mut self, mnt_namespace: String) {
-        self.tracer.remove(&mnt_namespace);
+        if let Some((pids, _)) = self.tracer.remove(&mnt_namespace) {
+            for pid in pids {
+                self.start_times.remove(&pid);
+            }
+        }
     }
 
     pub fn delete_pid(&mut self, mnt_namespace: String, pid: i32) {
@@ -822,6 +1592,7 @@ impl MntNamespaceTracer {
             let index = value.0.iter().position(|x| *x == pid);
             if let Some(i) = index {
                 value.0.remove(i);
+                self.start_times.remove(&pid);
             }
         }
     }
@@ -844,6 +1615,7 @@ impl MntNamespaceTracer {
                 let index = value.0.iter().position(|x| *x == pid);
                 if let Some(i) = index {
                     value.0.remove(i);
+                    self.start_times.remove(&pid);
                 }
             }
             if value.0.len() == 0 {
@@ -855,6 +1627,73 @@ impl MntNamespaceTracer {
     }
 }
 
+/// `pid` still runs in `mnt_namespace` and, unless `start_time` is 0, was not reused
+pub fn pid_alive_in(mnt_namespace: &str, pid: i32, start_time: u64) -> bool {
+    let process = match procfs::process::Process::new(pid) {
+        Ok(process) => process,
+        Err(_) => return false,
+    };
+    if start_time != 0 && process.stat.starttime != start_time {
+        return false;
+    }
+    match fs::read_link(format!("/proc/{}/ns/mnt", pid)) {
+        Ok(link) => link.to_string_lossy() == mnt_namespace,
+        Err(_) => false,
+    }
+}
+
+const MNT_NAMESPACE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// read-through cache of `/proc/<pid>/ns/mnt`, an entry is dropped once older than `ttl`
+/// or when looked up with a start time other than the one it was stored with (pid reuse).
+/// expired entries of every pid are pruned on each miss
+pub struct MntNamespaceCache {
+    pub ttl: Duration,
+    /// pid -> (mnt namespace, start time, read at)
+    entries: HashMap<i32, (String, u64, Instant)>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl MntNamespaceCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+    /// `start_time` in clock ticks as in `/proc/<pid>/stat`, read from there when `None`
+    pub fn get(&mut self, pid: i32, start_time: Option<u64>) -> AnyhowResult<String> {
+        if let Some((mnt_namespace, cached_start_time, read_at)) = self.entries.get(&pid) {
+            let reused = matches!(start_time, Some(start_time) if start_time != *cached_start_time);
+            if !reused && read_at.elapsed() < self.ttl {
+                self.hits += 1;
+                return Ok(mnt_namespace.clone());
+            }
+        }
+        self.misses += 1;
+        let ttl = self.ttl;
+        self.entries.retain(|entry_pid, (_, _, read_at)| {
+            *entry_pid != pid && read_at.elapsed() < ttl
+        });
+        let start_time = match start_time {
+            Some(start_time) => start_time,
+            None => procfs::process::Process::new(pid)?.stat.starttime,
+        };
+        let mnt_namespace = fs::read_link(format!("/proc/{}/ns/mnt", pid))?
+            .to_string_lossy()
+            .into_owned();
+        self.entries
+            .insert(pid, (mnt_namespace.clone(), start_time, Instant::now()));
+        Ok(mnt_namespace)
+    }
+    pub fn invalidate(&mut self, pid: i32) {
+        self.entries.remove(&pid);
+    }
+}
+
 impl RASPManager {
     /* 
     pub fn write_message_to_config_file(
@@ -969,4 +1808,387 @@ mod tests {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    struct MockStages {
+        calls: Vec<ShutdownStage>,
+        slow: Option<ShutdownStage>,
+    }
+
+    impl ShutdownStages for MockStages {
+        fn quiesce_sends(&mut self, _deadline: Instant) -> Vec<String> {
+            self.calls.push(ShutdownStage::QuiesceSends);
+            Vec::new()
+        }
+        fn detach_probes(&mut self, _deadline: Instant) -> Vec<String> {
+            self.calls.push(ShutdownStage::DetachProbes);
+            if self.slow == Some(ShutdownStage::DetachProbes) {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            vec!["detach 1234 failed".to_string()]
+        }
+        fn stop_process_servers(&mut self, _deadline: Instant) -> Vec<String> {
+            self.calls.push(ShutdownStage::StopProcessServers);
+            Vec::new()
+        }
+        fn stop_ebpf_daemon(&mut self, _deadline: Instant) -> Vec<String> {
+            self.calls.push(ShutdownStage::StopEbpfDaemon);
+            Vec::new()
+        }
+    }
+
+    fn bare_manager() -> RASPManager {
+        RASPManager {
+            namespace_tracer: MntNamespaceTracer::new(),
+            thread_comm: None,
+            process_comm: None,
+            ebpf_comm: None,
+            runtime_dir: false,
+            quiesced: false,
+            mnt_namespace_cache: MntNamespaceCache::new(MNT_NAMESPACE_CACHE_TTL),
+            pre_attach: None,
+            deferred_attaches: HashMap::new(),
+            namespace_modes: HashMap::new(),
+            namespace_comm_modes: HashMap::new(),
+            attach_limiter: None,
+        }
+    }
+
+    #[test]
+    fn pre_attach_decisions() {
+        let process_info = ProcessInfo::new(1234);
+        let unknown_runtime = |e: anyhow::Error| e.downcast_ref::<CommError>().is_none();
+        let mut manager = bare_manager();
+        manager.pre_attach = Some(Arc::new(|context: &AttachContext| {
+            assert_eq!(context.pid, 1234);
+            assert_eq!(context.operation, CommOperation::Attach);
+            AttachDecision::Proceed
+        }));
+        // past the hook, fails on the missing runtime
+        assert!(unknown_runtime(
+            manager.attach(&process_info, BPFSelect::FIRST).unwrap_err()
+        ));
+
+        manager.pre_attach = Some(Arc::new(|_: &AttachContext| AttachDecision::Skip {
+            reason: String::from("denied by policy"),
+        }));
+        let e = manager.attach(&process_info, BPFSelect::FIRST).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::AttachSkipped { pid: 1234, reason }) if reason == "denied by policy"
+        ));
+        assert!(manager.deferred_attaches.is_empty());
+
+        let asked = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = asked.clone();
+        manager.pre_attach = Some(Arc::new(move |_: &AttachContext| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            AttachDecision::Defer {
+                retry_after: Duration::from_millis(100),
+            }
+        }));
+        for _ in 0..2 {
+            let e = manager.attach(&process_info, BPFSelect::FIRST).unwrap_err();
+            assert!(matches!(
+                e.downcast_ref::<CommError>(),
+                Some(CommError::AttachDeferred { pid: 1234, .. })
+            ));
+        }
+        // the hook is not asked again while deferred
+        assert_eq!(asked.load(std::sync::atomic::Ordering::SeqCst), 1);
+        // unless a reset drops the deferral
+        assert_eq!(manager.reset_protections(), vec!["attach deferrals"]);
+        assert!(manager.attach(&process_info, BPFSelect::FIRST).is_err());
+        assert_eq!(asked.load(std::sync::atomic::Ordering::SeqCst), 2);
+        std::thread::sleep(Duration::from_millis(120));
+        manager.pre_attach = None;
+        assert!(unknown_runtime(
+            manager.attach(&process_info, BPFSelect::FIRST).unwrap_err()
+        ));
+        assert!(manager.deferred_attaches.is_empty());
+    }
+
+    #[test]
+    fn attach_rate_is_capped() {
+        let start = Instant::now();
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(AttachRateLimiter::new(rate, 5, RateLimitPolicy::Drop).is_err());
+        }
+        let mut limiter = AttachRateLimiter::new(10.0, 5, RateLimitPolicy::Drop).unwrap();
+        let granted = (0..20)
+            .filter(|_| limiter.acquire_at(start).is_ok())
+            .count();
+        assert_eq!(granted, 5);
+        assert_eq!(limiter.dropped, 15);
+        // one token every 100ms
+        let retry_after = limiter.acquire_at(start + Duration::from_millis(50)).unwrap_err();
+        assert!(retry_after <= Duration::from_millis(50));
+        assert!(limiter.acquire_at(start + Duration::from_millis(100)).is_ok());
+        assert!(limiter.acquire_at(start + Duration::from_millis(100)).is_err());
+        assert_eq!(limiter.current_rate(start + Duration::from_millis(500)), 6);
+        assert_eq!(limiter.current_rate(start + Duration::from_millis(1050)), 1);
+        // the bucket never holds more than the burst
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..10).filter(|_| limiter.acquire_at(later).is_ok()).count(), 5);
+        // granted attaches are forgotten without calling `current_rate`
+        assert_eq!(limiter.granted.len(), 5);
+
+        let process_info = ProcessInfo::new(1234);
+        let mut manager = bare_manager();
+        manager.attach_limiter =
+            Some(AttachRateLimiter::new(1.0, 1, RateLimitPolicy::Defer).unwrap());
+        // past the limiter, fails on the missing runtime
+        assert!(manager
+            .attach(&process_info, BPFSelect::FIRST)
+            .unwrap_err()
+            .downcast_ref::<CommError>()
+            .is_none());
+        let e = manager.attach(&process_info, BPFSelect::FIRST).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::AttachDeferred { pid: 1234, .. })
+        ));
+        assert!(manager.deferred_attaches.contains_key(&1234));
+        assert_eq!(manager.attach_rate_stats(), Some((1, 1, 0)));
+        // a reset refills the bucket along with dropping the deferral
+        assert_eq!(
+            manager.reset_protections(),
+            vec!["attach deferrals", "attach rate limit"]
+        );
+        assert!(manager
+            .attach(&process_info, BPFSelect::FIRST)
+            .unwrap_err()
+            .downcast_ref::<CommError>()
+            .is_none());
+    }
+
+    #[test]
+    fn attach_falls_back_on_hard_failure() {
+        let mut tried = Vec::new();
+        let dispatcher = AttachDispatcher::from_select(&BPFSelect::FIRST);
+        let mode = dispatcher
+            .dispatch(1234, |mode| {
+                tried.push(mode);
+                match mode {
+                    GolangBackend::Ebpf => Err(anyhow!("verifier rejected uprobe")),
+                    GolangBackend::Pangolin => Ok(true),
+                }
+            })
+            .unwrap();
+        assert_eq!(mode, GolangBackend::Pangolin);
+        assert_eq!(tried, vec![GolangBackend::Ebpf, GolangBackend::Pangolin]);
+
+        // timeouts the daemon gave up on fall back
+        tried.clear();
+        let timeout = |tried: &mut Vec<GolangBackend>, mode| -> AnyhowResult<bool> {
+            tried.push(mode);
+            match mode {
+                GolangBackend::Ebpf => Err(CommError::AttachTimeout {
+                    pid: 1234,
+                    diagnostics: Box::default(),
+                }
+                .into()),
+                GolangBackend::Pangolin => Ok(true),
+            }
+        };
+        let mode = dispatcher
+            .dispatch(1234, |mode| timeout(&mut tried, mode))
+            .unwrap();
+        assert_eq!(mode, GolangBackend::Pangolin);
+        assert_eq!(tried, vec![GolangBackend::Ebpf, GolangBackend::Pangolin]);
+
+        // resumed timeouts retry the primary and never fall back
+        let mut dispatcher = dispatcher;
+        dispatcher.resume_timed_out = true;
+        tried.clear();
+        let e = dispatcher
+            .dispatch(1234, |mode| {
+                tried.push(mode);
+                Err(CommError::AttachTimeout {
+                    pid: 1234,
+                    diagnostics: Box::default(),
+                }
+                .into())
+            })
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommError>(),
+            Some(CommError::AttachTimeout { .. })
+        ));
+        assert_eq!(tried, vec![GolangBackend::Ebpf, GolangBackend::Ebpf]);
+
+        let no_fallback = AttachDispatcher::from_select(&BPFSelect::FORCE);
+        assert!(no_fallback.dispatch(1234, |_| Ok(false)).is_err());
+    }
+
+    #[test]
+    fn namespace_mode_beats_selection() {
+        let mut dispatcher = AttachDispatcher::from_select(&BPFSelect::FORCE);
+        dispatcher.set_namespace_mode("mnt:[4026532001]", GolangBackend::Pangolin);
+        let mut tried = Vec::new();
+        let mode = dispatcher
+            .dispatch_in("mnt:[4026532001]", 1234, |mode| {
+                tried.push(mode);
+                Ok(true)
+            })
+            .unwrap();
+        assert_eq!(mode, GolangBackend::Pangolin);
+        // a pinned mode never falls back
+        tried.clear();
+        assert!(dispatcher
+            .dispatch_in("mnt:[4026532001]", 1234, |mode| {
+                tried.push(mode);
+                Ok(false)
+            })
+            .is_err());
+        assert_eq!(tried, vec![GolangBackend::Pangolin]);
+        let mode = dispatcher
+            .dispatch_in("mnt:[4026532002]", 1235, |_| Ok(true))
+            .unwrap();
+        assert_eq!(mode, GolangBackend::Ebpf);
+    }
+
+    #[test]
+    fn namespace_comm_mode_beats_the_agent_one() {
+        let mut manager = bare_manager();
+        assert_eq!(manager.comm_mode("mnt:[4026532001]"), None);
+        manager.process_comm = Some(ProcessMode::new(String::from("info"), Control::new()));
+        assert_eq!(manager.comm_mode("mnt:[4026532001]"), Some(CommMode::Process));
+        manager.set_namespace_comm_mode("mnt:[4026532001]", CommMode::Thread);
+        manager.set_namespace_mode("mnt:[4026532001]", GolangBackend::Pangolin);
+        assert_eq!(manager.comm_mode("mnt:[4026532001]"), Some(CommMode::Thread));
+        assert_eq!(manager.comm_mode("mnt:[4026532002]"), Some(CommMode::Process));
+        // gone with the namespace's last pid
+        manager.release_namespace("mnt:[4026532001]");
+        assert!(manager.namespace_modes.is_empty());
+        assert_eq!(manager.comm_mode("mnt:[4026532001]"), Some(CommMode::Process));
+    }
+
+    #[test]
+    fn conflicting_health_prefers_owner() {
+        let dispatcher = AttachDispatcher::from_select(&BPFSelect::FIRST);
+        let views = [
+            (GolangBackend::Ebpf, ProbeHealth::Dead),
+            (GolangBackend::Pangolin, ProbeHealth::Healthy),
+        ];
+        // fell back to pangolin, the dead ebpf entry is left over
+        let report = dispatcher.probe_health(1234, Some(GolangBackend::Pangolin), &views);
+        assert_eq!(
+            report,
+            HealthReport {
+                health: ProbeHealth::Healthy,
+                mode: Some(GolangBackend::Pangolin),
+                stale: vec![GolangBackend::Ebpf],
+            }
+        );
+        // owner unknown, primary decides
+        let report = dispatcher.probe_health(1234, None, &views);
+        assert_eq!(report.health, ProbeHealth::Dead);
+        assert_eq!(report.stale, vec![GolangBackend::Pangolin]);
+        // an owner without a view does not decide
+        let views = [
+            (GolangBackend::Ebpf, ProbeHealth::Healthy),
+            (GolangBackend::Pangolin, ProbeHealth::Unknown),
+        ];
+        let report = dispatcher.probe_health(1234, Some(GolangBackend::Pangolin), &views);
+        assert_eq!(report.mode, Some(GolangBackend::Ebpf));
+        assert!(report.stale.is_empty());
+        assert_eq!(dispatcher.probe_health(1234, None, &[]).health, ProbeHealth::Unknown);
+    }
+
+    #[test]
+    fn exe_deleted_detection() {
+        let binary = std::env::temp_dir().join(format!("librasp_sleep_{}", std::process::id()));
+        fs::copy("/bin/sleep", &binary).unwrap();
+        let mut child = Command::new(&binary).arg("30").spawn().unwrap();
+        let running = ProcessInfo::from_pid(child.id() as i32).unwrap();
+        assert!(!running.exe_deleted);
+        assert!(!RASPManager::warn_exe_deleted(&running));
+        fs::remove_file(&binary).unwrap();
+        let deleted = ProcessInfo::from_pid(child.id() as i32).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(deleted.exe_deleted);
+        assert!(RASPManager::warn_exe_deleted(&deleted));
+        assert!(deleted.exe_path.unwrap().ends_with(" (deleted)"));
+    }
+
+    #[test]
+    fn reused_mnt_namespace_is_evicted() {
+        let mnt_namespace = String::from("mnt:[4026532999]");
+        let mut tracer = MntNamespaceTracer::new();
+        // container started at 100 owned the inode, the new one started at 200
+        tracer.add_with_start_time(mnt_namespace.clone(), 1234, 100);
+        tracer.server_state_on(mnt_namespace.clone());
+        let new_container = |_: &str, pid: i32, start_time: u64| pid == 1234 && start_time == 200;
+        assert!(tracer.evict_if_reused(&mnt_namespace, new_container));
+        assert_eq!(tracer.server_state(&mnt_namespace), None);
+        assert!(tracer.start_times.is_empty());
+        // still alive, kept
+        tracer.add_with_start_time(mnt_namespace.clone(), 1234, 200);
+        assert!(!tracer.evict_if_reused(&mnt_namespace, new_container));
+        assert_eq!(tracer.server_state(&mnt_namespace), Some(false));
+        // untraced namespaces are left alone
+        assert!(!tracer.evict_if_reused(&String::from("mnt:[1]"), |_, _, _| false));
+
+        let pid = std::process::id() as i32;
+        let own_namespace = fs::read_link("/proc/self/ns/mnt")
+            .unwrap()
+            .display()
+            .to_string();
+        let start_time = procfs::process::Process::new(pid).unwrap().stat.starttime;
+        assert!(pid_alive_in(&own_namespace, pid, start_time));
+        assert!(pid_alive_in(&own_namespace, pid, 0));
+        assert!(!pid_alive_in(&own_namespace, pid, start_time + 1));
+        assert!(!pid_alive_in(&mnt_namespace, pid, start_time));
+    }
+
+    #[test]
+    fn mnt_namespace_cache() {
+        let pid = std::process::id() as i32;
+        let start_time = procfs::process::Process::new(pid).unwrap().stat.starttime;
+        let mnt_namespace = fs::read_link("/proc/self/ns/mnt")
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        let mut cache = MntNamespaceCache::new(Duration::from_secs(60));
+        assert_eq!(cache.get(pid, None).unwrap(), mnt_namespace);
+        assert_eq!(cache.get(pid, Some(start_time)).unwrap(), mnt_namespace);
+        assert_eq!((cache.hits, cache.misses), (1, 1));
+        // pid reused by a process started later
+        assert_eq!(cache.get(pid, Some(start_time + 1)).unwrap(), mnt_namespace);
+        assert_eq!((cache.hits, cache.misses), (1, 2));
+        cache.get(pid, Some(start_time + 1)).unwrap();
+        assert_eq!((cache.hits, cache.misses), (2, 2));
+        cache.ttl = Duration::from_secs(0);
+        cache.get(pid, None).unwrap();
+        assert_eq!((cache.hits, cache.misses), (2, 3));
+        // expired entries of other pids go on a miss, even a failed one
+        assert!(cache.get(i32::MAX, None).is_err());
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn shutdown_order() {
+        let mut stages = MockStages {
+            calls: Vec::new(),
+            slow: Some(ShutdownStage::DetachProbes),
+        };
+        let summary = run_shutdown(&mut stages, Duration::from_millis(10));
+        let expected = vec![
+            ShutdownStage::QuiesceSends,
+            ShutdownStage::DetachProbes,
+            ShutdownStage::StopProcessServers,
+            ShutdownStage::StopEbpfDaemon,
+        ];
+        assert_eq!(stages.calls, expected);
+        assert_eq!(
+            summary.steps.iter().map(|step| step.stage).collect::<Vec<_>>(),
+            expected
+        );
+        // a late, failing stage does not stop the rest
+        assert!(summary.steps[1].timed_out);
+        assert_eq!(summary.steps[1].errors.len(), 1);
+        assert!(!summary.steps[3].timed_out);
+        assert!(!summary.is_clean());
+    }
 }
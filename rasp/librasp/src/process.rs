@@ -11,12 +11,17 @@ use coarsetime::Clock;
 use procfs::process::{Namespaces, Process};
 use serde::{Deserialize, Serialize};
 
+/// appended by the kernel to `/proc/<pid>/exe` once the binary is unlinked
+pub const DELETED_EXE_SUFFIX: &str = " (deleted)";
+
 #[derive(Debug, Clone, Default)]
 pub struct ProcessInfo {
     pub pid: i32,
     pub cmdline: Option<String>,
     pub exe_name: Option<String>,
     pub exe_path: Option<String>,
+    /// the running binary was removed or replaced on disk, e.g. upgraded in place
+    pub exe_deleted: bool,
     pub sid: i32,
     pub ruid: u32,
     pub rgid: u32,
@@ -146,6 +151,7 @@ impl ProcessInfo {
                 return Err(anyhow!("convert osstr to string failed: {:?}", &eposs));
             }
         };
+        self.exe_deleted = exe_path.ends_with(DELETED_EXE_SUFFIX);
         self.exe_name = Some(exe_name.clone());
         self.exe_path = Some(exe_path.clone());
         Ok((exe_name, exe_path))
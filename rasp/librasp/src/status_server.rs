@@ -0,0 +1,142 @@
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::Result as AnyhowResult;
+use log::*;
+
+use crate::comm::Control;
+
+pub const DEFAULT_STATUS_ADDR: &str = "127.0.0.1:9900";
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+pub type StatusProvider = Arc<dyn Fn() -> String + Send + Sync>;
+
+/// tiny HTTP/1.1 server exposing comm state, GET only, one connection at a time
+pub struct StatusServer {
+    /// loopback by default, the routes are not authenticated
+    pub addr: SocketAddr,
+    pub status_route: String,
+    pub metrics_route: String,
+    /// JSON document served on `status_route`
+    pub status: StatusProvider,
+    /// OpenMetrics text served on `metrics_route`
+    pub metrics: StatusProvider,
+}
+
+impl StatusServer {
+    pub fn new(status: StatusProvider, metrics: StatusProvider) -> Self {
+        Self {
+            addr: DEFAULT_STATUS_ADDR.parse().unwrap(),
+            status_route: String::from("/status"),
+            metrics_route: String::from("/metrics"),
+            status,
+            metrics,
+        }
+    }
+    /// bind and serve in a thread until `ctrl` is stopped
+    pub fn start(self, ctrl: Control) -> AnyhowResult<(SocketAddr, JoinHandle<()>)> {
+        let listener = TcpListener::bind(self.addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        info!("status server listening on: {}", local_addr);
+        let handle = thread::Builder::new()
+            .name(String::from("status_server"))
+            .spawn(move || self.serve(listener, ctrl))?;
+        Ok((local_addr, handle))
+    }
+    fn serve(&self, listener: TcpListener, mut ctrl: Control) {
+        while ctrl.check() {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    if let Err(e) = self.handle(stream) {
+                        debug!("status request from {} failed: {}", peer, e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+                Err(e) => {
+                    warn!("status server accept failed: {}", e);
+                    thread::sleep(ACCEPT_POLL);
+                }
+            }
+        }
+        info!("status server stopped");
+    }
+    fn handle(&self, stream: TcpStream) -> AnyhowResult<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // drain headers, bodies are not expected for GET
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+        }
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let path = parts.next().unwrap_or_default();
+        let response = if method != "GET" {
+            Self::response("405 Method Not Allowed", "text/plain", String::new())
+        } else if path == self.status_route {
+            Self::response("200 OK", "application/json", (self.status)())
+        } else if path == self.metrics_route {
+            Self::response("200 OK", OPENMETRICS_CONTENT_TYPE, (self.metrics)())
+        } else {
+            Self::response("404 Not Found", "text/plain", String::new())
+        };
+        let mut stream = reader.into_inner();
+        stream.write_all(response.as_bytes())?;
+        stream.flush()?;
+        Ok(())
+    }
+    fn response(status: &str, content_type: &str, body: String) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_status_and_metrics() {
+        let mut server = StatusServer::new(
+            Arc::new(|| String::from("{\"ebpf\":\"running\"}")),
+            Arc::new(|| String::from("rasp_attach_total 3\n# EOF\n")),
+        );
+        server.addr = "127.0.0.1:0".parse().unwrap();
+        let mut ctrl = Control::new();
+        let (addr, handle) = server.start(ctrl.clone()).unwrap();
+        let status = get(addr, "/status");
+        assert!(status.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(status.contains("Content-Type: application/json\r\n"));
+        assert!(status.ends_with("{\"ebpf\":\"running\"}"));
+        let metrics = get(addr, "/metrics");
+        assert!(metrics.contains("application/openmetrics-text"));
+        assert!(metrics.ends_with("rasp_attach_total 3\n# EOF\n"));
+        assert!(get(addr, "/other").starts_with("HTTP/1.1 404"));
+        ctrl.stop().unwrap();
+        handle.join().unwrap();
+    }
+}
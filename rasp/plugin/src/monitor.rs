@@ -2,7 +2,7 @@ use std::{
     collections::HashMap,
     sync::Arc,
     thread::{sleep, Builder},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -415,6 +415,7 @@ fn internal_main(
     let operation_reporter = internal_message_sender.clone();
     let mut operator = crate::operation::Operator::new(internal_message_sender, ctrl.clone())?;
     // operator.host_rasp_server()?;
+    let mut last_maintained = Instant::now();
     let operation_thread = Builder::new()
         .name("operation".to_string())
         .spawn(move || loop {
@@ -423,6 +424,11 @@ fn internal_main(
                 warn!("operation recv stop signal, quiting.");
                 break;
             }
+            // restart exited servers and recycle expired ones, busy or not
+            if last_maintained.elapsed() >= Duration::from_secs(3) {
+                operator.maintain_servers();
+                last_maintained = Instant::now();
+            }
             let operation_message = match external_message_receiver.try_recv() {
                 Ok(p) => p,
                 Err(crossbeam::channel::TryRecvError::Empty) => {
@@ -22,9 +22,14 @@ impl Operator {
         let comm_mode = settings_string("server", "mode")?;
         let log_level = settings_string("service", "log_level")?;
         let bind_path = settings_string("server", "bind_path")?;
+        // comma separated, the socket is linked to each
         let linking_to = match settings_string("server", "linking_to") {
-            Ok(s) => Some(s),
-            Err(_) => None,
+            Ok(s) => s
+                .split(',')
+                .map(|path| path.trim().to_string())
+                .filter(|path| !path.is_empty())
+                .collect(),
+            Err(_) => Vec::new(),
         };
         let using_mount = match settings_bool("server", "using_mount") {
             Ok(v) => v,
@@ -68,6 +73,9 @@ impl Operator {
         self.rasp_manager.stop_comm(&process)?;
         Ok(())
     }
+    pub fn maintain_servers(&mut self) {
+        self.rasp_manager.maintain_servers();
+    }
     pub fn attach_process(&mut self, process: &mut ProcessInfo) -> AnyhowResult<()> {
         info!("process: {:?}", process);
         // /* stage one: copy probe binary to process namespace file path via /proc/<pid>/root */
@@ -9,6 +9,7 @@ use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
+use crate::proto::{PidMissingProbeConfig, ProbeConfig};
 use crate::utils::{generate_patch, time, Control};
 use crate::{RASPPair, RASPSock};
 
@@ -209,14 +210,19 @@ pub async fn start_bind(sock: RASPSock) -> Result<(), String> {
                 };
                 info!("recv new stream from: {}", pid);
                 let (sock_tx, ctrl) = new_pair(&mut pairs, pid as i32).await;
-                let sock_rx = sock.tx_channel.clone();
-                let rx_ctrl = ctrl.clone();
-                let tx_ctrl = ctrl.clone();
+                let connection = ProbeConnection {
+                    pid,
+                    sock_rx: sock.tx_channel.clone(),
+                    sock_tx,
+                    rx_ctrl: ctrl.clone(),
+                    tx_ctrl: ctrl.clone(),
+                    initial_config: sock.initial_config.clone(),
+                };
                 // let patches = sock.patches.clone();
                 spawn(async move {
                     let (rx, tx) = stream.into_split();
                     let mut stop_ctrl = ctrl.clone();
-                    looping(rx, tx, sock_rx, sock_tx, rx_ctrl, tx_ctrl, pid).await;
+                    looping(rx, tx, connection).await;
                     let _ = stop_ctrl.stop();
                 });
             }
@@ -228,16 +234,33 @@ pub async fn start_bind(sock: RASPSock) -> Result<(), String> {
     }
 }
 
+/// what `looping` serves a probe connection with, besides the stream itself
+pub struct ProbeConnection {
+    pub pid: i32,
+    /// records read from the probe, to the agent
+    pub sock_rx: Sender<plugins::Record>,
+    /// messages from the agent, to the probe
+    pub sock_tx: Receiver<String>,
+    pub rx_ctrl: Control,
+    pub tx_ctrl: Control,
+    /// sent to the probe before anything from the agent
+    pub initial_config: Arc<Vec<PidMissingProbeConfig>>,
+}
+
 pub async fn looping(
     rx: OwnedReadHalf,
     tx: OwnedWriteHalf,
-    sock_rx: Sender<plugins::Record>,
-    mut sock_tx: Receiver<String>,
-    mut rx_ctrl: Control,
-    mut tx_ctrl: Control,
-    pid: i32,
+    connection: ProbeConnection,
     // patch_data: Arc<dashmap::DashMap<i32, dashmap::DashMap<String, String>>>,
 ) {
+    let ProbeConnection {
+        pid,
+        sock_rx,
+        mut sock_tx,
+        mut rx_ctrl,
+        mut tx_ctrl,
+        initial_config,
+    } = connection;
     let mut framed_rx = tokio_util::codec::LengthDelimitedCodec::builder()
         .length_field_offset(0)
         .length_field_length(4)
@@ -265,6 +288,24 @@ pub async fn looping(
         p.extend(patch);
         drop(p)
     });
+    // configure the probe before anything from the agent reaches it
+    for config in initial_config.iter() {
+        let message = match serde_json::to_string(&ProbeConfig {
+            pid,
+            message_type: config.message_type,
+            data: config.data.clone(),
+        }) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("serialize initial config failed: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = framed_tx.send(Bytes::from(message)).await {
+            warn!("send initial config failed: {}", e);
+            return;
+        }
+    }
     loop {
         if !rx_ctrl.clone().check() {
             info!("select thread receive quit signal");
@@ -5,10 +5,11 @@ pub mod proto;
 pub mod thread_mode;
 pub mod utils;
 
+use crate::proto::PidMissingProbeConfig;
 use crate::utils::Control;
 use crossbeam::channel::{Receiver, Sender};
+use std::sync::Arc;
 // use dashmap::DashMap;
-// use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct RASPServerConfig {
@@ -24,6 +25,8 @@ pub struct RASPSock {
     pub rx_channel: Receiver<(i32, String)>,
     // pub patches: Arc<DashMap<i32, DashMap<String, String>>>,
     pub ctrl: Control,
+    /// sent to every probe once connected, see `process_mode::INITIAL_CONFIG_ENV`
+    pub initial_config: Arc<Vec<PidMissingProbeConfig>>,
 }
 
 pub struct RASPPair {
@@ -1,8 +1,10 @@
 use anyhow::Result as AnyHowResult;
 use crossbeam::channel::bounded;
+use libraspserver::process_mode::{read_initial_config, DEFAULT_SOCK_PATH, INITIAL_CONFIG_ENV};
 use libraspserver::utils::Control;
 use libraspserver::{RASPServer, RASPServerConfig, RASPServerRun, RASPSock};
 use std::path::Path;
+use std::sync::Arc;
 
 use clap;
 use log::*;
@@ -22,7 +24,7 @@ fn args() -> AnyHowResult<RASPServerConfig> {
         )
         .arg(
             clap::arg!(--path <PATH> "path for socket listen")
-                .default_value("/var/run/smith_agent.sock")
+                .default_value(DEFAULT_SOCK_PATH)
                 .required(false)
                 .validator(|s| s.parse::<String>()),
         )
@@ -54,6 +56,12 @@ fn main() -> AnyHowResult<()> {
     env_logger::init();
     let server_config = args().unwrap();
     debug!("starting rasp server with config: {:?}", server_config);
+    // the file lives on host, read it before leaving the host mount namespace
+    let initial_config = match std::env::var(INITIAL_CONFIG_ENV) {
+        Ok(path) => read_initial_config(Path::new(&path))?,
+        Err(_) => Vec::new(),
+    };
+    info!("initial config messages: {}", initial_config.len());
     // switch namespace
     if let Some(pid) = server_config.target_pid {
         libraspserver::ns::switch_namespace(pid).unwrap();
@@ -76,6 +84,7 @@ fn main() -> AnyHowResult<()> {
         rx_channel: agent_to_probe_receiver,
         // patches: Arc::new(DashMap::new()),
         ctrl: global_ctrl.clone(),
+        initial_config: Arc::new(initial_config),
     };
     rasp_server.start(sock);
     Ok(())
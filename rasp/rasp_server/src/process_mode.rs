@@ -1,18 +1,23 @@
 use std::collections::HashMap;
+use std::fs::{remove_file, OpenOptions};
 use std::io::{BufRead, BufReader, stdout, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::{ChildStdin, ChildStdout, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{process, thread};
 use thread::{sleep, Builder};
 
 use anyhow::anyhow;
 use anyhow::Result as AnyhowResult;
 use crossbeam::channel::{Receiver, Sender, TryRecvError};
-use libc::{kill, killpg, SIGKILL};
+use libc::{kill, killpg, SIGKILL, SIGTERM};
 use log::*;
 
-use crate::proto::{Message, ProbeConfig};
+use crate::proto::{Message, PidMissingProbeConfig, ProbeConfig};
 use crate::thread_mode::core_loop;
 use crate::RASPSock;
 use crate::{Control, RASPServer, RASPServerRun};
@@ -92,15 +97,73 @@ pub fn listen_stdin(sender: Sender<(i32, String)>) {
     }
 }
 
+/// path of a JSON file holding `Vec<PidMissingProbeConfig>`, sent to every probe on connect
+/// so a new server never serves probes unconfigured
+pub const INITIAL_CONFIG_ENV: &str = "RASP_SERVER_INITIAL_CONFIG";
+
+/// socket the server listens on inside its namespace, unless `--path` says otherwise
+pub const DEFAULT_SOCK_PATH: &str = "/var/run/smith_agent.sock";
+
+static INITIAL_CONFIG_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// written by the agent before spawn, only readable by owner
+pub fn write_initial_config(configs: &Vec<PidMissingProbeConfig>) -> AnyhowResult<PathBuf> {
+    let path = std::env::temp_dir().join(format!(
+        "rasp_server_config_{}_{}.json",
+        process::id(),
+        INITIAL_CONFIG_SEQ.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    file.write_all(serde_json::to_string(configs)?.as_bytes())?;
+    Ok(path)
+}
+
+/// read by the server before switching namespace, the file is removed once read
+pub fn read_initial_config(path: &Path) -> AnyhowResult<Vec<PidMissingProbeConfig>> {
+    let content = std::fs::read_to_string(path);
+    let _ = remove_file(path);
+    Ok(serde_json::from_str(&content?)?)
+}
+
+/// run the child of `command` on `cpus` only, also used for the golang ebpf daemon
+pub fn pin_to_cpus(command: &mut process::Command, cpus: &[usize]) {
+    // built before fork, only sched_setaffinity runs in the child
+    let mut cpu_set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus.iter() {
+        unsafe { libc::CPU_SET(*cpu, &mut cpu_set) };
+    }
+    unsafe {
+        command.pre_exec(move || {
+            if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &cpu_set) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
 pub fn spawn(
     rasp_server_bin_path: &str,
     pid: i32,
     log_level: String,
+    cpu_affinity: Option<&Vec<usize>>,
+    initial_config: Option<&Path>,
 ) -> AnyhowResult<process::Child> {
     let pid_string = pid.clone().to_string();
     let args = &["--pid", pid_string.as_str()];
     debug!("spawning rasp server: {} {:?}", rasp_server_bin_path, args);
-    let child = match std::process::Command::new(rasp_server_bin_path)
+    let mut command = std::process::Command::new(rasp_server_bin_path);
+    if let Some(cpus) = cpu_affinity {
+        pin_to_cpus(&mut command, cpus);
+    }
+    if let Some(path) = initial_config {
+        command.env(INITIAL_CONFIG_ENV, path);
+    }
+    let child = match command
         .env("RUST_LOG", log_level)
         .args(args)
         .stdin(Stdio::piped())
@@ -122,6 +185,8 @@ pub fn spawn(
     Ok(child)
 }
 
+const STOP_POLL: Duration = Duration::from_millis(50);
+
 pub struct RASPServerProcess {
     pub child_id: u32,
     patch_field: Arc<parking_lot::RwLock<HashMap<String, HashMap<&'static str, String>>>>,
@@ -130,6 +195,12 @@ pub struct RASPServerProcess {
     message_receiver: Receiver<String>,
     log_level: String,
     ctrl: Control,
+    cpu_affinity: Option<Vec<usize>>,
+    initial_config: Option<Vec<PidMissingProbeConfig>>,
+    /// removed by the server once read, and by `kill` in case it never was
+    initial_config_path: Option<PathBuf>,
+    /// stopped by the `comm_wait` thread once it reaped the server, `None` until spawned
+    child_ctrl: Option<Control>,
 }
 
 impl RASPServerProcess {
@@ -153,20 +224,43 @@ impl RASPServerProcess {
             message_receiver: message_receiver.clone(),
             log_level: log_level.clone(),
             ctrl: ctrl.clone(),
+            cpu_affinity: None,
+            initial_config: None,
+            initial_config_path: None,
+            child_ctrl: None,
         };
         server_process.update_patch_field(patch_field);
         Ok(server_process)
     }
+    /// pin the server to `cpus` when spawned, the caller validates them
+    pub fn set_cpu_affinity(&mut self, cpus: Vec<usize>) {
+        self.cpu_affinity = Some(cpus);
+    }
+    /// configs the server sends to each probe as soon as it connects
+    pub fn set_initial_config(&mut self, configs: Vec<PidMissingProbeConfig>) {
+        self.initial_config = Some(configs);
+    }
     pub fn spawn(&mut self, rasp_server_path: &str) -> AnyhowResult<()> {
-        let mut child = match spawn(rasp_server_path, self.pid, self.log_level.clone()) {
+        if let Some(configs) = self.initial_config.as_ref() {
+            self.initial_config_path = Some(write_initial_config(configs)?);
+        }
+        let mut child = match spawn(
+            rasp_server_path,
+            self.pid,
+            self.log_level.clone(),
+            self.cpu_affinity.as_ref(),
+            self.initial_config_path.as_deref(),
+        ) {
             Ok(child) => child,
             Err(e) => {
+                self.remove_initial_config();
                 let msg = format!("spawn command failed: {}", e);
                 error!("spawn rasp server failed: {}", msg);
                 return Err(anyhow!("{}", msg));
             }
         };
         let child_id = child.id();
+        self.child_id = child_id;
         let stdin = match child.stdin.take() {
             None => {
                 let msg = format!("can not take child stdin, pid: {}", child_id);
@@ -186,38 +280,31 @@ impl RASPServerProcess {
         let child_ctrl = Control::new();
         let mut wait_child_ctrl = child_ctrl.clone();
 
-        // wait child in new thread
+        // wait child in new thread, blocked until it exits so the exit is seen right away
         thread::Builder::new()
             .name(format!("comm_wait_{}", child.id()))
-            .spawn(move || loop {
-                match child.try_wait() {
-                    Ok(Some(status)) => {
-                        warn!("comm wait exited with: {}", status);
-                        let _ = wait_child_ctrl.stop();
-                        break;
-                    }
-                    Ok(None) => {
-                        sleep(Duration::from_secs(3));
-                    }
-                    Err(e) => {
-                        warn!("error attempting to wait: {}", e);
-                        let _ = wait_child_ctrl.stop();
-                        break;
-                    }
+            .spawn(move || {
+                match child.wait() {
+                    Ok(status) => warn!("comm wait exited with: {}", status),
+                    Err(e) => warn!("error attempting to wait: {}", e),
                 }
-                sleep(Duration::from_secs(3));
+                let _ = wait_child_ctrl.stop();
             })
             .unwrap();
+        self.child_ctrl = Some(child_ctrl.clone());
         // let patch_rw = Arc::new(parking_lot::RwLock::new(HashMap::new()));
         let patch_r = self.patch_field.clone();
-        let _ = process_comm(
+        let server = ServerChild {
             child_id,
+            stdin,
+            stdout,
+            child_ctrl: child_ctrl.clone(),
+        };
+        let _ = process_comm(
+            server,
             self.message_sender.clone(),
             self.message_receiver.clone(),
             self.ctrl.clone(),
-            child_ctrl.clone(),
-            stdin,
-            stdout,
             patch_r,
         );
         Ok(())
@@ -235,23 +322,79 @@ impl RASPServerProcess {
         drop(patch)
     }
     pub fn kill(&mut self) {
+        // never spawned, pid 0 would signal our own process group. once reaped the pid
+        // may belong to another process
+        if self.child_id == 0 || !self.is_running() {
+            self.remove_initial_config();
+            return;
+        }
         unsafe {
             killpg(self.child_id as i32, SIGKILL);
             kill(self.child_id as i32, SIGKILL);
         }
+        self.remove_initial_config();
+    }
+    /// spawned and not reaped since. tracked by the `comm_wait` thread rather than by
+    /// pid, which may belong to another process once reaped
+    pub fn is_running(&self) -> bool {
+        match self.child_ctrl.as_ref() {
+            Some(child_ctrl) => child_ctrl.clone().check(),
+            None => false,
+        }
     }
+    /// SIGTERM the server so it can flush and close its socket, `kill` it when still
+    /// running after `grace`. true when it exited on its own
+    pub fn stop_gracefully(&mut self, grace: Duration) -> bool {
+        if self.child_id == 0 || !self.is_running() {
+            self.remove_initial_config();
+            return true;
+        }
+        let pid = self.child_id as i32;
+        unsafe {
+            killpg(pid, SIGTERM);
+            kill(pid, SIGTERM);
+        }
+        let deadline = Instant::now() + grace;
+        while Instant::now() < deadline {
+            if !self.is_running() {
+                debug!("rasp server terminated: {}", pid);
+                self.remove_initial_config();
+                return true;
+            }
+            sleep(STOP_POLL);
+        }
+        warn!("rasp server still running {:?} after SIGTERM, killing: {}", grace, pid);
+        self.kill();
+        false
+    }
+    fn remove_initial_config(&mut self) {
+        if let Some(path) = self.initial_config_path.take() {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+/// a spawned server `process_comm` relays for, `child_ctrl` is stopped once it exits
+pub struct ServerChild {
+    pub child_id: u32,
+    pub stdin: ChildStdin,
+    pub stdout: ChildStdout,
+    pub child_ctrl: Control,
 }
 
 pub fn process_comm(
-    child_id: u32,
+    server: ServerChild,
     sender: Sender<plugins::Record>,
     receiver: Receiver<String>,
     ctrl: Control,
-    child_ctrl: Control,
-    mut stdin: ChildStdin,
-    stdout: ChildStdout,
     patch_field: Arc<parking_lot::RwLock<HashMap<String, HashMap<&'static str, String>>>>,
 ) {
+    let ServerChild {
+        child_id,
+        mut stdin,
+        stdout,
+        child_ctrl,
+    } = server;
     let receiver = receiver.clone();
     let sender = sender.clone();
     let mut recv_ctrl = ctrl.clone();
@@ -398,7 +541,7 @@ pub fn parse_server_stdout(buf: &String) -> Option<HashMap<&'static str, String>
         return None;
     }
     return match splited[0] {
-        "probe_report" | "heart_beat" | "jar" => {
+        "probe_report" | "heart_beat" | "jar" | "pong" => {
             let message: Message = match serde_json::from_str(splited[1]) {
                 Ok(m) => m,
                 Err(e) => {
@@ -29,6 +29,7 @@ pub struct ProbeData {
     pub golang: Option<GolangDepData>,
     pub request: Option<String>,
     pub blocked: Option<bool>,
+    pub uuid: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -94,6 +95,9 @@ impl ProbeData {
         if let Some(blocked) = self.blocked {
             pdhm.insert("blocked", blocked.to_string());
         }
+        if let Some(uuid) = self.uuid {
+            pdhm.insert("uuid", uuid);
+        }
         pdhm
     }
 }
@@ -333,6 +337,10 @@ pub fn message_handle(message: &String) -> Result<String, String> {
             Ok(_) => String::new(),
             Err(e) => return Err(e),
         },
+        18 => match pong_report(&message.clone()) {
+            Ok(_) => String::new(),
+            Err(e) => return Err(e),
+        },
         _ => return Err(String::from("bad message type")),
     };
     Ok(response)
@@ -345,6 +353,13 @@ pub fn jar_report(message: &Message) -> Result<String, String> {
     Ok(String::new())
 }
 
+pub fn pong_report(message: &Message) -> Result<String, String> {
+    let msg = message.clone();
+    let response = serde_json::json!(msg).to_string();
+    println!("pong:{}", response);
+    Ok(String::new())
+}
+
 pub fn heartbeat_handle(message: &Message) -> Result<String, String> {
     let msg = message.clone();
     let response = serde_json::json!(msg).to_string();
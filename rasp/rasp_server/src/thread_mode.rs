@@ -3,7 +3,8 @@ use crate::{comm, Control, RASPSock};
 use crossbeam::channel::{Receiver, Sender};
 use log::*;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::thread::Builder;
+use std::sync::Arc;
+use std::thread::{Builder, JoinHandle};
 
 pub fn core_loop(sock: RASPSock, max_thread: usize) {
     let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -28,21 +29,23 @@ pub fn core_loop(sock: RASPSock, max_thread: usize) {
     tokio_task.unwrap();
 }
 
+/// the returned handle finishes once the server stops, e.g. when binding `path` fails
 pub fn start(
     path: String,
     max_thread: usize,
     ctrl: Control,
     probe_to_agent_sender: Sender<plugins::Record>,
     agent_to_probe_receiver: Receiver<(i32, String)>,
-) {
+) -> JoinHandle<()> {
     let sock = RASPSock {
         server_addr: path,
         tx_channel: probe_to_agent_sender,
         rx_channel: agent_to_probe_receiver,
         ctrl: ctrl.clone(),
+        initial_config: Arc::new(Vec::new()),
     };
     Builder::new()
         .name("bind".to_string())
         .spawn(move || core_loop(sock, max_thread))
-        .unwrap();
+        .unwrap()
 }